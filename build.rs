@@ -1,11 +1,53 @@
 use bootloader::BootConfig;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Packs every file in `dir` but dotfiles like `.gitkeep` into an
+/// uncompressed ustar archive, which is the format the kernel expects its
+/// ramdisk in.
+fn build_ramdisk(dir: &Path, out: &Path) {
+    let mut archive = Vec::new();
+    let mut entries = fs::read_dir(dir)
+        .map(|entries| entries.map(|entry| entry.unwrap().path()).collect())
+        .unwrap_or_else(|_| Vec::new());
+    entries.sort();
+    let files = entries.iter().filter(|path| {
+        path.is_file() && !path.file_name().unwrap().to_string_lossy().starts_with('.')
+    });
+    for path in files {
+        println!("cargo:rerun-if-changed={}", path.display());
+        let name = path.file_name().unwrap().to_str().unwrap();
+        let data = fs::read(path).unwrap();
+        let mut header = [0u8; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(b"0000644");
+        header[124..135].copy_from_slice(format!("{:011o}", data.len()).as_bytes());
+        header[136..147].copy_from_slice(b"00000000000");
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        header[148..156].copy_from_slice(b"        ");
+        let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+        header[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(&data);
+        archive.resize(archive.len().div_ceil(512) * 512, 0);
+    }
+    archive.resize(archive.len() + 1024, 0);
+    fs::write(out, archive).unwrap();
+}
 
 fn main() {
     let out_dir = PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
     let kernel = PathBuf::from(std::env::var_os("CARGO_BIN_FILE_KERNEL_kernel").unwrap());
     println!("cargo:rustc-env=KERNEL_BINARY={}", kernel.display());
 
+    let ramdisk_dir =
+        PathBuf::from(std::env::var_os("CARGO_MANIFEST_DIR").unwrap()).join("ramdisk");
+    println!("cargo:rerun-if-changed={}", ramdisk_dir.display());
+    let ramdisk_path = out_dir.join("ramdisk.tar");
+    build_ramdisk(&ramdisk_dir, &ramdisk_path);
+
     let mut boot_config = BootConfig::default();
     boot_config.frame_buffer_logging = false;
     boot_config.serial_logging = true;
@@ -13,18 +55,21 @@ fn main() {
     let uefi_path = out_dir.join("uefi.img");
     bootloader::UefiBoot::new(&kernel)
         .set_boot_config(&boot_config)
+        .set_ramdisk(&ramdisk_path)
         .create_disk_image(&uefi_path)
         .unwrap();
 
     let uefi_path = out_dir.join("uefi.img");
     bootloader::UefiBoot::new(&kernel)
         .set_boot_config(&boot_config)
+        .set_ramdisk(&ramdisk_path)
         .create_disk_image(&uefi_path)
         .unwrap();
 
     let bios_path = out_dir.join("bios.img");
     bootloader::BiosBoot::new(&kernel)
         .set_boot_config(&boot_config)
+        .set_ramdisk(&ramdisk_path)
         .create_disk_image(&bios_path)
         .unwrap();
 
@@ -0,0 +1,103 @@
+use crate::memory::phys_to_virt;
+use alloc::vec::Vec;
+use spin::Once;
+use x86_64::PhysAddr;
+
+const HEADER_SIZE: usize = 36;
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_LOCAL_APIC_OVERRIDE: u8 = 5;
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// The physical addresses of the tables listed by the RSDT or XSDT.
+static TABLES: Once<Vec<u64>> = Once::new();
+
+/// Finds the ACPI tables through the RSDP the bootloader found, if any.
+pub fn init(rsdp_addr: Option<u64>) {
+    TABLES.call_once(|| rsdp_addr.and_then(root_tables).unwrap_or_default());
+}
+
+fn root_tables(rsdp_addr: u64) -> Option<Vec<u64>> {
+    let rsdp = physical(rsdp_addr, 36);
+    if &rsdp[0..8] != b"RSD PTR " || !checksum_ok(&rsdp[..20]) {
+        return None;
+    }
+    // revision 2 added the XSDT, which has 64-bit entries
+    let (root, entry_size) = if rsdp[15] >= 2 && checksum_ok(rsdp) {
+        (u64::from_le_bytes(rsdp[24..32].try_into().unwrap()), 8)
+    } else {
+        (
+            u32::from_le_bytes(rsdp[16..20].try_into().unwrap()) as u64,
+            4,
+        )
+    };
+    let root = table_at(root)?;
+    let entries = root[HEADER_SIZE..]
+        .chunks_exact(entry_size)
+        .map(|entry| match entry_size {
+            8 => u64::from_le_bytes(entry.try_into().unwrap()),
+            _ => u32::from_le_bytes(entry.try_into().unwrap()) as u64,
+        });
+    Some(entries.collect())
+}
+
+fn physical(addr: u64, len: usize) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(phys_to_virt(PhysAddr::new(addr)).as_ptr(), len) }
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+/// The whole table at `addr`, header included, if its checksum is right.
+fn table_at(addr: u64) -> Option<&'static [u8]> {
+    let header = physical(addr, HEADER_SIZE);
+    let len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+    let table = physical(addr, len.max(HEADER_SIZE));
+    checksum_ok(table).then_some(table)
+}
+
+/// The table with `signature`, such as `b"APIC"` for the MADT.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    TABLES
+        .get()?
+        .iter()
+        .filter(|&&addr| physical(addr, 4) == signature)
+        .find_map(|&addr| table_at(addr))
+}
+
+/// What the MADT says about the interrupt controllers.
+pub struct Madt {
+    pub local_apic_addr: u64,
+    /// The local APIC ids of the CPUs that can be started, in firmware
+    /// order, which starts with the bootstrap processor.
+    pub apic_ids: Vec<u8>,
+}
+
+pub fn madt() -> Option<Madt> {
+    let table = find_table(b"APIC")?;
+    let mut madt = Madt {
+        local_apic_addr: u32::from_le_bytes(table.get(36..40)?.try_into().unwrap()) as u64,
+        apic_ids: Vec::new(),
+    };
+    let mut entries = table.get(44..)?;
+    while let [kind, len, ..] = *entries {
+        let Some(entry) = entries.get(..len as usize).filter(|_| len >= 2) else {
+            break;
+        };
+        match kind {
+            MADT_LOCAL_APIC if entry.len() >= 8 => {
+                let flags = u32::from_le_bytes(entry[4..8].try_into().unwrap());
+                if flags & (LOCAL_APIC_ENABLED | LOCAL_APIC_ONLINE_CAPABLE) != 0 {
+                    madt.apic_ids.push(entry[3]);
+                }
+            }
+            MADT_LOCAL_APIC_OVERRIDE if entry.len() >= 12 => {
+                madt.local_apic_addr = u64::from_le_bytes(entry[4..12].try_into().unwrap());
+            }
+            _ => {}
+        }
+        entries = &entries[len as usize..];
+    }
+    Some(madt)
+}
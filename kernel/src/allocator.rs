@@ -0,0 +1,45 @@
+use crate::memory::map_range;
+use core::alloc::{GlobalAlloc, Layout};
+use linked_list_allocator::LockedHeap;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+pub const HEAP_START: u64 = 0x_4444_4444_0000;
+pub const HEAP_SIZE: u64 = 32 * 1024 * 1024;
+
+#[global_allocator]
+static ALLOCATOR: KernelHeap = KernelHeap(LockedHeap::empty());
+
+/// Holds the heap lock with interrupts off, so no thread is preempted with it
+/// held and interrupt handlers can allocate.
+struct KernelHeap(LockedHeap);
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        interrupts::without_interrupts(|| unsafe { self.0.alloc(layout) })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| unsafe { self.0.dealloc(ptr, layout) })
+    }
+}
+
+pub fn init_heap() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    map_range(VirtAddr::new(HEAP_START), HEAP_SIZE, flags).expect("failed to map heap");
+    unsafe {
+        ALLOCATOR
+            .0
+            .lock()
+            .init(HEAP_START as *mut u8, HEAP_SIZE as usize);
+    }
+}
+
+/// Returns `(used, free)` bytes of the kernel heap.
+pub fn usage() -> (usize, usize) {
+    interrupts::without_interrupts(|| {
+        let heap = ALLOCATOR.0.lock();
+        (heap.used(), heap.free())
+    })
+}
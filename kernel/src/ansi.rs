@@ -0,0 +1,156 @@
+const MAX_PARAMS: usize = 4;
+
+/// What the console should do with the next decoded character.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Print(char),
+    /// A control sequence `ESC [ [?] params [intermediate] final`. Missing
+    /// parameters read as 0.
+    Csi {
+        private: bool,
+        params: [u16; MAX_PARAMS],
+        intermediate: Option<char>,
+        action: char,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+}
+
+/// Splits a character stream into printable characters and the few escape
+/// sequences the console understands.
+pub struct Parser {
+    state: State,
+    private: bool,
+    params: [u16; MAX_PARAMS],
+    param: usize,
+    intermediate: Option<char>,
+}
+
+impl Parser {
+    pub const fn new() -> Parser {
+        Parser {
+            state: State::Ground,
+            private: false,
+            params: [0; MAX_PARAMS],
+            param: 0,
+            intermediate: None,
+        }
+    }
+
+    pub fn advance(&mut self, c: char) -> Option<Action> {
+        match (self.state, c) {
+            (State::Ground, '\x1b') => {
+                self.state = State::Escape;
+                None
+            }
+            (State::Ground, _) => Some(Action::Print(c)),
+            (State::Escape, '[') => {
+                *self = Parser::new();
+                self.state = State::Csi;
+                None
+            }
+            // anything but a CSI is dropped
+            (State::Escape, _) => {
+                self.state = State::Ground;
+                None
+            }
+            (State::Csi, '?') => {
+                self.private = true;
+                None
+            }
+            (State::Csi, '0'..='9') => {
+                if let Some(param) = self.params.get_mut(self.param) {
+                    *param = param
+                        .saturating_mul(10)
+                        .saturating_add(c as u16 - '0' as u16);
+                }
+                None
+            }
+            (State::Csi, ';') => {
+                self.param += 1;
+                None
+            }
+            (State::Csi, ' '..='/') => {
+                self.intermediate = Some(c);
+                None
+            }
+            (State::Csi, '@'..='~') => {
+                self.state = State::Ground;
+                Some(Action::Csi {
+                    private: self.private,
+                    params: self.params,
+                    intermediate: self.intermediate,
+                    action: c,
+                })
+            }
+            (State::Csi, _) => {
+                self.state = State::Ground;
+                None
+            }
+        }
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Parser {
+        Parser::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn parse(input: &str) -> Vec<Action> {
+        let mut parser = Parser::new();
+        input.chars().filter_map(|c| parser.advance(c)).collect()
+    }
+
+    fn csi(private: bool, params: [u16; MAX_PARAMS], action: char) -> Action {
+        Action::Csi {
+            private,
+            params,
+            intermediate: None,
+            action,
+        }
+    }
+
+    #[test_case]
+    fn sequences() {
+        assert_eq!(parse("\x1b[2;5H"), [csi(false, [2, 5, 0, 0], 'H')]);
+        assert_eq!(parse("\x1b[?25l"), [csi(true, [25, 0, 0, 0], 'l')]);
+        assert_eq!(
+            parse("\x1b[3 q"),
+            [Action::Csi {
+                private: false,
+                params: [3, 0, 0, 0],
+                intermediate: Some(' '),
+                action: 'q',
+            }]
+        );
+    }
+
+    #[test_case]
+    fn malformed() {
+        // too many and too large parameters
+        assert_eq!(parse("\x1b[1;2;3;4;5;6m"), [csi(false, [1, 2, 3, 4], 'm')]);
+        assert_eq!(
+            parse("\x1b[99999999J"),
+            [csi(false, [u16::MAX, 0, 0, 0], 'J')]
+        );
+        // escapes other than CSI and bytes that can't be in one are dropped
+        assert_eq!(parse("\x1b(Ba"), [Action::Print('B'), Action::Print('a')]);
+        assert_eq!(parse("\x1b[1\x07a"), [Action::Print('a')]);
+        // a new sequence starts clean
+        assert_eq!(
+            parse("\x1b[?1\x07\x1b[m"),
+            [csi(false, [0; MAX_PARAMS], 'm')]
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use crate::memory;
+use spin::Once;
+use x86_64::instructions::interrupts;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Where the local APIC sends interrupts it had to drop, which need no end
+/// of interrupt.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const ID: usize = 0x20;
+const END_OF_INTERRUPT: usize = 0xb0;
+const SPURIOUS: usize = 0xf0;
+const ERROR_STATUS: usize = 0x280;
+const ICR_LOW: usize = 0x300;
+const ICR_HIGH: usize = 0x310;
+const APIC_ENABLE: u32 = 1 << 8;
+const DELIVERY_PENDING: u32 = 1 << 12;
+const LEVEL_ASSERT: u32 = 1 << 14;
+const DELIVERY_NMI: u32 = 0b100 << 8;
+const DELIVERY_INIT: u32 = 0b101 << 8;
+const DELIVERY_STARTUP: u32 = 0b110 << 8;
+const SHORTHAND_SELF: u32 = 0b01 << 18;
+const SHORTHAND_ALL: u32 = 0b10 << 18;
+const SHORTHAND_OTHERS: u32 = 0b11 << 18;
+
+/// The local APIC registers. Every CPU sees its own at the same address.
+static LAPIC: Once<VirtAddr> = Once::new();
+
+/// Maps the local APIC registers at physical `addr`, then enables the
+/// calling CPU's.
+pub fn init(addr: u64) {
+    LAPIC.call_once(|| memory::map_mmio(PhysAddr::new(addr), 4096));
+    enable();
+}
+
+/// Enables the calling CPU's local APIC. The PIC keeps delivering through
+/// it, and the only interrupts of its own are IPIs.
+pub fn enable() {
+    write(SPURIOUS, APIC_ENABLE | SPURIOUS_VECTOR as u32);
+}
+
+/// The calling CPU's local APIC id.
+pub fn id() -> u8 {
+    (read(ID) >> 24) as u8
+}
+
+/// Tells the local APIC the interrupt being handled is done. Needed by every
+/// interrupt it delivered itself, like IPIs, but not by spurious ones.
+pub fn end_of_interrupt() {
+    write(END_OF_INTERRUPT, 0);
+}
+
+/// Who an IPI goes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    /// The CPU with this local APIC id.
+    Apic(u8),
+    /// The calling CPU.
+    Current,
+    All,
+    /// Every CPU but the calling one.
+    Others,
+}
+
+/// Raises interrupt `vector` on `dest`.
+pub fn send_fixed(dest: Destination, vector: u8) {
+    send_ipi(dest, vector as u32 | LEVEL_ASSERT);
+}
+
+/// Raises a non-maskable interrupt on `dest`.
+pub fn send_nmi(dest: Destination) {
+    send_ipi(dest, DELIVERY_NMI | LEVEL_ASSERT);
+}
+
+/// Puts the CPU with `apic_id` in its wait-for-startup state.
+pub fn send_init(apic_id: u8) {
+    send_ipi(Destination::Apic(apic_id), DELIVERY_INIT | LEVEL_ASSERT);
+}
+
+/// Starts a CPU waiting for startup in real mode at `page * 4096`.
+pub fn send_startup(apic_id: u8, page: u8) {
+    send_ipi(
+        Destination::Apic(apic_id),
+        DELIVERY_STARTUP | LEVEL_ASSERT | page as u32,
+    );
+}
+
+/// Writes the interrupt command register, which is shared by everything
+/// running on this CPU, so interrupts are off meanwhile.
+fn send_ipi(dest: Destination, command: u32) {
+    let (apic_id, shorthand) = match dest {
+        Destination::Apic(apic_id) => (apic_id, 0),
+        Destination::Current => (0, SHORTHAND_SELF),
+        Destination::All => (0, SHORTHAND_ALL),
+        Destination::Others => (0, SHORTHAND_OTHERS),
+    };
+    interrupts::without_interrupts(|| {
+        write(ERROR_STATUS, 0);
+        write(ICR_HIGH, (apic_id as u32) << 24);
+        write(ICR_LOW, command | shorthand);
+        while read(ICR_LOW) & DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+fn register(offset: usize) -> *mut u32 {
+    (*LAPIC.get().expect("local APIC not initialized") + offset as u64).as_mut_ptr()
+}
+
+fn read(offset: usize) -> u32 {
+    unsafe { register(offset).read_volatile() }
+}
+
+fn write(offset: usize, value: u32) {
+    unsafe { register(offset).write_volatile(value) }
+}
@@ -0,0 +1,188 @@
+use crate::demangle::Demangle;
+use crate::memory::phys_to_virt;
+use crate::unwind::{self, Registers};
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::fmt;
+use spin::Once;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::PhysAddr;
+
+const MAX_FRAMES: usize = 32;
+/// Bound on how far apart two frames may be before the walk is considered
+/// to have gone off the stack.
+const MAX_FRAME_SIZE: u64 = 1024 * 1024;
+const SYMBOL_SIZE: usize = 24;
+const STT_FUNC: u8 = 2;
+
+static IMAGE_OFFSET: Once<u64> = Once::new();
+/// The kernel's functions, sorted by address.
+static SYMBOLS: Once<Vec<Symbol>> = Once::new();
+
+struct Symbol {
+    /// Relative to the start of the image.
+    start: u64,
+    size: u64,
+    name: &'static str,
+}
+
+/// Records where the kernel was loaded and reads the symbol table and unwind
+/// info of its ELF file at physical `kernel_addr`. Needs the heap.
+pub fn init(kernel_addr: u64, kernel_len: u64, image_offset: u64) {
+    IMAGE_OFFSET.call_once(|| image_offset);
+    let elf: &'static [u8] = unsafe {
+        core::slice::from_raw_parts(
+            phys_to_virt(PhysAddr::new(kernel_addr)).as_ptr(),
+            kernel_len as usize,
+        )
+    };
+    SYMBOLS.call_once(|| read_symbols(elf).unwrap_or_default());
+    unwind::init(elf, image_offset);
+}
+
+/// The functions in the `.symtab` section of `elf`, which the bootloader
+/// doesn't load but passes along with the rest of the file.
+fn read_symbols(elf: &'static [u8]) -> Option<Vec<Symbol>> {
+    let symtab = unwind::find_section(elf, b".symtab")?;
+    let names = unwind::section(elf, symtab.link as usize)?.data(elf)?;
+    let mut symbols: Vec<_> = symtab
+        .data(elf)?
+        .chunks_exact(SYMBOL_SIZE)
+        .filter(|entry| entry[4] & 0xf == STT_FUNC)
+        .filter_map(|entry| {
+            let start = unwind::u64_at(entry, 8)?;
+            let name = names.get(unwind::u32_at(entry, 0)? as usize..)?;
+            let name = &name[..name.iter().position(|&b| b == 0)?];
+            let name = core::str::from_utf8(name).ok()?;
+            (start != 0).then_some(Symbol {
+                start,
+                size: unwind::u64_at(entry, 16)?,
+                name,
+            })
+        })
+        .collect();
+    symbols.sort_unstable_by_key(|symbol| symbol.start);
+    Some(symbols)
+}
+
+/// Return addresses of the frames on a stack. They come from the `.eh_frame`
+/// unwind info where it is available, and otherwise from following the saved
+/// frame pointer chain, which the kernel is built to keep with
+/// `force-frame-pointers`.
+#[derive(Clone, Copy)]
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+impl Backtrace {
+    /// Walks the stack of the caller, starting with the address this returns
+    /// to.
+    #[inline(never)]
+    pub fn capture() -> Backtrace {
+        // skip the frame of this function
+        if let Some(backtrace) = Backtrace::unwind(Registers::capture(), 1) {
+            return backtrace;
+        }
+        let rbp: u64;
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+        Backtrace::walk(rbp)
+    }
+
+    /// Walks the stack of the code an exception handler interrupted, starting
+    /// at the faulting instruction. Must be called from the handler itself.
+    #[inline(never)]
+    pub fn from_interrupt(stack_frame: &InterruptStackFrame) -> Backtrace {
+        let rbp: u64;
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+        // the handler saved the interrupted rbp at the bottom of its frame,
+        // which ours links to
+        let interrupted_rbp = unsafe { *(*(rbp as *const u64) as *const u64) };
+        let regs = Registers::interrupted(
+            stack_frame.instruction_pointer.as_u64(),
+            stack_frame.stack_pointer.as_u64(),
+            interrupted_rbp,
+        );
+        if let Some(backtrace) = Backtrace::unwind(regs, 0) {
+            return backtrace;
+        }
+        let mut backtrace = Backtrace::walk(rbp);
+        // the first two entries are the return into the handler and whatever
+        // sits above the handler's saved rbp, the pushed rip or error code
+        let skip = backtrace.len.min(2);
+        backtrace.frames.copy_within(skip..backtrace.len, 1);
+        backtrace.frames[0] = stack_frame.instruction_pointer.as_u64();
+        backtrace.len = backtrace.len - skip + 1;
+        backtrace
+    }
+
+    fn unwind(regs: Registers, skip: usize) -> Option<Backtrace> {
+        let mut backtrace = Backtrace {
+            frames: [0; MAX_FRAMES],
+            len: 0,
+        };
+        let mut skip = skip;
+        unwind::unwind(regs, |ip| {
+            if skip > 0 {
+                skip -= 1;
+            } else {
+                backtrace.frames[backtrace.len] = ip;
+                backtrace.len += 1;
+            }
+            backtrace.len < MAX_FRAMES
+        });
+        (backtrace.len > 0).then_some(backtrace)
+    }
+
+    fn walk(mut rbp: u64) -> Backtrace {
+        let mut backtrace = Backtrace {
+            frames: [0; MAX_FRAMES],
+            len: 0,
+        };
+        while backtrace.len < MAX_FRAMES && rbp != 0 && rbp.is_multiple_of(8) {
+            // a frame is the caller's rbp followed by the return address
+            let (next, address) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+            if address == 0 {
+                break;
+            }
+            backtrace.frames[backtrace.len] = address;
+            backtrace.len += 1;
+            if next <= rbp || next - rbp > MAX_FRAME_SIZE {
+                break;
+            }
+            rbp = next;
+        }
+        backtrace
+    }
+
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "backtrace:")?;
+        for (i, &address) in self.frames().iter().enumerate() {
+            match symbolize(address) {
+                Some((name, offset)) => {
+                    writeln!(f, "  #{:<2} {:#018x} {}+{:#x}", i, address, name, offset)?
+                }
+                None => writeln!(f, "  #{:<2} {:#018x} ??", i, address)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The function containing `address` and how far into it the address is.
+pub fn symbolize(address: u64) -> Option<(Demangle<'static>, u64)> {
+    let symbols = SYMBOLS.get()?;
+    let address = address.checked_sub(*IMAGE_OFFSET.get()?)?;
+    // return addresses point past the call, which may be the next function
+    let target = address.checked_sub(1)?;
+    let index = symbols.partition_point(|symbol| symbol.start <= target);
+    let symbol = &symbols[index.checked_sub(1)?];
+    let inside = symbol.size == 0 || target - symbol.start < symbol.size;
+    inside.then_some((Demangle(symbol.name), address - symbol.start))
+}
@@ -0,0 +1,70 @@
+use crate::ramdisk;
+use spin::Once;
+
+static CMDLINE: Once<&'static str> = Once::new();
+
+/// Reads the boot options, whitespace separated `key=value` pairs or bare
+/// flags, from the ramdisk's `cmdline` file. Falls back to `KERNEL_CMDLINE`
+/// at build time.
+pub fn init() {
+    CMDLINE.call_once(|| {
+        ramdisk::file("cmdline")
+            .and_then(|bytes| core::str::from_utf8(bytes).ok())
+            .or(option_env!("KERNEL_CMDLINE"))
+            .unwrap_or("")
+            .trim()
+    });
+}
+
+pub fn raw() -> &'static str {
+    CMDLINE.get().copied().unwrap_or("")
+}
+
+/// The value of option `key`, empty for a bare flag. Later options win.
+pub fn get(key: &str) -> Option<&'static str> {
+    find(raw(), key)
+}
+
+fn find<'a>(cmdline: &'a str, key: &str) -> Option<&'a str> {
+    cmdline
+        .split_whitespace()
+        .filter_map(|option| match option.split_once('=') {
+            Some((name, value)) => (name == key).then_some(value),
+            None => (option == key).then_some(""),
+        })
+        .next_back()
+}
+
+/// Whether `key` is set, either bare or to a truthy value.
+pub fn flag(key: &str) -> bool {
+    get(key).is_some_and(is_truthy)
+}
+
+fn is_truthy(value: &str) -> bool {
+    matches!(value, "" | "1" | "on" | "yes" | "true")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn options() {
+        let cmdline = " sched=fair  quiet\tlog=debug sched=priority ";
+        assert_eq!(find(cmdline, "sched"), Some("priority"));
+        assert_eq!(find(cmdline, "quiet"), Some(""));
+        assert_eq!(find(cmdline, "log"), Some("debug"));
+        assert_eq!(find(cmdline, "sch"), None);
+        assert_eq!(find("", "quiet"), None);
+    }
+
+    #[test_case]
+    fn malformed() {
+        assert_eq!(find("=on", ""), Some("on"));
+        assert_eq!(find("a=b=c", "a"), Some("b=c"));
+        assert_eq!(find("a=b=c", "a=b"), None);
+        assert_eq!(find("splash=", "splash"), Some(""));
+        assert!(is_truthy(find("splash=", "splash").unwrap()));
+        assert!(!is_truthy("off") && !is_truthy("On"));
+    }
+}
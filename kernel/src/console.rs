@@ -0,0 +1,442 @@
+use crate::ansi::{Action, Parser};
+use crate::dmesg;
+use crate::executor::WakerSlot;
+use crate::framebuffer::{CursorStyle, FrameBuffer, BLACK, FRAMEBUFFER};
+use crate::utf8::Utf8Decoder;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{self, Arguments, Write};
+use core::future::poll_fn;
+use core::ops::Range;
+use core::task::Poll;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const NUM_CONSOLES: usize = 6;
+/// tty1, where `print!` and kernel messages go.
+pub const LOG_CONSOLE: usize = 0;
+/// tty2, reserved for the interactive shell.
+pub const SHELL_CONSOLE: usize = 1;
+const INPUT_SIZE: usize = 256;
+
+static CONSOLES: Mutex<Consoles> = Mutex::new(Consoles::new());
+static INPUT_WAKERS: [WakerSlot; NUM_CONSOLES] = [const { WakerSlot::new() }; NUM_CONSOLES];
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::console::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+#[doc(hidden)]
+pub fn _print(args: Arguments) {
+    interrupts::without_interrupts(|| {
+        let mut consoles = CONSOLES.lock();
+        let mut tty = Tty {
+            consoles: &mut consoles,
+            index: LOG_CONSOLE,
+        };
+        tty.write_fmt(args).expect("failed to write console");
+        consoles.present(LOG_CONSOLE);
+    });
+}
+
+/// Sizes every console to a `dim` cell grid, clearing them, and replays the
+/// messages logged so far to the log console.
+pub fn init(dim: (usize, usize)) {
+    interrupts::without_interrupts(|| {
+        let mut consoles = CONSOLES.lock();
+        for console in consoles.ttys.iter_mut() {
+            *console = Console::new(dim);
+        }
+        dmesg::read(|bytes| consoles.ttys[LOG_CONSOLE].write(bytes));
+        let active = consoles.active;
+        consoles.switch(active);
+    });
+}
+
+pub fn write(tty: usize, bytes: &[u8]) {
+    interrupts::without_interrupts(|| {
+        let mut consoles = CONSOLES.lock();
+        consoles.ttys[tty].write(bytes);
+        consoles.present(tty);
+    });
+}
+
+/// Brings console `tty` to the screen and gives it keyboard focus.
+pub fn switch(tty: usize) {
+    if tty < NUM_CONSOLES {
+        interrupts::without_interrupts(|| CONSOLES.lock().switch(tty));
+    }
+}
+
+/// Stops drawing to the screen, leaving it to someone else, e.g. the boot
+/// splash. Consoles keep taking output in the meantime.
+pub fn suspend() {
+    interrupts::without_interrupts(|| CONSOLES.lock().suspended = true);
+}
+
+/// Redraws the active console and resumes drawing after `suspend`.
+pub fn resume() {
+    interrupts::without_interrupts(|| {
+        let mut consoles = CONSOLES.lock();
+        consoles.suspended = false;
+        FRAMEBUFFER.lock().fill(BLACK);
+        let active = consoles.active;
+        consoles.switch(active);
+    });
+}
+
+pub fn active() -> usize {
+    interrupts::without_interrupts(|| CONSOLES.lock().active)
+}
+
+/// Queues keyboard input for whichever console has focus.
+pub fn push_input(bytes: &[u8]) {
+    let active = interrupts::without_interrupts(|| {
+        let mut consoles = CONSOLES.lock();
+        let active = consoles.active;
+        for &byte in bytes {
+            consoles.ttys[active].input.push(byte);
+        }
+        active
+    });
+    INPUT_WAKERS[active].wake();
+}
+
+pub fn read_input(tty: usize) -> Option<u8> {
+    interrupts::without_interrupts(|| CONSOLES.lock().ttys[tty].input.pop())
+}
+
+/// Waits for a byte typed at console `tty`.
+pub async fn next_input(tty: usize) -> u8 {
+    poll_fn(|cx| {
+        INPUT_WAKERS[tty].register(cx.waker());
+        read_input(tty).map_or(Poll::Pending, Poll::Ready)
+    })
+    .await
+}
+
+/// Toggles the active console's cursor blink phase, called from the timer
+/// interrupt. Skips the tick if the console is busy rather than waiting.
+pub fn blink() {
+    if let Some(mut consoles) = CONSOLES.try_lock().filter(|consoles| !consoles.suspended) {
+        let active = consoles.active;
+        consoles.ttys[active].blink();
+        if let Some(mut framebuffer) = FRAMEBUFFER.try_lock() {
+            consoles.ttys[active].render(&mut framebuffer);
+            framebuffer.flush();
+        }
+    }
+}
+
+/// Frees the consoles for the panic handler when the panic hit someone in
+/// the middle of a write.
+///
+/// # Safety
+/// The holder of the lock must never run again.
+pub unsafe fn force_unlock() {
+    CONSOLES.force_unlock();
+}
+
+struct Consoles {
+    ttys: [Console; NUM_CONSOLES],
+    active: usize,
+    suspended: bool,
+}
+
+impl Consoles {
+    const fn new() -> Consoles {
+        Consoles {
+            ttys: [const { Console::const_default() }; NUM_CONSOLES],
+            active: LOG_CONSOLE,
+            suspended: false,
+        }
+    }
+
+    fn switch(&mut self, tty: usize) {
+        self.active = tty;
+        let console = &mut self.ttys[tty];
+        console.scrolled = 0;
+        console.damage(0..console.dim.1);
+        self.present(tty);
+    }
+
+    /// Draws pending changes of `tty` if it is on screen.
+    fn present(&mut self, tty: usize) {
+        if tty == self.active && !self.suspended {
+            let mut framebuffer = FRAMEBUFFER.lock();
+            self.ttys[tty].render(&mut framebuffer);
+            framebuffer.flush();
+        }
+    }
+}
+
+struct Tty<'a> {
+    consoles: &'a mut Consoles,
+    index: usize,
+}
+
+impl Write for Tty<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.index == LOG_CONSOLE {
+            dmesg::write(s.as_bytes());
+        }
+        self.consoles.ttys[self.index].write(s.as_bytes());
+        Ok(())
+    }
+}
+
+struct Cursor {
+    style: CursorStyle,
+    blinking: bool,
+    visible: bool,
+    // whether the cursor is in the lit half of its blink
+    lit: bool,
+}
+
+/// Bytes typed at a console or received on serial that nobody has read yet.
+/// Input beyond `INPUT_SIZE` bytes is dropped.
+pub struct InputQueue {
+    buf: [u8; INPUT_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl Default for InputQueue {
+    fn default() -> InputQueue {
+        InputQueue::new()
+    }
+}
+
+impl InputQueue {
+    pub const fn new() -> InputQueue {
+        InputQueue {
+            buf: [0; INPUT_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn push(&mut self, byte: u8) {
+        if self.len < INPUT_SIZE {
+            self.buf[(self.head + self.len) % INPUT_SIZE] = byte;
+            self.len += 1;
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % INPUT_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+/// A text terminal. Consoles keep their own cells and cursor and only draw
+/// to the framebuffer while they are the active one.
+pub struct Console {
+    dim: (usize, usize),
+    cells: Vec<char>,
+    pos: (usize, usize),
+    cursor: Cursor,
+    decoder: Utf8Decoder,
+    parser: Parser,
+    input: InputQueue,
+    // rows changed since the last render, and how far the screen scrolled
+    damaged: Option<Range<usize>>,
+    scrolled: usize,
+}
+
+impl Console {
+    const fn const_default() -> Console {
+        Console {
+            dim: (0, 0),
+            cells: Vec::new(),
+            pos: (0, 0),
+            cursor: Cursor {
+                style: CursorStyle::Block,
+                blinking: true,
+                visible: true,
+                lit: true,
+            },
+            decoder: Utf8Decoder::new(),
+            parser: Parser::new(),
+            input: InputQueue::new(),
+            damaged: None,
+            scrolled: 0,
+        }
+    }
+
+    fn new(dim: (usize, usize)) -> Console {
+        Console {
+            dim,
+            cells: vec![' '; dim.0 * dim.1],
+            ..Console::const_default()
+        }
+    }
+
+    /// Writes raw bytes, decoding them as UTF-8. A character split across
+    /// calls is completed by the next one.
+    pub fn write(&mut self, bytes: &[u8]) {
+        if self.cells.is_empty() {
+            return;
+        }
+        self.damage_row(self.pos.1);
+        let mut decoder = core::mem::take(&mut self.decoder);
+        decoder.decode(bytes, |c| {
+            if let Some(action) = self.parser.advance(c) {
+                self.perform(action);
+            }
+        });
+        self.decoder = decoder;
+        self.cursor.lit = true;
+        self.damage_row(self.pos.1);
+    }
+
+    fn write_char(&mut self, c: char) {
+        match c {
+            '\n' => self.newline(),
+            '\r' => self.pos.0 = 0,
+            '\x08' => self.pos.0 = self.pos.0.saturating_sub(1),
+            _ => {
+                self.set_cell(self.pos, c);
+                self.pos.0 += 1;
+                if self.pos.0 == self.dim.0 {
+                    self.newline();
+                }
+            }
+        }
+    }
+
+    fn newline(&mut self) {
+        self.pos.0 = 0;
+        if self.pos.1 + 1 < self.dim.1 {
+            self.pos.1 += 1;
+        } else {
+            self.scroll();
+        }
+    }
+
+    fn scroll(&mut self) {
+        let (cols, rows) = self.dim;
+        self.cells.copy_within(cols.., 0);
+        self.cells[cols * (rows - 1)..].fill(' ');
+        self.damaged = self
+            .damaged
+            .take()
+            .map(|rows| rows.start.saturating_sub(1)..rows.end.saturating_sub(1));
+        self.scrolled += 1;
+    }
+
+    fn set_cell(&mut self, (x, y): (usize, usize), c: char) {
+        self.cells[y * self.dim.0 + x] = c;
+        self.damage_row(y);
+    }
+
+    fn damage_row(&mut self, y: usize) {
+        self.damage(y..y + 1);
+    }
+
+    fn damage(&mut self, rows: Range<usize>) {
+        self.damaged = Some(match self.damaged.take() {
+            Some(damaged) if !damaged.is_empty() => {
+                damaged.start.min(rows.start)..damaged.end.max(rows.end)
+            }
+            _ => rows,
+        });
+    }
+
+    fn blink(&mut self) {
+        if self.cursor.visible && self.cursor.blinking {
+            self.cursor.lit = !self.cursor.lit;
+            self.damage_row(self.pos.1);
+        }
+    }
+
+    fn render(&mut self, framebuffer: &mut FrameBuffer) {
+        if self.scrolled > 0 {
+            framebuffer.scroll(self.scrolled);
+            self.scrolled = 0;
+        }
+        let Some(rows) = self.damaged.take() else {
+            return;
+        };
+        let cursor = (self.cursor.visible && self.cursor.lit).then_some(self.cursor.style);
+        for y in rows {
+            for x in 0..self.dim.0 {
+                let cursor = cursor.filter(|_| (x, y) == self.pos);
+                framebuffer.render_cell(self.cells[y * self.dim.0 + x], (x, y), cursor);
+            }
+        }
+    }
+
+    fn perform(&mut self, action: Action) {
+        match action {
+            Action::Print(c) => self.write_char(c),
+            Action::Csi {
+                private,
+                params,
+                intermediate,
+                action,
+            } => self.csi(private, params, intermediate, action),
+        }
+    }
+
+    fn csi(&mut self, private: bool, params: [u16; 4], intermediate: Option<char>, action: char) {
+        let count = params[0].max(1) as usize;
+        let (cols, rows) = self.dim;
+        self.damage_row(self.pos.1);
+        match (private, intermediate, action) {
+            (true, None, 'h') if params[0] == 25 => self.cursor.visible = true,
+            (true, None, 'l') if params[0] == 25 => self.cursor.visible = false,
+            // DECSCUSR, odd styles blink and even ones are steady
+            (false, Some(' '), 'q') => {
+                self.cursor.style = match params[0] {
+                    0..=2 => CursorStyle::Block,
+                    3 | 4 => CursorStyle::Underline,
+                    _ => CursorStyle::Bar,
+                };
+                self.cursor.blinking = params[0] == 0 || params[0] % 2 == 1;
+            }
+            (false, None, 'A') => self.pos.1 = self.pos.1.saturating_sub(count),
+            (false, None, 'B') => self.pos.1 = (self.pos.1 + count).min(rows - 1),
+            (false, None, 'C') => self.pos.0 = (self.pos.0 + count).min(cols - 1),
+            (false, None, 'D') => self.pos.0 = self.pos.0.saturating_sub(count),
+            (false, None, 'H') => {
+                self.pos = (
+                    (params[1].max(1) as usize - 1).min(cols - 1),
+                    (params[0].max(1) as usize - 1).min(rows - 1),
+                );
+            }
+            (false, None, 'J') if params[0] == 2 => self.clear(0..rows),
+            (false, None, 'J') => {
+                self.clear_line(self.pos.0..cols);
+                self.clear(self.pos.1 + 1..rows);
+            }
+            (false, None, 'K') => self.clear_line(self.pos.0..cols),
+            _ => {}
+        }
+        self.damage_row(self.pos.1);
+    }
+
+    fn clear(&mut self, rows: Range<usize>) {
+        let cols = self.dim.0;
+        self.cells[rows.start * cols..rows.end * cols].fill(' ');
+        self.damage(rows);
+    }
+
+    fn clear_line(&mut self, cols: Range<usize>) {
+        let start = self.pos.1 * self.dim.0;
+        self.cells[start + cols.start..start + cols.end].fill(' ');
+        self.damage_row(self.pos.1);
+    }
+}
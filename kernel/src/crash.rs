@@ -0,0 +1,276 @@
+use crate::backtrace::Backtrace;
+use crate::framebuffer::FRAMEBUFFER;
+use crate::interrupt::{ticks, TIMER_HZ};
+use crate::serial::{RawSerial, SERIAL1};
+use crate::shell::{self, Command, CommandError};
+use crate::{
+    cmdline, console, dmesg, exit_qemu, lockdep, percpu, println, smp, splash, QemuExitCode,
+};
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::port::Port;
+use x86_64::instructions::tables::{lidt, DescriptorTablePointer};
+use x86_64::instructions::{self, interrupts};
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::registers::rflags;
+use x86_64::VirtAddr;
+
+/// Log lines included at the end of a crash report.
+const REPORT_LOG_LINES: usize = 32;
+
+const NO_CPU: usize = usize::MAX;
+
+/// The CPU that got to panic first, the only one that reports.
+static PANIC_CPU: AtomicUsize = AtomicUsize::new(NO_CPU);
+/// Panics in progress on that CPU, more than one if the panic path itself
+/// panicked.
+static PANICKING: AtomicUsize = AtomicUsize::new(0);
+
+/// What to do once a panic has been reported, from the `panic` boot option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicAction {
+    Halt,
+    Reboot,
+    /// Exit QEMU with `QemuExitCode::Failed`, for unattended runs.
+    Exit,
+}
+
+impl PanicAction {
+    pub fn from_cmdline() -> PanicAction {
+        match cmdline::get("panic") {
+            Some("reboot") => PanicAction::Reboot,
+            Some("exit") => PanicAction::Exit,
+            _ => PanicAction::Halt,
+        }
+    }
+}
+
+pub fn init() {
+    shell::register(&RebootCommand);
+    shell::register(&ShutdownCommand);
+}
+
+/// Writes a crash report to serial, shows the panic on screen and then halts,
+/// reboots or exits as configured.
+pub fn panic(info: &PanicInfo) -> ! {
+    interrupts::disable();
+    let cpu = percpu::id();
+    if let Err(owner) = PANIC_CPU.compare_exchange(NO_CPU, cpu, Ordering::SeqCst, Ordering::SeqCst)
+    {
+        if owner != cpu {
+            // another CPU is reporting its panic and stopping this one
+            halt();
+        }
+    }
+    match PANICKING.fetch_add(1, Ordering::SeqCst) {
+        0 => {}
+        // the report or the screen panicked, say so without touching either
+        1 => {
+            let _ = writeln!(RawSerial, "\nnested panic: {}", info);
+            finish();
+        }
+        _ => halt(),
+    }
+    // the other CPUs could be holding the locks forced open below
+    smp::stop_others();
+    let registers = Registers::capture();
+    let backtrace = Backtrace::capture();
+    // whatever the panic interrupted will never release its locks
+    lockdep::disable();
+    unsafe {
+        SERIAL1.force_unlock();
+        FRAMEBUFFER.force_unlock();
+        console::force_unlock();
+        dmesg::force_unlock();
+        splash::force_unlock();
+    }
+    // the report goes first and without locks, so it gets out even if the
+    // screen is what's broken
+    let _ = report(&mut RawSerial, info, &registers, &backtrace);
+    splash::finish();
+    println!("{}", info);
+    println!("{}", backtrace);
+    finish()
+}
+
+/// Does the configured panic action.
+fn finish() -> ! {
+    match PanicAction::from_cmdline() {
+        PanicAction::Halt => {}
+        PanicAction::Reboot => reboot(),
+        PanicAction::Exit => exit_qemu(QemuExitCode::Failed),
+    }
+    halt()
+}
+
+pub fn report(
+    out: &mut impl Write,
+    info: &PanicInfo,
+    registers: &Registers,
+    backtrace: &Backtrace,
+) -> fmt::Result {
+    let ticks = ticks();
+    writeln!(
+        out,
+        "==================== KERNEL PANIC ===================="
+    )?;
+    writeln!(out, "message:  {}", info.message())?;
+    if let Some(location) = info.location() {
+        writeln!(out, "location: {}", location)?;
+    }
+    writeln!(
+        out,
+        "uptime:   {}.{:03}s, cpu {}",
+        ticks / TIMER_HZ,
+        ticks % TIMER_HZ * 1000 / TIMER_HZ,
+        percpu::id()
+    )?;
+    writeln!(out, "{}", registers)?;
+    write!(out, "{}", backtrace)?;
+    writeln!(out, "last {} log lines:", REPORT_LOG_LINES)?;
+    let mut result = Ok(());
+    dmesg::read_tail(REPORT_LOG_LINES, |bytes| {
+        let text = core::str::from_utf8(bytes).unwrap_or("<invalid utf-8>\n");
+        result = result.and_then(|_| out.write_str(text));
+    });
+    result?;
+    writeln!(
+        out,
+        "======================================================"
+    )
+}
+
+pub fn halt() -> ! {
+    interrupts::disable();
+    loop {
+        instructions::hlt();
+    }
+}
+
+/// Resets through the keyboard controller, or by triple faulting if that
+/// doesn't work.
+pub fn reboot() -> ! {
+    unsafe {
+        Port::<u8>::new(0x64).write(0xfe);
+        lidt(&DescriptorTablePointer {
+            limit: 0,
+            base: VirtAddr::zero(),
+        });
+        asm!("int3", options(nomem, nostack));
+    }
+    halt()
+}
+
+/// Powers off through the ACPI ports QEMU and Bochs have at fixed places,
+/// halting where there are none.
+pub fn shutdown() -> ! {
+    unsafe {
+        Port::<u16>::new(0x604).write(0x2000);
+        Port::<u16>::new(0xb004).write(0x2000);
+    }
+    halt()
+}
+
+pub struct RebootCommand;
+
+impl Command for RebootCommand {
+    fn name(&self) -> &'static str {
+        "reboot"
+    }
+
+    fn help(&self) -> &'static str {
+        "restart the machine"
+    }
+
+    fn run(&self, _args: &[&str], _out: &mut dyn Write) -> Result<(), CommandError> {
+        reboot()
+    }
+}
+
+pub struct ShutdownCommand;
+
+impl Command for ShutdownCommand {
+    fn name(&self) -> &'static str {
+        "shutdown"
+    }
+
+    fn help(&self) -> &'static str {
+        "power off the machine"
+    }
+
+    fn run(&self, _args: &[&str], _out: &mut dyn Write) -> Result<(), CommandError> {
+        shutdown()
+    }
+}
+
+/// General purpose and control registers at the time of a crash.
+pub struct Registers {
+    general: [u64; 16],
+    rflags: u64,
+    cr0: u64,
+    cr2: u64,
+    cr3: u64,
+    cr4: u64,
+}
+
+const REGISTER_NAMES: [&str; 16] = [
+    "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15",
+];
+
+impl Registers {
+    #[inline(always)]
+    pub fn capture() -> Registers {
+        let mut general = [0u64; 16];
+        unsafe {
+            asm!(
+                "mov [rdi], rax",
+                "mov [rdi + 8], rbx",
+                "mov [rdi + 16], rcx",
+                "mov [rdi + 24], rdx",
+                "mov [rdi + 32], rsi",
+                "mov [rdi + 40], rdi",
+                "mov [rdi + 48], rbp",
+                "mov [rdi + 56], rsp",
+                "mov [rdi + 64], r8",
+                "mov [rdi + 72], r9",
+                "mov [rdi + 80], r10",
+                "mov [rdi + 88], r11",
+                "mov [rdi + 96], r12",
+                "mov [rdi + 104], r13",
+                "mov [rdi + 112], r14",
+                "mov [rdi + 120], r15",
+                in("rdi") general.as_mut_ptr(),
+                options(nostack, preserves_flags),
+            );
+        }
+        Registers {
+            general,
+            rflags: rflags::read_raw(),
+            cr0: Cr0::read_raw(),
+            cr2: Cr2::read_raw(),
+            cr3: Cr3::read_raw().0.start_address().as_u64(),
+            cr4: Cr4::read_raw(),
+        }
+    }
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "registers:")?;
+        for (names, values) in REGISTER_NAMES.chunks(4).zip(self.general.chunks(4)) {
+            for (name, value) in names.iter().zip(values) {
+                write!(f, "  {:<3}={:#018x}", name, value)?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f, "  rflags={:#018x}", self.rflags)?;
+        write!(
+            f,
+            "  cr0={:#018x}  cr2={:#018x}  cr3={:#018x}  cr4={:#018x}",
+            self.cr0, self.cr2, self.cr3, self.cr4
+        )
+    }
+}
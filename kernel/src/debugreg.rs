@@ -0,0 +1,239 @@
+use crate::shell::{self, Command, CommandError};
+use crate::smp;
+use alloc::string::ToString;
+use core::arch::asm;
+use core::fmt::{self, Write};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::debug::{
+    BreakpointCondition, BreakpointSize, DebugAddressRegister, DebugAddressRegisterNumber, Dr0,
+    Dr1, Dr2, Dr3, Dr6, Dr6Flags, Dr7, Dr7Flags,
+};
+
+pub const NUM_WATCHPOINTS: usize = 4;
+
+static WATCHPOINTS: Mutex<[Option<Watchpoint>; NUM_WATCHPOINTS]> =
+    Mutex::new([None; NUM_WATCHPOINTS]);
+
+/// What a watchpoint triggers on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Execute,
+    Write,
+    ReadWrite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchError {
+    NoFreeSlot,
+    /// Lengths are 1, 2, 4 or 8 bytes, and 1 for execute breakpoints.
+    BadLength,
+    /// The address isn't aligned to the length.
+    Unaligned,
+}
+
+impl fmt::Display for WatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            WatchError::NoFreeSlot => "all debug registers are in use",
+            WatchError::BadLength => "bad length",
+            WatchError::Unaligned => "address not aligned to length",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addr: u64,
+    pub len: usize,
+    pub access: Access,
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let access = match self.access {
+            Access::Execute => "execute",
+            Access::Write => "write",
+            Access::ReadWrite => "read/write",
+        };
+        write!(f, "{} {:#x} ({} bytes)", access, self.addr, self.len)
+    }
+}
+
+pub fn init() {
+    shell::register(&WatchCommand);
+}
+
+/// Arms a free debug register on every CPU to trap on `access` to `len`
+/// bytes at `addr`, returning its slot.
+pub fn set(addr: u64, len: usize, access: Access) -> Result<usize, WatchError> {
+    if BreakpointSize::new(len).is_none() || (access == Access::Execute && len != 1) {
+        return Err(WatchError::BadLength);
+    }
+    if !addr.is_multiple_of(len as u64) {
+        return Err(WatchError::Unaligned);
+    }
+    let slot = interrupts::without_interrupts(|| {
+        let mut watchpoints = WATCHPOINTS.lock();
+        let slot = watchpoints
+            .iter()
+            .position(Option::is_none)
+            .ok_or(WatchError::NoFreeSlot)?;
+        watchpoints[slot] = Some(Watchpoint { addr, len, access });
+        Ok(slot)
+    })?;
+    smp::call_on_all(load);
+    Ok(slot)
+}
+
+/// Programs the calling CPU's debug registers with the watchpoints, arming
+/// the set slots and disarming the others. Run on every CPU when they change
+/// and by CPUs as they start.
+pub fn load() {
+    interrupts::without_interrupts(|| {
+        let watchpoints = WATCHPOINTS.lock();
+        let mut dr7 = Dr7::read();
+        for (slot, watchpoint) in watchpoints.iter().enumerate() {
+            let n = register(slot);
+            let Some(Watchpoint { addr, len, access }) = *watchpoint else {
+                dr7.remove_flags(Dr7Flags::local_breakpoint_enable(n));
+                continue;
+            };
+            write_address(n, addr);
+            dr7.set_condition(n, condition(access));
+            dr7.set_size(n, BreakpointSize::new(len).unwrap());
+            dr7.insert_flags(Dr7Flags::local_breakpoint_enable(n));
+        }
+        Dr7::write(dr7);
+    });
+}
+
+/// Disarms `slot` on every CPU, returning whether it was set.
+pub fn clear(slot: usize) -> bool {
+    if slot >= NUM_WATCHPOINTS {
+        return false;
+    }
+    let was_set = interrupts::without_interrupts(|| WATCHPOINTS.lock()[slot].take().is_some());
+    smp::call_on_all(load);
+    was_set
+}
+
+pub fn list() -> [Option<Watchpoint>; NUM_WATCHPOINTS] {
+    interrupts::without_interrupts(|| *WATCHPOINTS.lock())
+}
+
+/// The watchpoints that caused the current debug exception, by slot. They
+/// are read back from the calling CPU's debug registers rather than the
+/// list, so the handler takes no lock.
+pub fn triggered() -> impl Iterator<Item = (usize, Watchpoint)> {
+    let (status, dr7) = (Dr6::read(), Dr7::read());
+    (0..NUM_WATCHPOINTS).filter_map(move |slot| {
+        let n = register(slot);
+        let enabled = dr7.flags().contains(Dr7Flags::local_breakpoint_enable(n));
+        if !enabled || !status.contains(Dr6Flags::trap(n)) {
+            return None;
+        }
+        let access = match dr7.condition(n) {
+            BreakpointCondition::InstructionExecution => Access::Execute,
+            BreakpointCondition::DataWrites => Access::Write,
+            BreakpointCondition::DataReadsWrites => Access::ReadWrite,
+            BreakpointCondition::IoReadsWrites => return None,
+        };
+        let len = match dr7.size(n) {
+            BreakpointSize::Length1B => 1,
+            BreakpointSize::Length2B => 2,
+            BreakpointSize::Length4B => 4,
+            BreakpointSize::Length8B => 8,
+        };
+        let addr = read_address(n);
+        Some((slot, Watchpoint { addr, len, access }))
+    })
+}
+
+/// Clears the debug status, which the cpu never does by itself.
+pub fn acknowledge() {
+    unsafe { asm!("mov dr6, {}", in(reg) 0u64, options(nomem, nostack, preserves_flags)) };
+}
+
+fn read_address(n: DebugAddressRegisterNumber) -> u64 {
+    match n {
+        DebugAddressRegisterNumber::Dr0 => Dr0::read(),
+        DebugAddressRegisterNumber::Dr1 => Dr1::read(),
+        DebugAddressRegisterNumber::Dr2 => Dr2::read(),
+        DebugAddressRegisterNumber::Dr3 => Dr3::read(),
+    }
+}
+
+fn write_address(n: DebugAddressRegisterNumber, addr: u64) {
+    match n {
+        DebugAddressRegisterNumber::Dr0 => Dr0::write(addr),
+        DebugAddressRegisterNumber::Dr1 => Dr1::write(addr),
+        DebugAddressRegisterNumber::Dr2 => Dr2::write(addr),
+        DebugAddressRegisterNumber::Dr3 => Dr3::write(addr),
+    }
+}
+
+fn condition(access: Access) -> BreakpointCondition {
+    match access {
+        Access::Execute => BreakpointCondition::InstructionExecution,
+        Access::Write => BreakpointCondition::DataWrites,
+        Access::ReadWrite => BreakpointCondition::DataReadsWrites,
+    }
+}
+
+fn register(slot: usize) -> DebugAddressRegisterNumber {
+    DebugAddressRegisterNumber::new(slot as u8).expect("no such debug register")
+}
+
+pub struct WatchCommand;
+
+impl Command for WatchCommand {
+    fn name(&self) -> &'static str {
+        "watch"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[<addr> [w|rw|x] [len] | clear <slot>]"
+    }
+
+    fn help(&self) -> &'static str {
+        "list, set or clear hardware watchpoints"
+    }
+
+    fn run(&self, args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+        match *args {
+            [] => {
+                for (slot, watchpoint) in list().iter().enumerate() {
+                    if let Some(watchpoint) = watchpoint {
+                        writeln!(out, "  {}: {}", slot, watchpoint)?;
+                    }
+                }
+            }
+            ["clear", slot] => {
+                let slot = shell::parse_number(slot)? as usize;
+                if !clear(slot) {
+                    return Err(CommandError::Failed("no such watchpoint".to_string()));
+                }
+            }
+            [addr, ref rest @ ..] if rest.len() <= 2 => {
+                let addr = shell::parse_number(addr)?;
+                let access = match rest.first().copied() {
+                    None | Some("w") => Access::Write,
+                    Some("rw") => Access::ReadWrite,
+                    Some("x") => Access::Execute,
+                    Some(_) => return Err(CommandError::Usage),
+                };
+                let len = match rest.get(1) {
+                    Some(len) => shell::parse_number(len)? as usize,
+                    None if access == Access::Execute => 1,
+                    None => 8,
+                };
+                let slot = set(addr, len, access)
+                    .map_err(|error| CommandError::Failed(error.to_string()))?;
+                writeln!(out, "  {}: {}", slot, Watchpoint { addr, len, access })?;
+            }
+            _ => return Err(CommandError::Usage),
+        }
+        Ok(())
+    }
+}
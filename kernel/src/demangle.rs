@@ -0,0 +1,557 @@
+//! Demangling of the v0 symbol names rustc gives functions, like
+//! `_RNvNtCs8NwYtU1Mohg_4core9panicking9panic_fmt` for
+//! `core::panicking::panic_fmt`. Crate hashes are left out.
+
+use core::fmt::{self, Write};
+
+/// Bound on how deep paths and types nest, which backrefs could otherwise
+/// make endless.
+const MAX_DEPTH: u32 = 64;
+
+/// Displays a symbol name demangled, or as it is when it isn't a v0 name or
+/// uses something this doesn't understand.
+#[derive(Clone, Copy)]
+pub struct Demangle<'a>(pub &'a str);
+
+impl fmt::Display for Demangle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(mangled) = self.0.strip_prefix("_R") {
+            // a dry run first, so a name is never printed half demangled
+            if Printer::new(mangled, &mut Discard).symbol().is_ok() {
+                return Printer::new(mangled, f).symbol().map_err(|_| fmt::Error);
+            }
+        }
+        f.write_str(self.0)
+    }
+}
+
+struct Discard;
+
+impl Write for Discard {
+    fn write_str(&mut self, _: &str) -> fmt::Result {
+        Ok(())
+    }
+}
+
+struct Invalid;
+
+impl From<fmt::Error> for Invalid {
+    fn from(_: fmt::Error) -> Invalid {
+        Invalid
+    }
+}
+
+type Result<T = ()> = core::result::Result<T, Invalid>;
+
+/// Parses a mangled name, without the `_R`, and prints what it stands for
+/// while doing so.
+struct Printer<'a, 'w> {
+    input: &'a [u8],
+    pos: usize,
+    depth: u32,
+    /// Above zero while parsing parts that aren't printed, like the path of
+    /// an impl.
+    skipping: u32,
+    out: &'w mut dyn Write,
+}
+
+impl<'a, 'w> Printer<'a, 'w> {
+    fn new(input: &'a str, out: &'w mut dyn Write) -> Printer<'a, 'w> {
+        Printer {
+            input: input.as_bytes(),
+            pos: 0,
+            depth: 0,
+            skipping: 0,
+            out,
+        }
+    }
+
+    fn symbol(&mut self) -> Result {
+        // an encoding version would come first, and there is only one
+        if self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            return Err(Invalid);
+        }
+        self.path(true)?;
+        // the instantiating crate, then suffixes like `.llvm.123` that aren't
+        // part of the mangling
+        if self.peek().is_some_and(|b| b.is_ascii_uppercase()) {
+            self.skipping += 1;
+            self.path(false)?;
+            self.skipping -= 1;
+        }
+        Ok(())
+    }
+
+    fn print(&mut self, text: impl fmt::Display) -> Result {
+        if self.skipping == 0 {
+            write!(self.out, "{}", text)?;
+        }
+        Ok(())
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<u8> {
+        let b = self.peek().ok_or(Invalid)?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn eat(&mut self, b: u8) -> bool {
+        let matches = self.peek() == Some(b);
+        if matches {
+            self.pos += 1;
+        }
+        matches
+    }
+
+    /// Runs `parse` one level deeper, bounded by `MAX_DEPTH`.
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        if self.depth == MAX_DEPTH {
+            return Err(Invalid);
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    /// Runs `parse` at the position a backref points to, then carries on
+    /// after the backref.
+    fn backref<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        let start = self.pos - 1;
+        let target = self.base62()?;
+        // backrefs only point back, which with the depth bound ends loops
+        if target >= start as u64 {
+            return Err(Invalid);
+        }
+        let resume = core::mem::replace(&mut self.pos, target as usize);
+        let result = self.nested(parse);
+        self.pos = resume;
+        result
+    }
+
+    /// A base 62 number ended by `_`, where the empty number is 0 and the
+    /// rest are one more than their digits say.
+    fn base62(&mut self) -> Result<u64> {
+        if self.eat(b'_') {
+            return Ok(0);
+        }
+        let mut value = 0u64;
+        loop {
+            let digit = match self.next()? {
+                b'_' => return value.checked_add(1).ok_or(Invalid),
+                b @ b'0'..=b'9' => b - b'0',
+                b @ b'a'..=b'z' => b - b'a' + 10,
+                b @ b'A'..=b'Z' => b - b'A' + 36,
+                _ => return Err(Invalid),
+            };
+            value = value
+                .checked_mul(62)
+                .and_then(|value| value.checked_add(digit as u64))
+                .ok_or(Invalid)?;
+        }
+    }
+
+    fn decimal(&mut self) -> Result<usize> {
+        // there are no leading zeros, so a 0 ends the number
+        if self.eat(b'0') {
+            return Ok(0);
+        }
+        if !self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            return Err(Invalid);
+        }
+        let mut value = 0usize;
+        while let Some(b @ b'0'..=b'9') = self.peek() {
+            value = value
+                .checked_mul(10)
+                .and_then(|value| value.checked_add((b - b'0') as usize))
+                .ok_or(Invalid)?;
+            self.pos += 1;
+        }
+        Ok(value)
+    }
+
+    /// The optional `s` number that tells apart items of the same name.
+    fn disambiguator(&mut self) -> Result<u64> {
+        if self.eat(b's') {
+            Ok(self.base62()? + 1)
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn identifier(&mut self) -> Result<&'a str> {
+        // punycode would need decoding
+        if self.peek() == Some(b'u') {
+            return Err(Invalid);
+        }
+        let len = self.decimal()?;
+        // separates a name starting with a digit or `_` from its length
+        self.eat(b'_');
+        let bytes = self.input.get(self.pos..self.pos + len).ok_or(Invalid)?;
+        self.pos += len;
+        core::str::from_utf8(bytes).map_err(|_| Invalid)
+    }
+
+    /// Prints a path, with `::<` before generic arguments when it names a
+    /// value rather than a type.
+    fn path(&mut self, in_value: bool) -> Result {
+        if self.path_open_generics(in_value)? {
+            self.print('>')?;
+        }
+        Ok(())
+    }
+
+    /// Prints a path, leaving the generic argument list it ends with open for
+    /// the associated types of a `dyn` bound. Returns if there is one.
+    fn path_open_generics(&mut self, in_value: bool) -> Result<bool> {
+        self.nested(|this| match this.next()? {
+            b'C' => {
+                this.disambiguator()?;
+                let name = this.identifier()?;
+                this.print(name)?;
+                Ok(false)
+            }
+            b'N' => {
+                let namespace = this.next()?;
+                if !namespace.is_ascii_alphabetic() {
+                    return Err(Invalid);
+                }
+                this.path(in_value)?;
+                let disambiguator = this.disambiguator()?;
+                let name = this.identifier()?;
+                if namespace.is_ascii_uppercase() {
+                    let kind = match namespace {
+                        b'C' => "closure",
+                        b'S' => "shim",
+                        _ => return Err(Invalid),
+                    };
+                    this.print("::{")?;
+                    this.print(kind)?;
+                    if !name.is_empty() {
+                        this.print(':')?;
+                        this.print(name)?;
+                    }
+                    this.print('#')?;
+                    this.print(disambiguator)?;
+                    this.print('}')?;
+                } else if !name.is_empty() {
+                    this.print("::")?;
+                    this.print(name)?;
+                }
+                Ok(false)
+            }
+            b @ (b'M' | b'X' | b'Y') => {
+                if b != b'Y' {
+                    this.disambiguator()?;
+                    this.skipping += 1;
+                    let impl_path = this.path(false);
+                    this.skipping -= 1;
+                    impl_path?;
+                }
+                this.print('<')?;
+                this.ty()?;
+                if b != b'M' {
+                    this.print(" as ")?;
+                    this.path(false)?;
+                }
+                this.print('>')?;
+                Ok(false)
+            }
+            b'I' => {
+                this.path(in_value)?;
+                this.print(if in_value { "::<" } else { "<" })?;
+                this.generic_args()?;
+                Ok(true)
+            }
+            b'B' => this.backref(|this| this.path_open_generics(in_value)),
+            _ => Err(Invalid),
+        })
+    }
+
+    /// Prints generic arguments up to the `E` that ends them, separated by
+    /// commas.
+    fn generic_args(&mut self) -> Result {
+        let mut first = true;
+        while !self.eat(b'E') {
+            if !first {
+                self.print(", ")?;
+            }
+            first = false;
+            if self.eat(b'L') {
+                self.base62()?;
+                self.print("'_")?;
+            } else if self.eat(b'K') {
+                self.constant()?;
+            } else {
+                self.ty()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn ty(&mut self) -> Result {
+        self.nested(|this| {
+            let b = this.peek().ok_or(Invalid)?;
+            if let Some(name) = basic_type(b) {
+                this.pos += 1;
+                return this.print(name);
+            }
+            match b {
+                b'R' | b'Q' | b'P' | b'O' => {
+                    this.pos += 1;
+                    this.print(match b {
+                        b'R' => "&",
+                        b'Q' => "&mut ",
+                        b'P' => "*const ",
+                        _ => "*mut ",
+                    })?;
+                    if matches!(b, b'R' | b'Q') && this.eat(b'L') {
+                        this.base62()?;
+                    }
+                    this.ty()
+                }
+                b'A' | b'S' => {
+                    this.pos += 1;
+                    this.print('[')?;
+                    this.ty()?;
+                    if b == b'A' {
+                        this.print("; ")?;
+                        this.constant()?;
+                    }
+                    this.print(']')
+                }
+                b'T' => {
+                    this.pos += 1;
+                    this.print('(')?;
+                    let mut count = 0;
+                    while !this.eat(b'E') {
+                        if count > 0 {
+                            this.print(", ")?;
+                        }
+                        this.ty()?;
+                        count += 1;
+                    }
+                    this.print(if count == 1 { ",)" } else { ")" })
+                }
+                b'F' => {
+                    this.pos += 1;
+                    this.fn_sig()
+                }
+                b'D' => {
+                    this.pos += 1;
+                    this.dyn_bounds()
+                }
+                b'B' => {
+                    this.pos += 1;
+                    this.backref(Self::ty)
+                }
+                _ => this.path(false),
+            }
+        })
+    }
+
+    fn fn_sig(&mut self) -> Result {
+        if self.eat(b'G') {
+            self.base62()?;
+        }
+        if self.eat(b'U') {
+            self.print("unsafe ")?;
+        }
+        if self.eat(b'K') {
+            self.print("extern \"")?;
+            if self.eat(b'C') {
+                self.print('C')?;
+            } else {
+                // `-` is mangled as `_`
+                for (i, part) in self.identifier()?.split('_').enumerate() {
+                    if i > 0 {
+                        self.print('-')?;
+                    }
+                    self.print(part)?;
+                }
+            }
+            self.print("\" ")?;
+        }
+        self.print("fn(")?;
+        let mut first = true;
+        while !self.eat(b'E') {
+            if !first {
+                self.print(", ")?;
+            }
+            first = false;
+            self.ty()?;
+        }
+        self.print(')')?;
+        if self.eat(b'u') {
+            return Ok(());
+        }
+        self.print(" -> ")?;
+        self.ty()
+    }
+
+    fn dyn_bounds(&mut self) -> Result {
+        if self.eat(b'G') {
+            self.base62()?;
+        }
+        self.print("dyn ")?;
+        let mut first = true;
+        while !self.eat(b'E') {
+            if !first {
+                self.print(" + ")?;
+            }
+            first = false;
+            let mut open = self.path_open_generics(false)?;
+            while self.eat(b'p') {
+                self.print(if open { ", " } else { "<" })?;
+                open = true;
+                let name = self.identifier()?;
+                self.print(name)?;
+                self.print(" = ")?;
+                self.ty()?;
+            }
+            if open {
+                self.print('>')?;
+            }
+        }
+        // the object lifetime bound
+        if !self.eat(b'L') {
+            return Err(Invalid);
+        }
+        self.base62()?;
+        Ok(())
+    }
+
+    /// Prints a const generic argument. Only integers, `bool` and `char` are
+    /// understood.
+    fn constant(&mut self) -> Result {
+        self.nested(|this| match this.next()? {
+            b'p' => this.print('_'),
+            b'B' => this.backref(Self::constant),
+            ty @ (b'a' | b'b' | b'c' | b'h' | b'i' | b'j' | b'l' | b'm' | b'n' | b'o' | b's'
+            | b't' | b'x' | b'y') => {
+                let negative = this.eat(b'n');
+                let mut value = 0u64;
+                let mut digits = 0;
+                loop {
+                    let digit = match this.next()? {
+                        b'_' => break,
+                        b @ b'0'..=b'9' => b - b'0',
+                        b @ b'a'..=b'f' => b - b'a' + 10,
+                        _ => return Err(Invalid),
+                    };
+                    digits += 1;
+                    // 128 bit values don't fit, which is fine for a backtrace
+                    if digits > 16 {
+                        return Err(Invalid);
+                    }
+                    value = value << 4 | digit as u64;
+                }
+                match ty {
+                    b'b' => this.print(match value {
+                        0 => "false",
+                        1 => "true",
+                        _ => return Err(Invalid),
+                    }),
+                    b'c' => {
+                        let c = char::from_u32(value as u32).ok_or(Invalid)?;
+                        this.print(format_args!("{:?}", c))
+                    }
+                    _ => {
+                        if negative {
+                            this.print('-')?;
+                        }
+                        this.print(value)
+                    }
+                }
+            }
+            _ => Err(Invalid),
+        })
+    }
+}
+
+fn basic_type(b: u8) -> Option<&'static str> {
+    Some(match b {
+        b'a' => "i8",
+        b'b' => "bool",
+        b'c' => "char",
+        b'd' => "f64",
+        b'e' => "str",
+        b'f' => "f32",
+        b'h' => "u8",
+        b'i' => "isize",
+        b'j' => "usize",
+        b'l' => "i32",
+        b'm' => "u32",
+        b'n' => "i128",
+        b'o' => "u128",
+        b'p' => "_",
+        b's' => "i16",
+        b't' => "u16",
+        b'u' => "()",
+        b'v' => "...",
+        b'x' => "i64",
+        b'y' => "u64",
+        b'z' => "!",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    fn demangle(symbol: &str) -> alloc::string::String {
+        Demangle(symbol).to_string()
+    }
+
+    #[test_case]
+    fn function() {
+        assert_eq!(
+            demangle("_RNvNtCs8NwYtU1Mohg_4core9panicking9panic_fmt"),
+            "core::panicking::panic_fmt"
+        );
+    }
+
+    #[test_case]
+    fn closure_and_suffix() {
+        assert_eq!(
+            demangle("_RNCNvNtCs1234_6kernel6thread5spawn0.llvm.42"),
+            "kernel::thread::spawn::{closure#0}"
+        );
+    }
+
+    #[test_case]
+    fn inherent_impl_with_backrefs() {
+        assert_eq!(
+            demangle("_RNvMNtNtCs8NwYtU1Mohg_4core4cell4onceINtB2_8OnceCellmE3get"),
+            "<core::cell::once::OnceCell<u32>>::get"
+        );
+    }
+
+    #[test_case]
+    fn trait_impl_and_generic_function() {
+        assert_eq!(
+            demangle("_RINvXs_NtCs1_6kernel6threadNtB5_6ThreadNtNtCs2_4core3fmt7Display3fmtRhEB5_"),
+            "<kernel::thread::Thread as core::fmt::Display>::fmt::<&u8>"
+        );
+    }
+
+    #[test_case]
+    fn dyn_with_associated_type() {
+        assert_eq!(
+            demangle("_RINvCs1_6kernel3runDINtNtCs2_4core3ops6FnOnceTjEEp6OutputuEL_E"),
+            "kernel::run::<dyn core::ops::FnOnce<(usize,), Output = ()>>"
+        );
+    }
+
+    #[test_case]
+    fn not_v0() {
+        assert_eq!(demangle("memcpy"), "memcpy");
+        assert_eq!(demangle("_RNvC"), "_RNvC");
+    }
+}
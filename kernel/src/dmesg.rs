@@ -0,0 +1,146 @@
+use crate::serial::SERIAL1;
+use crate::shell::{self, Command, CommandError};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+const DMESG_SIZE: usize = 64 * 1024;
+
+/// Lives in .bss so messages are kept from the first line of `kernel_main`,
+/// before the heap or the console exist.
+static DMESG: Mutex<Ring> = Mutex::new(Ring {
+    buf: [0; DMESG_SIZE],
+    end: 0,
+    len: 0,
+});
+
+pub fn init() {
+    shell::register(&DmesgCommand);
+}
+
+/// Appends to the kernel message buffer, overwriting the oldest bytes once
+/// it is full.
+pub fn write(bytes: &[u8]) {
+    interrupts::without_interrupts(|| DMESG.lock().write(bytes));
+}
+
+/// Like `write`, but drops the bytes rather than wait for the buffer, for
+/// handlers that may have interrupted whoever holds it. Returns whether they
+/// were written.
+pub fn try_write(bytes: &[u8]) -> bool {
+    interrupts::without_interrupts(|| DMESG.try_lock().map(|mut dmesg| dmesg.write(bytes)))
+        .is_some()
+}
+
+/// Calls `f` with the buffered messages, oldest first, in at most two pieces.
+pub fn read(mut f: impl FnMut(&[u8])) {
+    interrupts::without_interrupts(|| {
+        let dmesg = DMESG.lock();
+        let (older, newer) = dmesg.contents();
+        f(older);
+        f(newer);
+    });
+}
+
+/// Like `read`, but only the last `lines` lines.
+pub fn read_tail(lines: usize, mut f: impl FnMut(&[u8])) {
+    interrupts::without_interrupts(|| {
+        let dmesg = DMESG.lock();
+        let (older, newer) = dmesg.contents();
+        // a trailing newline ends the last line rather than starting another
+        let len = older.len() + newer.len();
+        let byte_at = |i: usize| {
+            older
+                .get(i)
+                .copied()
+                .unwrap_or_else(|| newer[i - older.len()])
+        };
+        let mut newlines = 0;
+        let mut start = len;
+        for i in (0..len).rev() {
+            if byte_at(i) == b'\n' && i + 1 != len {
+                newlines += 1;
+                if newlines == lines {
+                    break;
+                }
+            }
+            start = i;
+        }
+        f(&older[start.min(older.len())..]);
+        f(&newer[start.saturating_sub(older.len())..]);
+    });
+}
+
+/// Writes the whole buffer to serial, for when the screen can't be trusted.
+pub fn dump() {
+    read(|bytes| {
+        let mut serial = SERIAL1.lock();
+        for &byte in bytes {
+            serial.send(byte);
+        }
+    });
+}
+
+/// # Safety
+/// Only for the panic path, where the holder of the lock will never run
+/// again.
+pub unsafe fn force_unlock() {
+    DMESG.force_unlock();
+}
+
+struct Ring {
+    buf: [u8; DMESG_SIZE],
+    end: usize,
+    len: usize,
+}
+
+impl Ring {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.buf[self.end] = byte;
+            self.end = (self.end + 1) % DMESG_SIZE;
+            self.len = (self.len + 1).min(DMESG_SIZE);
+        }
+    }
+
+    fn contents(&self) -> (&[u8], &[u8]) {
+        let start = (self.end + DMESG_SIZE - self.len) % DMESG_SIZE;
+        if start + self.len <= DMESG_SIZE {
+            (&self.buf[start..start + self.len], &[])
+        } else {
+            (&self.buf[start..], &self.buf[..self.end])
+        }
+    }
+}
+
+pub struct DmesgCommand;
+
+impl Command for DmesgCommand {
+    fn name(&self) -> &'static str {
+        "dmesg"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[lines]"
+    }
+
+    fn help(&self) -> &'static str {
+        "show kernel messages"
+    }
+
+    fn run(&self, args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+        // copied out first, so the buffer isn't held while the output is slow
+        let mut text = Vec::new();
+        match *args {
+            [] => read(|bytes| text.extend_from_slice(bytes)),
+            [lines] => read_tail(shell::parse_number(lines)? as usize, |bytes| {
+                text.extend_from_slice(bytes)
+            }),
+            _ => return Err(CommandError::Usage),
+        }
+        out.write_str(&String::from_utf8_lossy(&text))?;
+        Ok(())
+    }
+}
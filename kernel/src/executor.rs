@@ -0,0 +1,390 @@
+use crate::interrupt::ticks;
+use crate::thread::{self, ThreadId};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use alloc::vec::Vec;
+use core::convert::Infallible;
+use core::future::Future;
+use core::pin::{pin, Pin};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+
+pub const MAX_TASKS: usize = 1024;
+/// Each task in `TASKS`, the one out of it while it is polled, which can have
+/// woken itself, and the future passed to `block_on`.
+const QUEUE_SIZE: usize = MAX_TASKS + 2;
+/// Stands in the ready queue for the future passed to `block_on`.
+const BLOCK_ON: TaskId = TaskId(0);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+static NEXT_SLEEPER: AtomicU64 = AtomicU64::new(0);
+static TASKS: Mutex<BTreeMap<TaskId, Task>> = Mutex::new(BTreeMap::new());
+/// Tasks to poll, pushed by wakers that may be running in interrupt
+/// handlers, so it never allocates and is only locked with interrupts off.
+static READY: Mutex<ReadyQueue> = Mutex::new(ReadyQueue {
+    ids: [BLOCK_ON; QUEUE_SIZE],
+    head: 0,
+    len: 0,
+    parked: None,
+});
+/// The thread running `block_on`, the only one that polls tasks.
+static OWNER: Once<ThreadId> = Once::new();
+/// Pending `Sleep`s, checked by the timer interrupt.
+static SLEEPERS: Mutex<Vec<Sleeper>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+struct Task {
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
+    waker: Arc<TaskWaker>,
+}
+
+struct TaskWaker {
+    id: TaskId,
+    // set while the task is in the ready queue, so it is there at most once
+    queued: AtomicBool,
+}
+
+impl TaskWaker {
+    fn schedule(&self) {
+        if !self.queued.swap(true, Ordering::AcqRel) {
+            interrupts::without_interrupts(|| {
+                let mut ready = READY.lock();
+                ready.push(self.id);
+                if let Some(parked) = ready.parked.take() {
+                    thread::unblock(parked);
+                }
+            });
+        }
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.schedule();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.schedule();
+    }
+}
+
+struct ReadyQueue {
+    ids: [TaskId; QUEUE_SIZE],
+    head: usize,
+    len: usize,
+    /// The executor's thread while it is blocked waiting for a task.
+    parked: Option<ThreadId>,
+}
+
+impl ReadyQueue {
+    fn push(&mut self, id: TaskId) {
+        // each task is queued once, so this can't fill up
+        debug_assert!(self.len < self.ids.len());
+        self.ids[(self.head + self.len) % self.ids.len()] = id;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<TaskId> {
+        if self.len == 0 {
+            return None;
+        }
+        let id = self.ids[self.head];
+        self.head = (self.head + 1) % self.ids.len();
+        self.len -= 1;
+        Some(id)
+    }
+}
+
+/// The output of a spawned task, which is itself a future.
+pub struct JoinHandle<T> {
+    id: TaskId,
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+struct JoinState<T> {
+    output: Option<T>,
+    finished: bool,
+    waiter: Option<Waker>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().finished
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        let mut state = self.state.lock();
+        match state.output.take() {
+            Some(output) => Poll::Ready(output),
+            None => {
+                state.waiter = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Starts running `future` as a task the next time the executor gets to it.
+/// Dropping the handle leaves the task running.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let state = Arc::new(Mutex::new(JoinState {
+        output: None,
+        finished: false,
+        waiter: None,
+    }));
+    let task_state = state.clone();
+    let future = async move {
+        let output = future.await;
+        let mut state = task_state.lock();
+        state.output = Some(output);
+        state.finished = true;
+        if let Some(waiter) = state.waiter.take() {
+            waiter.wake();
+        }
+    };
+    let id = TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    let waker = Arc::new(TaskWaker {
+        id,
+        queued: AtomicBool::new(false),
+    });
+    let mut tasks = TASKS.lock();
+    assert!(tasks.len() < MAX_TASKS, "too many tasks");
+    tasks.insert(
+        id,
+        Task {
+            future: Box::pin(future),
+            waker: waker.clone(),
+        },
+    );
+    drop(tasks);
+    waker.schedule();
+    JoinHandle { id, state }
+}
+
+/// Runs tasks until `future` completes, blocking the thread whenever none of
+/// them are ready. Only for code outside of tasks, like `kernel_main` and
+/// tests, and always from the same thread, as a task is out of `TASKS` while
+/// it is polled.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let me = thread::current();
+    assert_eq!(
+        *OWNER.call_once(|| me),
+        me,
+        "executor run from a second thread"
+    );
+    let mut future = pin!(future);
+    let main = Arc::new(TaskWaker {
+        id: BLOCK_ON,
+        queued: AtomicBool::new(false),
+    });
+    let waker = Waker::from(main.clone());
+    main.schedule();
+    loop {
+        while let Some(id) = interrupts::without_interrupts(|| READY.lock().pop()) {
+            if id != BLOCK_ON {
+                run_task(id);
+                continue;
+            }
+            main.queued.store(false, Ordering::Release);
+            if let Poll::Ready(output) = future.as_mut().poll(&mut Context::from_waker(&waker)) {
+                return output;
+            }
+        }
+        prune_sleepers();
+        sleep_if_idle();
+    }
+}
+
+/// Runs tasks forever.
+pub fn run() -> ! {
+    match block_on(core::future::pending::<Infallible>()) {}
+}
+
+fn run_task(id: TaskId) {
+    // a task that finished can still have been woken
+    let Some(mut task) = TASKS.lock().remove(&id) else {
+        return;
+    };
+    task.waker.queued.store(false, Ordering::Release);
+    let waker = Waker::from(task.waker.clone());
+    // polled without `TASKS` held, so the task can spawn others
+    if task
+        .future
+        .as_mut()
+        .poll(&mut Context::from_waker(&waker))
+        .is_pending()
+    {
+        TASKS.lock().insert(id, task);
+    }
+}
+
+/// Blocks the thread until a task is woken unless one is ready, leaving the
+/// CPU to other threads and halting to the idle thread. The waker unblocks
+/// whichever thread it finds parked, so one that comes after the check isn't
+/// missed.
+fn sleep_if_idle() {
+    interrupts::without_interrupts(|| {
+        {
+            let mut ready = READY.lock();
+            if ready.len != 0 {
+                return;
+            }
+            ready.parked = Some(thread::current());
+        }
+        thread::block();
+    });
+}
+
+/// Completes once `ticks` timer ticks have passed.
+pub fn sleep(ticks: u64) -> Sleep {
+    Sleep {
+        deadline: crate::interrupt::ticks() + ticks,
+        id: None,
+    }
+}
+
+pub struct Sleep {
+    deadline: u64,
+    /// Its entry in `SLEEPERS`, once polled.
+    id: Option<u64>,
+}
+
+struct Sleeper {
+    id: u64,
+    deadline: u64,
+    waker: Waker,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        // no tick can pass between the check and adding the sleeper
+        interrupts::without_interrupts(|| {
+            if ticks() >= self.deadline {
+                return Poll::Ready(());
+            }
+            let mut sleepers = SLEEPERS.lock();
+            // polled again, maybe by another task
+            if let Some(sleeper) = self
+                .id
+                .and_then(|id| sleepers.iter_mut().find(|sleeper| sleeper.id == id))
+            {
+                if !sleeper.waker.will_wake(cx.waker()) {
+                    sleeper.waker = cx.waker().clone();
+                }
+                return Poll::Pending;
+            }
+            let id = NEXT_SLEEPER.fetch_add(1, Ordering::Relaxed);
+            sleepers.push(Sleeper {
+                id,
+                deadline: self.deadline,
+                waker: cx.waker().clone(),
+            });
+            self.id = Some(id);
+            Poll::Pending
+        })
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let sleeper = interrupts::without_interrupts(|| {
+                let mut sleepers = SLEEPERS.lock();
+                let index = sleepers.iter().position(|sleeper| sleeper.id == id)?;
+                Some(sleepers.swap_remove(index))
+            });
+            // the waker is dropped with the lock released and interrupts on
+            drop(sleeper);
+        }
+    }
+}
+
+/// Wakes the sleepers that are due. Called from the timer interrupt, which
+/// leaves dropping their wakers to `prune_sleepers`.
+pub fn wake_sleepers(now: u64) {
+    for sleeper in SLEEPERS.lock().iter() {
+        if sleeper.deadline <= now {
+            sleeper.waker.wake_by_ref();
+        }
+    }
+}
+
+fn prune_sleepers() {
+    interrupts::without_interrupts(|| {
+        let now = ticks();
+        SLEEPERS.lock().retain(|sleeper| sleeper.deadline > now);
+    });
+}
+
+/// The waker of a future waiting for an interrupt. Handlers only wake it by
+/// reference, so wakers are never freed in interrupt context.
+#[derive(Default)]
+pub struct WakerSlot(Mutex<Option<Waker>>);
+
+impl WakerSlot {
+    pub const fn new() -> WakerSlot {
+        WakerSlot(Mutex::new(None))
+    }
+
+    /// Call before checking for the event, so one that happens in between
+    /// still wakes `waker`.
+    pub fn register(&self, waker: &Waker) {
+        interrupts::without_interrupts(|| {
+            let mut slot = self.0.lock();
+            if !slot.as_ref().is_some_and(|old| old.will_wake(waker)) {
+                *slot = Some(waker.clone());
+            }
+        });
+    }
+
+    pub fn wake(&self) {
+        interrupts::without_interrupts(|| {
+            if let Some(waker) = self.0.lock().as_ref() {
+                waker.wake_by_ref();
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sleepers() -> usize {
+        interrupts::without_interrupts(|| SLEEPERS.lock().len())
+    }
+
+    #[test_case]
+    fn sleep_keeps_one_sleeper() {
+        let before = sleepers();
+        {
+            let mut sleeping = pin!(sleep(1000));
+            let mut cx = Context::from_waker(Waker::noop());
+            for _ in 0..3 {
+                assert!(sleeping.as_mut().poll(&mut cx).is_pending());
+            }
+            assert_eq!(sleepers(), before + 1);
+        }
+        assert_eq!(sleepers(), before);
+    }
+}
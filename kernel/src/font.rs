@@ -1,6 +1,94 @@
 // yes i put the entire font in ram. deal with it
-pub const FONT_DIM: (u8, u8) = (8, 16);
-pub const FONT: [u128; 256] = [
+const FONT_HEIGHT: usize = 16;
+
+/// The builtin 8x16 font, repacked as a psf1 file so it goes through the same
+/// loader as fonts from the ramdisk.
+pub static DEFAULT_FONT: [u8; 4 + 256 * FONT_HEIGHT] = to_psf1(&FONT);
+
+const fn to_psf1(font: &[u128; 256]) -> [u8; 4 + 256 * FONT_HEIGHT] {
+    let mut psf = [0; 4 + 256 * FONT_HEIGHT];
+    psf[0] = 0x36;
+    psf[1] = 0x04;
+    psf[3] = FONT_HEIGHT as u8;
+    let mut glyph = 0;
+    while glyph < 256 {
+        let mut row = 0;
+        while row < FONT_HEIGHT {
+            // rows are packed lsb first with the leftmost pixel in the low bit
+            let bits = (font[glyph] >> (row * 8)) as u8;
+            psf[4 + glyph * FONT_HEIGHT + row] = bits.reverse_bits();
+            row += 1;
+        }
+        glyph += 1;
+    }
+    psf
+}
+
+/// Line weights of the up, right, down and left arms of a box drawing
+/// character: 0 none, 1 light, 2 heavy, 3 double.
+pub fn box_arms(c: char) -> Option<[u8; 4]> {
+    Some(match c {
+        '─' => [0, 1, 0, 1],
+        '━' => [0, 2, 0, 2],
+        '│' => [1, 0, 1, 0],
+        '┃' => [2, 0, 2, 0],
+        '┌' => [0, 1, 1, 0],
+        '┏' => [0, 2, 2, 0],
+        '┐' => [0, 0, 1, 1],
+        '┓' => [0, 0, 2, 2],
+        '└' => [1, 1, 0, 0],
+        '┗' => [2, 2, 0, 0],
+        '┘' => [1, 0, 0, 1],
+        '┛' => [2, 0, 0, 2],
+        '├' => [1, 1, 1, 0],
+        '┣' => [2, 2, 2, 0],
+        '┤' => [1, 0, 1, 1],
+        '┫' => [2, 0, 2, 2],
+        '┬' => [0, 1, 1, 1],
+        '┳' => [0, 2, 2, 2],
+        '┴' => [1, 1, 0, 1],
+        '┻' => [2, 2, 0, 2],
+        '┼' => [1, 1, 1, 1],
+        '╋' => [2, 2, 2, 2],
+        '═' => [0, 3, 0, 3],
+        '║' => [3, 0, 3, 0],
+        '╔' => [0, 3, 3, 0],
+        '╗' => [0, 0, 3, 3],
+        '╚' => [3, 3, 0, 0],
+        '╝' => [3, 0, 0, 3],
+        '╠' => [3, 3, 3, 0],
+        '╣' => [3, 0, 3, 3],
+        '╦' => [0, 3, 3, 3],
+        '╩' => [3, 3, 0, 3],
+        '╬' => [3, 3, 3, 3],
+        '╴' => [0, 0, 0, 1],
+        '╵' => [1, 0, 0, 0],
+        '╶' => [0, 1, 0, 0],
+        '╷' => [0, 0, 1, 0],
+        _ => return None,
+    })
+}
+
+/// Whether pixel `(x, y)` of a `dim` sized cell is lit for the given arms.
+pub fn box_pixel(arms: [u8; 4], (width, height): (usize, usize), (x, y): (usize, usize)) -> bool {
+    let (cx, cy) = (width / 2, height / 2);
+    let on_stroke = |offset: usize, center: usize, weight: u8| match weight {
+        1 => offset == center,
+        2 => offset + 1 == center || offset == center,
+        3 => offset + 2 == center || offset == center + 2,
+        _ => false,
+    };
+    let [up, right, down, left] = arms;
+    // horizontal arms run through the vertical center, vertical ones through
+    // the horizontal center, each reaching from the edge to the middle
+    let double_pad = |weight: u8| if weight == 3 { 2 } else { 0 };
+    (on_stroke(y, cy, left) && x <= cx + double_pad(left))
+        || (on_stroke(y, cy, right) && x + double_pad(right) >= cx)
+        || (on_stroke(x, cx, up) && y <= cy + double_pad(up))
+        || (on_stroke(x, cx, down) && y + double_pad(down) >= cy)
+}
+
+const FONT: [u128; 256] = [
     0x0000007ee7e7ffe7e7cf9999c37e0000,
     0x00000000c36666666667fe0000000000,
     0x000000000003067f0c187f3060000000,
@@ -1,14 +1,18 @@
-use crate::interrupt::{pop_call_stack, push_call_stack};
-use bootloader_api::info::{self, FrameBufferInfo, Optional};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader_api::info::{self, FrameBufferInfo, Optional, PixelFormat};
 use bytemuck::{from_bytes, from_bytes_mut, Pod, Zeroable};
-use core::fmt::{self, Arguments, Write};
 use core::ops::{Index, IndexMut};
-use spin::Mutex;
-use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
 
-use crate::font::{FONT, FONT_DIM};
+use crate::font::{box_arms, box_pixel, DEFAULT_FONT};
+use crate::lockdep::SpinLock;
+use crate::memory::set_write_combining;
+use crate::psf::Font;
 
-pub static FRAMEBUFFER: Mutex<FrameBuffer> = Mutex::new(FrameBuffer::const_default());
+pub static FRAMEBUFFER: SpinLock<FrameBuffer> =
+    SpinLock::new("FRAMEBUFFER", FrameBuffer::const_default());
 
 pub const BLACK: Pixel = Pixel {
     b: 0x00,
@@ -22,7 +26,7 @@ pub const GREEN: Pixel = Pixel {
     r: 0x00,
 };
 
-#[derive(Zeroable, Pod, Clone, Copy)]
+#[derive(Zeroable, Pod, Clone, Copy, PartialEq, Eq, Debug)]
 #[repr(C)]
 pub struct Pixel {
     pub b: u8,
@@ -30,156 +34,245 @@ pub struct Pixel {
     pub r: u8,
 }
 
-#[macro_export]
-macro_rules! print {
-    ($($arg:tt)*) => ($crate::framebuffer::_print(format_args!($($arg)*)));
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorStyle {
+    Block,
+    Underline,
+    Bar,
 }
 
-#[macro_export]
-macro_rules! println {
-    () => ($crate::print!("\n"));
-    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+/// Bounds of the region of the back buffer not yet copied to video memory,
+/// as `(x0, y0)..(x1, y1)` in pixels.
+#[derive(Clone, Copy)]
+struct Rect {
+    x0: usize,
+    y0: usize,
+    x1: usize,
+    y1: usize,
 }
 
-#[doc(hidden)]
-pub fn _print(args: Arguments) {
-    FRAMEBUFFER
-        .lock()
-        .write_fmt(args)
-        .expect("failed to write FRAMEBUFFER")
+impl Rect {
+    fn union(self, other: Rect) -> Rect {
+        Rect {
+            x0: self.x0.min(other.x0),
+            y0: self.y0.min(other.y0),
+            x1: self.x1.max(other.x1),
+            y1: self.y1.max(other.y1),
+        }
+    }
 }
 
 pub struct FrameBuffer {
+    /// Shared so drawing can hold on to it while writing to the buffer.
+    font: Option<Arc<Font<'static>>>,
     pixel_dim: (usize, usize),
     pub term_dim: (usize, usize),
     stride: usize,
     bbp: usize,
-    pos: (usize, usize),
+    // video memory stores red first instead of blue
+    rgb: bool,
     buffer: Option<&'static mut [u8]>,
-    lines: [[u8; 256]; 64],
-    last_line: usize,
+    back: Vec<u8>,
+    dirty: Option<Rect>,
 }
 
 impl FrameBuffer {
     pub fn new(framebuffer: &'static mut Optional<info::FrameBuffer>) -> FrameBuffer {
         let framebuffer = framebuffer.as_mut().expect("failed to write FRAMEBUFFER");
         let info = framebuffer.info();
-        let buffer = Some(framebuffer.buffer_mut());
+        let buffer = framebuffer.buffer_mut();
+        set_write_combining(VirtAddr::from_ptr(buffer.as_ptr()), buffer.len() as u64);
+        let back = vec![0; buffer.len()];
+        let buffer = Some(buffer);
         let FrameBufferInfo {
             width,
             height,
             stride,
             bytes_per_pixel: bbp,
+            pixel_format,
             ..
         } = info;
+        let font = Font::parse(&DEFAULT_FONT).expect("builtin font is malformed");
 
         Self {
+            term_dim: term_dim((width, height), font.dim()),
+            font: Some(Arc::new(font)),
             pixel_dim: (width, height),
-            term_dim: (width / FONT_DIM.0 as usize, height / FONT_DIM.1 as usize),
             stride,
             bbp,
-            pos: (0, 0),
+            rgb: pixel_format == PixelFormat::Rgb,
             buffer,
-            lines: [[b' '; 256]; 64],
-            last_line: 0,
+            back,
+            dirty: None,
         }
     }
 
     pub fn fill(&mut self, color: Pixel) {
+        if self.pixel_dim.1 == 0 {
+            return;
+        }
+        let color = self.to_native(color);
         for x in 0..self.pixel_dim.0 {
-            for y in 0..self.pixel_dim.1 {
-                self[(x, y)] = color;
+            self[(x, 0)] = color;
+        }
+        let row = self.stride * self.bbp;
+        for y in 1..self.pixel_dim.1 {
+            self.back.copy_within(0..row, y * row);
+        }
+        self.mark_dirty(Rect {
+            x0: 0,
+            y0: 0,
+            x1: self.pixel_dim.0,
+            y1: self.pixel_dim.1,
+        });
+    }
+
+    /// Copies the scanlines touched since the last flush to video memory.
+    pub fn flush(&mut self) {
+        let (Some(dirty), Some(buffer)) = (self.dirty.take(), self.buffer.as_mut()) else {
+            return;
+        };
+        for y in dirty.y0..dirty.y1 {
+            let start = (y * self.stride + dirty.x0) * self.bbp;
+            let end = (y * self.stride + dirty.x1) * self.bbp;
+            buffer[start..end].copy_from_slice(&self.back[start..end]);
+        }
+    }
+
+    /// Converts between `Pixel`'s bgr layout and the layout of video memory,
+    /// which is the same swap both ways.
+    pub fn to_native(&self, color: Pixel) -> Pixel {
+        if self.rgb {
+            Pixel {
+                b: color.r,
+                g: color.g,
+                r: color.b,
             }
+        } else {
+            color
         }
     }
 
+    fn mark_dirty(&mut self, rect: Rect) {
+        self.dirty = Some(self.dirty.map_or(rect, |dirty| dirty.union(rect)));
+    }
+
+    /// Switches to `font`, clearing the screen since the cell grid changes
+    /// with the glyph size.
+    pub fn set_font(&mut self, font: Font<'static>) {
+        self.term_dim = term_dim(self.pixel_dim, font.dim());
+        self.font = Some(Arc::new(font));
+        self.fill(BLACK);
+    }
+
+    pub fn pixel_dim(&self) -> (usize, usize) {
+        self.pixel_dim
+    }
+
     pub const fn const_default() -> FrameBuffer {
         FrameBuffer {
+            font: None,
             pixel_dim: (0, 0),
             term_dim: (0, 0),
             stride: 0,
             bbp: 0,
-            pos: (0, 0),
+            rgb: false,
             buffer: None,
-            lines: [[b' '; 256]; 64],
-            last_line: 0,
+            back: Vec::new(),
+            dirty: None,
         }
     }
 
-    pub fn write_str(&mut self, bytes: &[u8]) {
-        for &byte in bytes {
-            match byte {
-                b'\n' => {
-                    if self.pos.1 < self.term_dim.1 - 1 {
-                        for i in self.pos.0..self.term_dim.0 {
-                            self.lines[self.last_line as usize][i as usize] = b' ';
-                        }
-                        self.pos.1 += 1;
-                        self.pos.0 = 0;
-                        self.last_line = (self.last_line + 1) % self.term_dim.1;
-                    } else {
-                        self.last_line = (self.last_line + 1) % self.term_dim.1;
-                        for i in 0..self.lines[0].len() {
-                            self.lines[self.last_line + 2][i] = b' ';
-                        }
-                        for (y, line) in (0..self.lines.len())
-                            .cycle()
-                            .skip(self.last_line - 1)
-                            .take(self.term_dim.1)
-                            .enumerate()
-                        {
-                            for (x, c) in self.lines[line].into_iter().enumerate() {
-                                self.render_char(c, Some((x, y)));
-                            }
-                        }
-                    }
-                }
-                _ => {
-                    self.render_char(byte, None);
-                    self.lines[self.pos.1][self.pos.0] = byte;
-                    self.pos.0 = (self.pos.0 + 1).min(self.term_dim.0 - 1);
-                    if self.pos.0 == self.term_dim.0 - 1 {
-                        self.pos.0 = 0;
-                        self.pos.1 += 1;
-                    }
-                }
-            }
-        }
+    /// Moves the text grid up by `lines` rows, shifting the back buffer
+    /// instead of redrawing all the glyphs. The rows uncovered at the bottom
+    /// are cleared.
+    pub fn scroll(&mut self, lines: usize) {
+        let Some(font) = &self.font else {
+            return;
+        };
+        let font_height = font.dim().1;
+        let rows = self.term_dim.1;
+        let lines = lines.min(rows);
+        let line_bytes = self.stride * self.bbp * font_height;
+        self.back
+            .copy_within(line_bytes * lines..line_bytes * rows, 0);
+        self.back[line_bytes * (rows - lines)..line_bytes * rows].fill(0);
+        self.mark_dirty(Rect {
+            x0: 0,
+            y0: 0,
+            x1: self.pixel_dim.0,
+            y1: font_height * rows,
+        });
     }
 
-    fn render_char(&mut self, byte: u8, pos: Option<(usize, usize)>) {
-        let (x, y) = pos.unwrap_or(self.pos);
-        for i in 0..FONT_DIM.0 as usize {
-            for j in 0..FONT_DIM.1 as usize {
-                let index = (x * FONT_DIM.0 as usize + i, y * FONT_DIM.1 as usize + j);
-                self[index] = if FONT[byte as usize] & 1 << (j * FONT_DIM.0 as usize + i) > 0 {
-                    GREEN
-                } else {
-                    BLACK
-                }
+    /// Draws `c` into text cell `(x, y)`, with a cursor of the given style
+    /// on top.
+    pub fn render_cell(&mut self, c: char, (x, y): (usize, usize), cursor: Option<CursorStyle>) {
+        let Some(font) = self.font.clone() else {
+            return;
+        };
+        let (width, height) = font.dim();
+        let glyph = glyph(&font, c);
+        for i in 0..width {
+            for j in 0..height {
+                let lit = match glyph {
+                    Glyph::Font(index) => font.pixel(index, (i, j)),
+                    Glyph::Box(arms) => box_pixel(arms, (width, height), (i, j)),
+                };
+                let lit = match cursor {
+                    None => lit,
+                    Some(CursorStyle::Block) => !lit,
+                    Some(CursorStyle::Underline) => lit || j + 2 >= height,
+                    Some(CursorStyle::Bar) => lit || i < 2,
+                };
+                self[(x * width + i, y * height + j)] = if lit { GREEN } else { BLACK };
             }
         }
     }
 }
 
-impl Write for FrameBuffer {
-    fn write_str(&mut self, s: &str) -> fmt::Result {
-        self.write_str(s.as_bytes());
-        Ok(())
+enum Glyph {
+    Font(usize),
+    Box([u8; 4]),
+}
+
+/// Picks what to draw for `c`: the font's own glyph, a synthesized box
+/// drawing character, or the replacement glyph.
+fn glyph(font: &Font, c: char) -> Glyph {
+    if let Some(index) = font.glyph_for(c) {
+        Glyph::Font(index)
+    } else if let Some(arms) = box_arms(c) {
+        Glyph::Box(arms)
+    } else {
+        let index = font
+            .glyph_for(char::REPLACEMENT_CHARACTER)
+            .or_else(|| font.glyph_for('?'))
+            .unwrap_or(0);
+        Glyph::Font(index)
     }
 }
 
+fn term_dim(pixel_dim: (usize, usize), font_dim: (usize, usize)) -> (usize, usize) {
+    (pixel_dim.0 / font_dim.0, pixel_dim.1 / font_dim.1)
+}
+
 impl Index<(usize, usize)> for FrameBuffer {
     type Output = Pixel;
     fn index(&self, (x, y): (usize, usize)) -> &Pixel {
         let pixel_index = (y * self.stride + x) * self.bbp;
-        from_bytes(&self.buffer.as_ref().unwrap()[pixel_index..pixel_index + 3])
+        from_bytes(&self.back[pixel_index..pixel_index + 3])
     }
 }
 
 impl IndexMut<(usize, usize)> for FrameBuffer {
     fn index_mut(&mut self, (x, y): (usize, usize)) -> &mut Pixel {
+        self.mark_dirty(Rect {
+            x0: x,
+            y0: y,
+            x1: x + 1,
+            y1: y + 1,
+        });
         let pixel_index = (y * self.stride + x) * self.bbp;
-        from_bytes_mut(&mut self.buffer.as_mut().unwrap()[pixel_index..pixel_index + 3])
+        from_bytes_mut(&mut self.back[pixel_index..pixel_index + 3])
     }
 }
@@ -0,0 +1,764 @@
+use crate::thread::{self, SavedRegisters, ThreadId};
+use crate::{cmdline, memory, smp};
+use alloc::format;
+use core::arch::{asm, global_asm};
+use core::fmt::{self, Write};
+use log::info;
+use spin::Mutex;
+use uart_16550::SerialPort;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::VirtAddr;
+
+const COM2: u16 = 0x2F8;
+const PACKET_SIZE: usize = 4096;
+const MAX_BREAKPOINTS: usize = 32;
+const INT3: u8 = 0xcc;
+const TRAP_FLAG: u64 = 1 << 8;
+const SIGTRAP: u8 = 5;
+const BREAKPOINT_VECTOR: u64 = 3;
+/// rax through gs in the order of gdb's amd64 `g` packet. The x87 and SSE
+/// registers after them are left out, which gdb shows as unavailable.
+const NUM_REGS: usize = 24;
+
+static STUB: Mutex<GdbStub> = Mutex::new(GdbStub {
+    port: unsafe { SerialPort::new(COM2) },
+    packet: [0; PACKET_SIZE],
+    reply: Reply {
+        buf: [0; PACKET_SIZE],
+        len: 0,
+    },
+    breakpoints: Breakpoints([None; MAX_BREAKPOINTS]),
+    running: false,
+    selected: None,
+});
+
+// Entries for #BP and #DB that save every register into a `TrapFrame`, so
+// gdb can read and change them before they are restored.
+global_asm!(
+    ".global gdbstub_breakpoint_entry",
+    "gdbstub_breakpoint_entry:",
+    "push 3",
+    "jmp gdbstub_trap_common",
+    ".global gdbstub_debug_entry",
+    "gdbstub_debug_entry:",
+    "push 1",
+    "jmp gdbstub_trap_common",
+    "gdbstub_trap_common:",
+    "push r15",
+    "push r14",
+    "push r13",
+    "push r12",
+    "push r11",
+    "push r10",
+    "push r9",
+    "push r8",
+    "push rbp",
+    "push rdi",
+    "push rsi",
+    "push rdx",
+    "push rcx",
+    "push rbx",
+    "push rax",
+    "mov rdi, rsp",
+    "mov rbx, rsp",
+    "and rsp, -16",
+    "call {trap}",
+    "mov rsp, rbx",
+    "pop rax",
+    "pop rbx",
+    "pop rcx",
+    "pop rdx",
+    "pop rsi",
+    "pop rdi",
+    "pop rbp",
+    "pop r8",
+    "pop r9",
+    "pop r10",
+    "pop r11",
+    "pop r12",
+    "pop r13",
+    "pop r14",
+    "pop r15",
+    "add rsp, 8",
+    "iretq",
+    trap = sym trap,
+);
+
+extern "C" {
+    fn gdbstub_breakpoint_entry();
+    fn gdbstub_debug_entry();
+}
+
+macro_rules! read_segment {
+    ($reg:literal) => {{
+        let value: u16;
+        unsafe { asm!(concat!("mov {:x}, ", $reg), out(reg) value, options(nomem, nostack)) };
+        value
+    }};
+}
+
+/// Registers of the code that trapped, as pushed by the entry stubs and the
+/// cpu.
+#[repr(C)]
+pub struct TrapFrame {
+    rax: u64,
+    rbx: u64,
+    rcx: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    rbp: u64,
+    r8: u64,
+    r9: u64,
+    r10: u64,
+    r11: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    vector: u64,
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+}
+
+impl TrapFrame {
+    /// Register `n` in gdb's numbering, and its size in bytes.
+    fn register(&self, n: usize) -> Option<(u64, usize)> {
+        let segment = |value: u16| Some((value as u64, 4));
+        match n {
+            0..=15 => Some((*self.gpr(n)?, 8)),
+            16 => Some((self.rip, 8)),
+            17 => Some((self.rflags, 4)),
+            18 => segment(self.cs as u16),
+            19 => segment(self.ss as u16),
+            20 => segment(read_segment!("ds")),
+            21 => segment(read_segment!("es")),
+            22 => segment(read_segment!("fs")),
+            23 => segment(read_segment!("gs")),
+            _ => None,
+        }
+    }
+
+    /// Segment registers can't be changed, writes to them are dropped.
+    fn set_register(&mut self, n: usize, value: u64) -> bool {
+        match n {
+            0..=15 => {
+                if let Some(gpr) = self.gpr_mut(n) {
+                    *gpr = value;
+                }
+            }
+            16 => self.rip = value,
+            17 => self.rflags = value,
+            18..NUM_REGS => {}
+            _ => return false,
+        }
+        true
+    }
+
+    fn gpr(&self, n: usize) -> Option<&u64> {
+        Some(match n {
+            0 => &self.rax,
+            1 => &self.rbx,
+            2 => &self.rcx,
+            3 => &self.rdx,
+            4 => &self.rsi,
+            5 => &self.rdi,
+            6 => &self.rbp,
+            7 => &self.rsp,
+            8 => &self.r8,
+            9 => &self.r9,
+            10 => &self.r10,
+            11 => &self.r11,
+            12 => &self.r12,
+            13 => &self.r13,
+            14 => &self.r14,
+            15 => &self.r15,
+            _ => return None,
+        })
+    }
+
+    fn gpr_mut(&mut self, n: usize) -> Option<&mut u64> {
+        Some(match n {
+            0 => &mut self.rax,
+            1 => &mut self.rbx,
+            2 => &mut self.rcx,
+            3 => &mut self.rdx,
+            4 => &mut self.rsi,
+            5 => &mut self.rdi,
+            6 => &mut self.rbp,
+            7 => &mut self.rsp,
+            8 => &mut self.r8,
+            9 => &mut self.r9,
+            10 => &mut self.r10,
+            11 => &mut self.r11,
+            12 => &mut self.r12,
+            13 => &mut self.r13,
+            14 => &mut self.r14,
+            15 => &mut self.r15,
+            _ => return None,
+        })
+    }
+}
+
+/// Whether the stub is on, from the `gdb` boot option.
+pub fn is_enabled() -> bool {
+    cmdline::flag("gdb")
+}
+
+/// Handler address for the #BP entry of the IDT.
+pub fn breakpoint_entry() -> VirtAddr {
+    VirtAddr::new(gdbstub_breakpoint_entry as *const () as u64)
+}
+
+/// Handler address for the #DB entry of the IDT.
+pub fn debug_entry() -> VirtAddr {
+    VirtAddr::new(gdbstub_debug_entry as *const () as u64)
+}
+
+/// Sets up COM2 and stops at a breakpoint until gdb attaches and continues.
+pub fn init() {
+    if !is_enabled() {
+        return;
+    }
+    interrupts::without_interrupts(|| STUB.lock().port.init());
+    info!("waiting for gdb on COM2");
+    interrupts::int3();
+}
+
+extern "C" fn trap(frame: &mut TrapFrame) {
+    let mut stub = STUB.lock();
+    // the rest of the machine stands still while gdb looks at it
+    smp::pause_others();
+    stub.selected = None;
+    // report a breakpoint we planted at its own address, not the one after
+    if frame.vector == BREAKPOINT_VECTOR && stub.breakpoints.contains(frame.rip - 1) {
+        frame.rip -= 1;
+    }
+    frame.rflags &= !TRAP_FLAG;
+    // gdb is waiting for a stop reply if it resumed us, otherwise it asks
+    // with `?` once it attaches
+    if stub.running {
+        stub.reply.clear();
+        let _ = write!(stub.reply, "S{:02x}", SIGTRAP);
+        stub.send_reply();
+    }
+    loop {
+        let len = stub.receive_packet();
+        match stub.handle(frame, len) {
+            Action::Reply => stub.send_reply(),
+            Action::Continue => break,
+            Action::Step => {
+                frame.rflags |= TRAP_FLAG;
+                break;
+            }
+            Action::Detach => {
+                stub.send_reply();
+                stub.breakpoints.clear();
+                stub.running = false;
+                smp::resume_others();
+                return;
+            }
+        }
+    }
+    stub.running = true;
+    smp::resume_others();
+}
+
+enum Action {
+    Reply,
+    Continue,
+    Step,
+    Detach,
+}
+
+struct GdbStub {
+    port: SerialPort,
+    packet: [u8; PACKET_SIZE],
+    reply: Reply,
+    breakpoints: Breakpoints,
+    // whether gdb resumed the kernel and expects to hear when it stops
+    running: bool,
+    /// The registers of the thread gdb picked with `Hg`, if it isn't the one
+    /// that trapped.
+    selected: Option<SavedRegisters>,
+}
+
+impl GdbStub {
+    /// Waits for a packet with a valid checksum, acknowledging it, and
+    /// returns its length.
+    fn receive_packet(&mut self) -> usize {
+        loop {
+            while self.port.receive() != b'$' {}
+            let (mut len, mut sum) = (0, 0u8);
+            loop {
+                let byte = self.port.receive();
+                if byte == b'#' {
+                    break;
+                }
+                sum = sum.wrapping_add(byte);
+                if len < PACKET_SIZE {
+                    self.packet[len] = byte;
+                    len += 1;
+                }
+            }
+            let checksum = [self.port.receive(), self.port.receive()];
+            if parse_hex(&checksum) == Some(sum as u64) {
+                self.port.send_raw(b'+');
+                return len;
+            }
+            self.port.send_raw(b'-');
+        }
+    }
+
+    /// Sends the reply until gdb acknowledges it.
+    fn send_reply(&mut self) {
+        let reply = &self.reply.buf[..self.reply.len];
+        let sum = reply.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        loop {
+            self.port.send_raw(b'$');
+            for &byte in reply {
+                self.port.send_raw(byte);
+            }
+            self.port.send_raw(b'#');
+            self.port.send_raw(HEX[(sum >> 4) as usize]);
+            self.port.send_raw(HEX[(sum & 0xf) as usize]);
+            if self.port.receive() == b'+' {
+                return;
+            }
+        }
+    }
+
+    fn handle(&mut self, frame: &mut TrapFrame, len: usize) -> Action {
+        let packet = &self.packet[..len];
+        let reply = &mut self.reply;
+        reply.clear();
+        let Some((&command, args)) = packet.split_first() else {
+            return Action::Reply;
+        };
+        let result = match command {
+            b'?' => write!(reply, "S{:02x}", SIGTRAP),
+            b'g' => {
+                for n in 0..NUM_REGS {
+                    if let Some((value, size)) = register(frame, self.selected.as_ref(), n) {
+                        reply.register(value, size);
+                    }
+                }
+                Ok(())
+            }
+            // another thread's registers can only be looked at
+            b'G' | b'P' if self.selected.is_some() => reply.error(),
+            b'G' => {
+                let mut args = args;
+                for n in 0..NUM_REGS {
+                    let Some((_, size)) = frame.register(n) else {
+                        continue;
+                    };
+                    let Some(value) = args.get(..size * 2).and_then(parse_hex_le) else {
+                        break;
+                    };
+                    frame.set_register(n, value);
+                    args = &args[size * 2..];
+                }
+                reply.ok()
+            }
+            b'p' => {
+                let n = parse_hex(args).map(|n| n as usize);
+                match n.and_then(|n| register(frame, self.selected.as_ref(), n)) {
+                    Some((value, size)) => {
+                        reply.register(value, size);
+                        Ok(())
+                    }
+                    None => reply.error(),
+                }
+            }
+            b'P' => {
+                let written = split2(args, b'=').and_then(|(n, value)| {
+                    let n = parse_hex(n)? as usize;
+                    Some(frame.set_register(n, parse_hex_le(value)?))
+                });
+                match written {
+                    Some(true) => reply.ok(),
+                    _ => reply.error(),
+                }
+            }
+            b'm' => {
+                let range = split2(args, b',')
+                    .and_then(|(addr, len)| Some((parse_hex(addr)?, parse_hex(len)? as usize)));
+                let mut buf = [0u8; PACKET_SIZE / 2];
+                let read = range.and_then(|(addr, len)| {
+                    let bytes = &mut buf[..len.min(PACKET_SIZE / 2)];
+                    read_memory(addr, bytes).then_some((addr, bytes))
+                });
+                match read {
+                    Some((addr, bytes)) => {
+                        self.breakpoints.hide(addr, bytes);
+                        for &byte in bytes.iter() {
+                            reply.hex_le(byte as u64, 1);
+                        }
+                        Ok(())
+                    }
+                    _ => reply.error(),
+                }
+            }
+            b'M' => {
+                let written = split2(args, b':').and_then(|(range, data)| {
+                    let (addr, len) = split2(range, b',')?;
+                    let (addr, len) = (parse_hex(addr)?, parse_hex(len)? as usize);
+                    let mut buf = [0u8; PACKET_SIZE / 2];
+                    let bytes = buf.get_mut(..len)?;
+                    for (i, byte) in bytes.iter_mut().enumerate() {
+                        *byte = parse_hex(data.get(i * 2..i * 2 + 2)?)? as u8;
+                    }
+                    Some(write_memory(addr, bytes))
+                });
+                match written {
+                    Some(true) => reply.ok(),
+                    _ => reply.error(),
+                }
+            }
+            // software breakpoints, other kinds are left to gdb
+            b'Z' | b'z' if args.first() == Some(&b'0') => {
+                let addr = args.split(|&b| b == b',').nth(1).and_then(parse_hex);
+                let done = addr.is_some_and(|addr| match command {
+                    b'Z' => self.breakpoints.insert(addr),
+                    _ => self.breakpoints.remove(addr),
+                });
+                if done {
+                    reply.ok()
+                } else {
+                    reply.error()
+                }
+            }
+            b'c' | b's' => {
+                if let Some(addr) = parse_hex(args) {
+                    frame.rip = addr;
+                }
+                return match command {
+                    b'c' => Action::Continue,
+                    _ => Action::Step,
+                };
+            }
+            b'D' => {
+                let _ = reply.ok();
+                return Action::Detach;
+            }
+            b'k' => {
+                self.breakpoints.clear();
+                return Action::Continue;
+            }
+            // `Hg` picks the thread whose registers `g` and `p` read, which
+            // for a thread that isn't running are what it saved when it was
+            // switched out. Stepping and continuing are for all threads.
+            b'H' => match args.split_first() {
+                Some((b'g', id)) => {
+                    let current = gdb_thread_id(thread::current());
+                    match parse_thread_id(id) {
+                        Some(id) if id == 0 || id == current => {
+                            self.selected = None;
+                            reply.ok()
+                        }
+                        Some(id) => {
+                            let saved = thread::try_list().and_then(|threads| {
+                                let thread = threads
+                                    .iter()
+                                    .find(|thread| gdb_thread_id(thread.entity.id) == id)?;
+                                thread::saved_registers(thread.entity.id)
+                            });
+                            match saved {
+                                Some(saved) => {
+                                    self.selected = Some(saved);
+                                    reply.ok()
+                                }
+                                None => reply.error(),
+                            }
+                        }
+                        None => reply.error(),
+                    }
+                }
+                _ => reply.ok(),
+            },
+            b'T' => {
+                let alive = parse_hex(args).is_some_and(|id| {
+                    thread::try_list().is_some_and(|threads| {
+                        threads
+                            .iter()
+                            .any(|thread| gdb_thread_id(thread.entity.id) == id)
+                    })
+                });
+                if alive {
+                    reply.ok()
+                } else {
+                    reply.error()
+                }
+            }
+            b'q' => {
+                if args.starts_with(b"Supported") {
+                    write!(reply, "PacketSize={:x}", PACKET_SIZE)
+                } else if args == b"Attached" {
+                    reply.write_str("1")
+                } else if args == b"C" {
+                    write!(reply, "QC{:x}", gdb_thread_id(thread::current()))
+                } else if args == b"fThreadInfo" {
+                    // the whole list fits in one reply
+                    match thread::try_list() {
+                        Some(threads) => threads.iter().enumerate().try_for_each(|(i, thread)| {
+                            let sep = if i == 0 { 'm' } else { ',' };
+                            write!(reply, "{}{:x}", sep, gdb_thread_id(thread.entity.id))
+                        }),
+                        None => write!(reply, "m{:x}", gdb_thread_id(thread::current())),
+                    }
+                } else if args == b"sThreadInfo" {
+                    reply.write_str("l")
+                } else if let Some(id) = args.strip_prefix(b"ThreadExtraInfo,") {
+                    let thread = parse_hex(id).and_then(|id| {
+                        thread::try_list()?
+                            .into_iter()
+                            .find(|thread| gdb_thread_id(thread.entity.id) == id)
+                    });
+                    match thread {
+                        Some(thread) => {
+                            let info = format!("{} ({})", thread.name, thread.state.name());
+                            for byte in info.bytes() {
+                                reply.hex_le(byte as u64, 1);
+                            }
+                            Ok(())
+                        }
+                        None => reply.error(),
+                    }
+                } else {
+                    Ok(())
+                }
+            }
+            // an empty reply tells gdb the packet isn't supported
+            _ => Ok(()),
+        };
+        if result.is_err() {
+            reply.clear();
+        }
+        Action::Reply
+    }
+}
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+/// gdb numbers threads from 1, kernel threads start at 0.
+fn gdb_thread_id(id: ThreadId) -> u64 {
+    id.as_u64() + 1
+}
+
+/// A thread id from `H`, with -1, meaning all threads, as 0, meaning any.
+fn parse_thread_id(id: &[u8]) -> Option<u64> {
+    if id == b"-1" {
+        Some(0)
+    } else {
+        parse_hex(id)
+    }
+}
+
+/// Register `n` and its size, of the trapped thread or the one `Hg`
+/// selected, whose value is `None` if its thread didn't save it.
+fn register(
+    frame: &TrapFrame,
+    selected: Option<&SavedRegisters>,
+    n: usize,
+) -> Option<(Option<u64>, usize)> {
+    let (value, size) = frame.register(n)?;
+    match selected {
+        None => Some((Some(value), size)),
+        Some(saved) => Some((saved_register(saved, n), size)),
+    }
+}
+
+/// Register `n` in gdb's numbering, if `thread_switch` saved it.
+fn saved_register(saved: &SavedRegisters, n: usize) -> Option<u64> {
+    match n {
+        1 => Some(saved.rbx),
+        6 => Some(saved.rbp),
+        7 => Some(saved.rsp),
+        12 => Some(saved.r12),
+        13 => Some(saved.r13),
+        14 => Some(saved.r14),
+        15 => Some(saved.r15),
+        16 => Some(saved.rip),
+        _ => None,
+    }
+}
+
+struct Reply {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.len < PACKET_SIZE {
+            self.buf[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    /// Appends the low `size` bytes of `value` as hex, in target byte order.
+    fn hex_le(&mut self, value: u64, size: usize) {
+        for i in 0..size {
+            let byte = (value >> (i * 8)) as u8;
+            self.push(HEX[(byte >> 4) as usize]);
+            self.push(HEX[(byte & 0xf) as usize]);
+        }
+    }
+
+    /// Appends a register of `size` bytes, as `x`s if its value is unknown.
+    fn register(&mut self, value: Option<u64>, size: usize) {
+        match value {
+            Some(value) => self.hex_le(value, size),
+            None => (0..size * 2).for_each(|_| self.push(b'x')),
+        }
+    }
+
+    fn ok(&mut self) -> fmt::Result {
+        self.write_str("OK")
+    }
+
+    fn error(&mut self) -> fmt::Result {
+        self.write_str("E01")
+    }
+}
+
+impl Write for Reply {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.bytes().for_each(|byte| self.push(byte));
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Breakpoint {
+    addr: u64,
+    original: u8,
+}
+
+struct Breakpoints([Option<Breakpoint>; MAX_BREAKPOINTS]);
+
+impl Breakpoints {
+    fn contains(&self, addr: u64) -> bool {
+        self.0.iter().flatten().any(|bp| bp.addr == addr)
+    }
+
+    fn insert(&mut self, addr: u64) -> bool {
+        if self.contains(addr) {
+            return true;
+        }
+        let mut original = [0];
+        let Some(slot) = self.0.iter_mut().find(|slot| slot.is_none()) else {
+            return false;
+        };
+        if !read_memory(addr, &mut original) || !write_memory(addr, &[INT3]) {
+            return false;
+        }
+        *slot = Some(Breakpoint {
+            addr,
+            original: original[0],
+        });
+        true
+    }
+
+    fn remove(&mut self, addr: u64) -> bool {
+        let Some(slot) = self
+            .0
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|bp| bp.addr == addr))
+        else {
+            return false;
+        };
+        let bp = slot.take().unwrap();
+        write_memory(bp.addr, &[bp.original])
+    }
+
+    fn clear(&mut self) {
+        for bp in self.0.iter_mut().filter_map(Option::take) {
+            write_memory(bp.addr, &[bp.original]);
+        }
+    }
+
+    /// Puts the original bytes back into a copy of memory at `addr`, so gdb
+    /// never sees the int3s.
+    fn hide(&self, addr: u64, bytes: &mut [u8]) {
+        for bp in self.0.iter().flatten() {
+            if let Some(byte) = bp
+                .addr
+                .checked_sub(addr)
+                .and_then(|offset| bytes.get_mut(offset as usize))
+            {
+                *byte = bp.original;
+            }
+        }
+    }
+}
+
+fn is_accessible(addr: u64, len: usize) -> bool {
+    let Some(last) = addr.checked_add(len.saturating_sub(1) as u64) else {
+        return false;
+    };
+    (addr & !0xfff..=last)
+        .step_by(4096)
+        .all(|page| VirtAddr::try_new(page).is_ok_and(memory::is_mapped))
+}
+
+fn read_memory(addr: u64, buf: &mut [u8]) -> bool {
+    if !is_accessible(addr, buf.len()) {
+        return false;
+    }
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = unsafe { core::ptr::read_volatile((addr + i as u64) as *const u8) };
+    }
+    true
+}
+
+/// Writes with write protection off, so breakpoints can go into the
+/// read-only kernel text.
+fn write_memory(addr: u64, bytes: &[u8]) -> bool {
+    if !is_accessible(addr, bytes.len()) {
+        return false;
+    }
+    let cr0 = Cr0::read();
+    unsafe {
+        Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+        for (i, &byte) in bytes.iter().enumerate() {
+            core::ptr::write_volatile((addr + i as u64) as *mut u8, byte);
+        }
+        Cr0::write(cr0);
+    }
+    true
+}
+
+fn split2(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let at = bytes.iter().position(|&b| b == separator)?;
+    Some((&bytes[..at], &bytes[at + 1..]))
+}
+
+fn parse_hex(bytes: &[u8]) -> Option<u64> {
+    if bytes.is_empty() || bytes.len() > 16 {
+        return None;
+    }
+    bytes.iter().try_fold(0u64, |value, &b| {
+        Some(value << 4 | (b as char).to_digit(16)? as u64)
+    })
+}
+
+/// Parses hex in target byte order, as registers are sent.
+fn parse_hex_le(bytes: &[u8]) -> Option<u64> {
+    if !bytes.len().is_multiple_of(2) || bytes.len() > 16 {
+        return None;
+    }
+    bytes
+        .chunks(2)
+        .rev()
+        .try_fold(0u64, |value, pair| Some(value << 8 | parse_hex(pair)?))
+}
@@ -1,52 +1,38 @@
-use crate::print;
-use spin::Once;
+use alloc::alloc::{alloc_zeroed, handle_alloc_error, Layout};
+use alloc::boxed::Box;
 use x86_64::instructions::{
     segmentation::{Segment, CS},
     tables::load_tss,
 };
 use x86_64::structures::{
-    gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
+    gdt::{Descriptor, GlobalDescriptorTable},
     tss::TaskStateSegment,
 };
 use x86_64::VirtAddr;
 
-static TSS: Once<TaskStateSegment> = Once::new();
-static GDT: Once<(GlobalDescriptorTable, Selectors)> = Once::new();
 pub const DOUBLE_FAULT_1ST_INDEX: u16 = 0;
+const DOUBLE_FAULT_STACK_SIZE: usize = 0x5000;
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
-}
-
+/// Builds and loads a GDT and TSS for the calling CPU. Every CPU needs its
+/// own TSS, which the CPU marks busy, and its own double fault stack.
 pub fn init_gdt() {
-    TSS.call_once(|| {
-        let mut tss = TaskStateSegment::new();
-        const STACK_SIZE: usize = 0x5000;
-        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-        let stack_start = VirtAddr::from_ptr(unsafe { &raw const STACK });
-        let stack_end = stack_start + STACK_SIZE as u64;
-        tss.interrupt_stack_table[DOUBLE_FAULT_1ST_INDEX as usize] = stack_end;
-        tss
-    });
-
-    GDT.call_once(|| {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.append(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.append(Descriptor::tss_segment(TSS.get().unwrap()));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
-    });
+    let layout = Layout::from_size_align(DOUBLE_FAULT_STACK_SIZE, 16).unwrap();
+    let stack = unsafe { alloc_zeroed(layout) };
+    if stack.is_null() {
+        handle_alloc_error(layout);
+    }
+    let stack_start = VirtAddr::from_ptr(stack);
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_1ST_INDEX as usize] =
+        stack_start + DOUBLE_FAULT_STACK_SIZE as u64;
+    let tss = Box::leak(Box::new(tss));
 
-    GDT.get().unwrap().0.load();
+    let gdt = Box::leak(Box::new(GlobalDescriptorTable::new()));
+    let code_selector = gdt.append(Descriptor::kernel_code_segment());
+    let tss_selector = gdt.append(Descriptor::tss_segment(tss));
+    gdt.load();
     unsafe {
-        CS::set_reg(GDT.get().unwrap().1.code_selector);
-        load_tss(GDT.get().unwrap().1.tss_selector);
+        CS::set_reg(code_selector);
+        load_tss(tss_selector);
     }
 }
@@ -0,0 +1,442 @@
+use crate::framebuffer::{FrameBuffer, Pixel, BLACK};
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// Anything that can be drawn on pixel by pixel. Coordinates taken by the
+/// drawing methods are signed and clipped against `dim`, so shapes may hang
+/// off the edges.
+pub trait Canvas {
+    fn dim(&self) -> (usize, usize);
+    fn pixel(&self, x: usize, y: usize) -> Pixel;
+    fn set_pixel(&mut self, x: usize, y: usize, color: Pixel);
+
+    fn put_pixel(&mut self, (x, y): (isize, isize), color: Pixel) {
+        let (width, height) = self.dim();
+        if x >= 0 && y >= 0 && (x as usize) < width && (y as usize) < height {
+            self.set_pixel(x as usize, y as usize, color);
+        }
+    }
+
+    /// Draws `color` over the existing pixel with opacity `alpha`.
+    fn blend_pixel(&mut self, (x, y): (isize, isize), color: Pixel, alpha: u8) {
+        let (width, height) = self.dim();
+        if x < 0 || y < 0 || x as usize >= width || y as usize >= height {
+            return;
+        }
+        let (x, y) = (x as usize, y as usize);
+        let blended = blend(self.pixel(x, y), color, alpha);
+        self.set_pixel(x, y, blended);
+    }
+
+    /// Steps along the longer axis, rounding the other coordinate, and
+    /// only over the steps that can land on the canvas.
+    fn draw_line(&mut self, from: (isize, isize), to: (isize, isize), color: Pixel) {
+        let (width, height) = self.dim();
+        let (x0, y0) = (from.0 as i128, from.1 as i128);
+        let (dx, dy) = (to.0 as i128 - x0, to.1 as i128 - y0);
+        let steps = dx.abs().max(dy.abs());
+        if steps == 0 {
+            self.put_pixel(from, color);
+            return;
+        }
+        let (x_first, x_last) = visible_steps(x0, dx, steps, width);
+        let (y_first, y_last) = visible_steps(y0, dy, steps, height);
+        for step in x_first.max(y_first).max(0)..=x_last.min(y_last).min(steps) {
+            let x = x0 + lerp(dx, step, steps);
+            let y = y0 + lerp(dy, step, steps);
+            self.put_pixel((x as isize, y as isize), color);
+        }
+    }
+
+    fn draw_rect(&mut self, (x, y): (isize, isize), (width, height): (usize, usize), color: Pixel) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        // an edge past isize::MAX is off the canvas either way
+        let (x1, y1) = (
+            x.saturating_add_unsigned(width - 1),
+            y.saturating_add_unsigned(height - 1),
+        );
+        self.draw_line((x, y), (x1, y), color);
+        self.draw_line((x, y1), (x1, y1), color);
+        self.draw_line((x, y), (x, y1), color);
+        self.draw_line((x1, y), (x1, y1), color);
+    }
+
+    fn fill_rect(&mut self, pos: (isize, isize), dim: (usize, usize), color: Pixel) {
+        let Some(((x0, y0), (x1, y1))) = clip(self.dim(), pos, dim) else {
+            return;
+        };
+        for y in y0..y1 {
+            for x in x0..x1 {
+                self.set_pixel(x, y, color);
+            }
+        }
+    }
+
+    fn draw_circle(&mut self, (cx, cy): (isize, isize), radius: usize, color: Pixel) {
+        let (mut x, mut y) = (radius as isize, 0);
+        let mut err = 1 - x;
+        while x >= y {
+            for (px, py) in [
+                (x, y),
+                (y, x),
+                (-y, x),
+                (-x, y),
+                (-x, -y),
+                (-y, -x),
+                (y, -x),
+                (x, -y),
+            ] {
+                self.put_pixel((cx + px, cy + py), color);
+            }
+            y += 1;
+            if err < 0 {
+                err += 2 * y + 1;
+            } else {
+                x -= 1;
+                err += 2 * (y - x) + 1;
+            }
+        }
+    }
+
+    /// Only visits the rows on the canvas, in 128 bits, so any radius is
+    /// fine.
+    fn fill_circle(&mut self, (cx, cy): (isize, isize), radius: usize, color: Pixel) {
+        let (cx, cy, r) = (cx as i128, cy as i128, radius as i128);
+        let height = self.dim().1 as i128;
+        let saturate = |x: i128| x.clamp(isize::MIN as i128, isize::MAX as i128) as isize;
+        for y in (cy - r).max(0)..=(cy + r).min(height - 1) {
+            let dy = (y - cy).unsigned_abs();
+            let half = isqrt(radius as u128 * radius as u128 - dy * dy) as i128;
+            self.draw_line(
+                (saturate(cx - half), y as isize),
+                (saturate(cx + half), y as isize),
+                color,
+            );
+        }
+    }
+
+    /// Outlines the closed polygon through `points`.
+    fn draw_polygon(&mut self, points: &[(isize, isize)], color: Pixel) {
+        for (i, &from) in points.iter().enumerate() {
+            self.draw_line(from, points[(i + 1) % points.len()], color);
+        }
+    }
+
+    /// Fills the closed polygon through `points` using the even-odd rule.
+    fn fill_polygon(&mut self, points: &[(isize, isize)], color: Pixel) {
+        let (Some(top), Some(bottom)) = (
+            points.iter().map(|p| p.1).min(),
+            points.iter().map(|p| p.1).max(),
+        ) else {
+            return;
+        };
+        let height = self.dim().1 as isize;
+        let mut crossings = Vec::new();
+        for y in top.max(0)..=bottom.min(height - 1) {
+            crossings.clear();
+            for (i, &(x0, y0)) in points.iter().enumerate() {
+                let (x1, y1) = points[(i + 1) % points.len()];
+                if (y0 <= y) != (y1 <= y) {
+                    crossings.push(x0 + (y - y0) * (x1 - x0) / (y1 - y0));
+                }
+            }
+            crossings.sort_unstable();
+            for span in crossings.chunks_exact(2) {
+                self.draw_line((span[0], y), (span[1], y), color);
+            }
+        }
+    }
+
+    /// Copies `src` onto this canvas with its top-left corner at `pos`,
+    /// skipping whatever falls outside.
+    fn blit<S: Canvas + ?Sized>(&mut self, src: &S, pos: (isize, isize)) {
+        self.blit_blend(src, pos, u8::MAX);
+    }
+
+    /// Draws `src` stretched to `dim` with its top-left corner at `pos`,
+    /// sampling the nearest source pixel.
+    fn blit_scaled<S: Canvas + ?Sized>(
+        &mut self,
+        src: &S,
+        pos: (isize, isize),
+        dim: (usize, usize),
+    ) {
+        let (src_width, src_height) = src.dim();
+        let Some(((x0, y0), (x1, y1))) = clip(self.dim(), pos, dim) else {
+            return;
+        };
+        // in 128 bits, as the offsets times the source size can overflow
+        let scale = |offset: i128, src_len: usize, len: usize| {
+            (offset as u128 * src_len as u128 / len as u128) as usize
+        };
+        for y in y0..y1 {
+            let src_y = scale(y as i128 - pos.1 as i128, src_height, dim.1);
+            for x in x0..x1 {
+                let src_x = scale(x as i128 - pos.0 as i128, src_width, dim.0);
+                self.set_pixel(x, y, src.pixel(src_x, src_y));
+            }
+        }
+    }
+
+    /// Like `blit`, but draws `src` with opacity `alpha`.
+    fn blit_blend<S: Canvas + ?Sized>(&mut self, src: &S, pos: (isize, isize), alpha: u8) {
+        let Some(((x0, y0), (x1, y1))) = clip(self.dim(), pos, src.dim()) else {
+            return;
+        };
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let color = src.pixel((x as isize - pos.0) as usize, (y as isize - pos.1) as usize);
+                if alpha == u8::MAX {
+                    self.set_pixel(x, y, color);
+                } else {
+                    let blended = blend(self.pixel(x, y), color, alpha);
+                    self.set_pixel(x, y, blended);
+                }
+            }
+        }
+    }
+}
+
+/// An off-screen canvas backed by RAM.
+pub struct Surface {
+    width: usize,
+    height: usize,
+    pixels: Vec<Pixel>,
+}
+
+impl Surface {
+    pub fn new(width: usize, height: usize) -> Surface {
+        Surface {
+            width,
+            height,
+            pixels: vec![BLACK; width * height],
+        }
+    }
+
+    pub fn from_pixels(width: usize, height: usize, pixels: Vec<Pixel>) -> Surface {
+        assert_eq!(pixels.len(), width * height, "surface size mismatch");
+        Surface {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn pixels(&self) -> &[Pixel] {
+        &self.pixels
+    }
+}
+
+impl Canvas for Surface {
+    fn dim(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> Pixel {
+        self.pixels[y * self.width + x]
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, color: Pixel) {
+        self.pixels[y * self.width + x] = color;
+    }
+}
+
+impl Canvas for FrameBuffer {
+    fn dim(&self) -> (usize, usize) {
+        self.pixel_dim()
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> Pixel {
+        self.to_native(self[(x, y)])
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, color: Pixel) {
+        self[(x, y)] = self.to_native(color);
+    }
+}
+
+pub fn blend(dst: Pixel, src: Pixel, alpha: u8) -> Pixel {
+    let mix = |d: u8, s: u8| {
+        ((s as u16 * alpha as u16 + d as u16 * (255 - alpha as u16) + 127) / 255) as u8
+    };
+    Pixel {
+        b: mix(dst.b, src.b),
+        g: mix(dst.g, src.g),
+        r: mix(dst.r, src.r),
+    }
+}
+
+/// Intersects the rectangle at `pos` of size `dim` with a canvas of size
+/// `bounds`, returning the visible corners.
+fn clip(
+    bounds: (usize, usize),
+    pos: (isize, isize),
+    dim: (usize, usize),
+) -> Option<((usize, usize), (usize, usize))> {
+    let x0 = pos.0.max(0) as usize;
+    let y0 = pos.1.max(0) as usize;
+    // in 128 bits, as the far corner can be past isize::MAX
+    let x1 = (pos.0 as i128 + dim.0 as i128).clamp(0, bounds.0 as i128) as usize;
+    let y1 = (pos.1 as i128 + dim.1 as i128).clamp(0, bounds.1 as i128) as usize;
+    (x0 < x1 && y0 < y1).then_some(((x0, y0), (x1, y1)))
+}
+
+/// The steps of a line whose coordinate, starting at `start` and moving
+/// `delta` over `steps` steps, lies within a pixel of `0..len`.
+fn visible_steps(start: i128, delta: i128, steps: i128, len: usize) -> (i128, i128) {
+    let (low, high) = (-1 - start, len as i128 - start);
+    match delta {
+        0 if low < 0 && high > 0 => (0, steps),
+        0 => (1, 0),
+        _ => {
+            // step * delta must lie in low * steps..=high * steps
+            let (low, high) = if delta > 0 {
+                (low, high)
+            } else {
+                (-high, -low)
+            };
+            let delta = delta.abs();
+            (
+                -scale_down(-low, steps, delta),
+                scale_down(high, steps, delta),
+            )
+        }
+    }
+}
+
+/// `delta * step / steps` rounded to the nearest, halves up, for
+/// `|delta| <= steps` and `0 <= step <= steps`. The product may take all of
+/// a u128.
+fn lerp(delta: i128, step: i128, steps: i128) -> i128 {
+    let product = delta.unsigned_abs() * step as u128;
+    let steps = steps as u128;
+    let (whole, rest) = ((product / steps) as i128, product % steps);
+    if delta >= 0 {
+        whole + (2 * rest >= steps) as i128
+    } else {
+        -whole - (2 * rest > steps) as i128
+    }
+}
+
+/// `limit * steps / delta` rounded down, for `0 < delta <= steps`. Both can
+/// be close to 2^64, so `limit` is first clamped to within `delta + 1` of 0,
+/// which leaves results outside `0..=steps` outside, and the product is
+/// taken in a u128.
+fn scale_down(limit: i128, steps: i128, delta: i128) -> i128 {
+    let limit = limit.clamp(-delta - 1, delta + 1);
+    let product = limit.unsigned_abs() * steps as u128;
+    if limit >= 0 {
+        (product / delta as u128) as i128
+    } else {
+        -(product.div_ceil(delta as u128) as i128)
+    }
+}
+
+fn isqrt(n: u128) -> u128 {
+    if n == 0 {
+        return 0;
+    }
+    let mut x = n;
+    let mut y = x.div_ceil(2);
+    while y < x {
+        x = y;
+        y = (x + n / x) / 2;
+    }
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framebuffer::GREEN;
+
+    fn lit(surface: &Surface) -> Vec<(usize, usize)> {
+        let (width, height) = surface.dim();
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .filter(|&(x, y)| surface.pixel(x, y) != BLACK)
+            .collect()
+    }
+
+    #[test_case]
+    fn line_inside() {
+        let mut surface = Surface::new(8, 8);
+        surface.draw_line((1, 1), (5, 3), GREEN);
+        let lit = lit(&surface);
+        assert!(lit.contains(&(1, 1)) && lit.contains(&(5, 3)));
+        assert_eq!(lit.len(), 5);
+    }
+
+    #[test_case]
+    fn line_off_the_edges() {
+        let mut surface = Surface::new(8, 8);
+        surface.draw_line((-100, 4), (100, 4), GREEN);
+        assert_eq!(lit(&surface), (0..8).map(|x| (x, 4)).collect::<Vec<_>>());
+        let mut surface = Surface::new(8, 8);
+        surface.draw_line((-4, -4), (isize::MAX / 2, isize::MAX / 2), GREEN);
+        assert_eq!(lit(&surface), (0..8).map(|i| (i, i)).collect::<Vec<_>>());
+    }
+
+    #[test_case]
+    fn line_off_screen() {
+        let mut surface = Surface::new(8, 8);
+        surface.draw_line((-5, -1), (20, -1), GREEN);
+        surface.draw_line((-8, 4), (4, -8), GREEN);
+        assert!(lit(&surface).is_empty());
+    }
+
+    #[test_case]
+    fn rect_clipped() {
+        let mut surface = Surface::new(4, 4);
+        surface.fill_rect((-2, 2), (4, 10), GREEN);
+        assert_eq!(lit(&surface), [(0, 2), (1, 2), (0, 3), (1, 3)]);
+        let mut surface = Surface::new(4, 4);
+        surface.fill_rect((4, 0), (2, 2), GREEN);
+        surface.fill_rect((0, 0), (0, 3), GREEN);
+        assert!(lit(&surface).is_empty());
+    }
+
+    #[test_case]
+    fn huge_shapes() {
+        let mut surface = Surface::new(4, 4);
+        surface.fill_rect((-2, -2), (usize::MAX, usize::MAX), GREEN);
+        assert_eq!(lit(&surface).len(), 16);
+        let mut surface = Surface::new(4, 4);
+        surface.draw_rect((1, 1), (usize::MAX, usize::MAX), GREEN);
+        assert_eq!(lit(&surface), [(1, 1), (2, 1), (3, 1), (1, 2), (1, 3)]);
+        let mut surface = Surface::new(4, 4);
+        surface.fill_circle((isize::MIN, 2), usize::MAX, GREEN);
+        assert_eq!(lit(&surface).len(), 16);
+        let mut surface = Surface::new(4, 4);
+        surface.fill_circle((2, isize::MAX), usize::MAX / 4, GREEN);
+        assert!(lit(&surface).is_empty());
+        let mut surface = Surface::new(4, 4);
+        surface.blit_scaled(
+            &Surface::from_pixels(1, 1, vec![GREEN]),
+            (-1, -1),
+            (usize::MAX, 2),
+        );
+        assert_eq!(lit(&surface), [(0, 0), (1, 0), (2, 0), (3, 0)]);
+    }
+
+    #[test_case]
+    fn blit_clipped() {
+        let src = Surface::from_pixels(2, 2, vec![GREEN, BLACK, BLACK, GREEN]);
+        let mut surface = Surface::new(3, 3);
+        surface.blit(&src, (-1, -1));
+        assert_eq!(lit(&surface), [(0, 0)]);
+        let mut surface = Surface::new(3, 3);
+        surface.blit(&src, (2, 2));
+        assert_eq!(lit(&surface), [(2, 2)]);
+    }
+
+    #[test_case]
+    fn blit_scaled() {
+        let src = Surface::from_pixels(2, 1, vec![GREEN, BLACK]);
+        let mut surface = Surface::new(4, 2);
+        surface.blit_scaled(&src, (0, 0), (4, 2));
+        assert_eq!(lit(&surface), [(0, 0), (1, 0), (0, 1), (1, 1)]);
+    }
+}
@@ -0,0 +1,426 @@
+use crate::framebuffer::Pixel;
+use crate::graphics::Surface;
+use crate::ramdisk;
+use alloc::vec::Vec;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageError {
+    UnknownFormat,
+    Unsupported,
+    Malformed,
+    NotFound,
+}
+
+/// Decodes an uncompressed BMP, TGA or binary/ASCII PPM/PGM image, picking
+/// the format from its header.
+pub fn decode(bytes: &[u8]) -> Result<Surface, ImageError> {
+    match bytes {
+        [b'B', b'M', ..] => decode_bmp(bytes),
+        [b'P', b'2' | b'3' | b'5' | b'6', ..] => decode_pnm(bytes),
+        // tga has no magic, go by the image type byte
+        [_, _, 1..=3, ..] if bytes.len() >= 18 => decode_tga(bytes),
+        _ => Err(ImageError::UnknownFormat),
+    }
+}
+
+pub fn load(name: &str) -> Result<Surface, ImageError> {
+    decode(ramdisk::file(name).ok_or(ImageError::NotFound)?)
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> Result<u16, ImageError> {
+    let field = bytes.get(at..at + 2).ok_or(ImageError::Malformed)?;
+    Ok(u16::from_le_bytes([field[0], field[1]]))
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> Result<u32, ImageError> {
+    let field = bytes.get(at..at + 4).ok_or(ImageError::Malformed)?;
+    Ok(u32::from_le_bytes(field.try_into().unwrap()))
+}
+
+fn gray(level: u8) -> Pixel {
+    Pixel {
+        b: level,
+        g: level,
+        r: level,
+    }
+}
+
+/// Reads one pixel stored as little endian bgr(a), 16 bit 5-5-5 or 8 bit
+/// gray, the layouts shared by bmp and tga.
+fn bgr(data: &[u8], bits: usize) -> Pixel {
+    match bits {
+        8 => gray(data[0]),
+        15 | 16 => {
+            let value = u16::from_le_bytes([data[0], data[1]]);
+            let channel = |shift: u16| (((value >> shift) & 0x1f) * 255 / 31) as u8;
+            Pixel {
+                b: channel(0),
+                g: channel(5),
+                r: channel(10),
+            }
+        }
+        _ => Pixel {
+            b: data[0],
+            g: data[1],
+            r: data[2],
+        },
+    }
+}
+
+fn decode_bmp(bytes: &[u8]) -> Result<Surface, ImageError> {
+    let data_offset = u32_at(bytes, 10)? as usize;
+    let header_size = u32_at(bytes, 14)? as usize;
+    let width = u32_at(bytes, 18)? as i32;
+    let height = u32_at(bytes, 22)? as i32;
+    let bits = u16_at(bytes, 28)? as usize;
+    let compression = u32_at(bytes, 30)?;
+    // BI_RGB, or BI_BITFIELDS which is only accepted for the usual masks,
+    // which follow the 40 byte header or sit at the same place in later ones
+    let usual_masks = || -> Result<bool, ImageError> {
+        Ok([u32_at(bytes, 54)?, u32_at(bytes, 58)?, u32_at(bytes, 62)?]
+            == [0x00ff_0000, 0x0000_ff00, 0x0000_00ff])
+    };
+    if !(compression == 0 || compression == 3 && bits == 32 && usual_masks()?) {
+        return Err(ImageError::Unsupported);
+    }
+    if width <= 0 || height == 0 {
+        return Err(ImageError::Malformed);
+    }
+    let (width, top_down) = (width as usize, height < 0);
+    let height = height.unsigned_abs() as usize;
+    if width.saturating_mul(height) > bytes.len().saturating_mul(8) {
+        return Err(ImageError::Malformed);
+    }
+
+    let palette = if bits <= 8 {
+        let colors = match u32_at(bytes, 46)? {
+            0 => 1 << bits,
+            colors => colors as usize,
+        };
+        let start = 14 + header_size;
+        bytes
+            .get(start..start + colors * 4)
+            .ok_or(ImageError::Malformed)?
+            .chunks_exact(4)
+            .map(|entry| bgr(entry, 24))
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    let row_size = (bits * width).div_ceil(32) * 4;
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let row = if top_down { y } else { height - 1 - y };
+        let start = data_offset + row * row_size;
+        let row = bytes
+            .get(start..start + row_size)
+            .ok_or(ImageError::Malformed)?;
+        for x in 0..width {
+            let pixel = match bits {
+                1 | 4 | 8 => {
+                    let bit = x * bits;
+                    let index = (row[bit / 8] >> (8 - bits - bit % 8)) & ((1 << bits) - 1) as u8;
+                    *palette.get(index as usize).ok_or(ImageError::Malformed)?
+                }
+                16 | 24 | 32 => bgr(&row[x * bits / 8..], bits),
+                _ => return Err(ImageError::Unsupported),
+            };
+            pixels.push(pixel);
+        }
+    }
+    Ok(Surface::from_pixels(width, height, pixels))
+}
+
+fn decode_tga(bytes: &[u8]) -> Result<Surface, ImageError> {
+    let id_len = bytes[0] as usize;
+    let has_color_map = bytes[1] == 1;
+    let image_type = bytes[2];
+    let map_first = u16_at(bytes, 3)? as usize;
+    let map_len = u16_at(bytes, 5)? as usize;
+    let map_bits = bytes[7] as usize;
+    let width = u16_at(bytes, 12)? as usize;
+    let height = u16_at(bytes, 14)? as usize;
+    let bits = bytes[16] as usize;
+    let top_down = bytes[17] & 0x20 > 0;
+
+    // checked up front, as zero bit pixels would pass the size check below
+    if !matches!((image_type, bits), (1, 8) | (2, 15 | 16 | 24 | 32) | (3, 8)) {
+        return Err(ImageError::Unsupported);
+    }
+    if image_type == 1 && !has_color_map {
+        return Err(ImageError::Malformed);
+    }
+    if has_color_map && !matches!(map_bits, 8 | 15 | 16 | 24 | 32) {
+        return Err(ImageError::Unsupported);
+    }
+
+    let map_start = 18 + id_len;
+    let map_size = if has_color_map {
+        map_len * map_bits.div_ceil(8)
+    } else {
+        0
+    };
+    let color_map = bytes
+        .get(map_start..map_start + map_size)
+        .ok_or(ImageError::Malformed)?;
+    let pixel_size = bits.div_ceil(8);
+    let data_start = map_start + map_size;
+    let data = bytes
+        .get(data_start..data_start + width * height * pixel_size)
+        .ok_or(ImageError::Malformed)?;
+
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let row = if top_down { y } else { height - 1 - y };
+        for x in 0..width {
+            let at = (row * width + x) * pixel_size;
+            let pixel = if image_type == 1 {
+                let entry = (data[at] as usize)
+                    .checked_sub(map_first)
+                    .ok_or(ImageError::Malformed)?;
+                let size = map_bits.div_ceil(8);
+                let entry = color_map
+                    .get(entry * size..(entry + 1) * size)
+                    .ok_or(ImageError::Malformed)?;
+                bgr(entry, map_bits)
+            } else {
+                bgr(&data[at..], bits)
+            };
+            pixels.push(pixel);
+        }
+    }
+    Ok(Surface::from_pixels(width, height, pixels))
+}
+
+fn decode_pnm(bytes: &[u8]) -> Result<Surface, ImageError> {
+    let (ascii, channels) = match bytes[1] {
+        b'2' => (true, 1),
+        b'3' => (true, 3),
+        b'5' => (false, 1),
+        _ => (false, 3),
+    };
+    let mut tokens = PnmTokens { bytes, at: 2 };
+    let width = tokens.number()?;
+    let height = tokens.number()?;
+    let max = tokens.number()?;
+    if max == 0 || max > u16::MAX as usize || width.saturating_mul(height) > bytes.len() {
+        return Err(ImageError::Malformed);
+    }
+    // a single whitespace byte separates the header from binary samples
+    let mut at = tokens.at + 1;
+    let wide = max > 255;
+    let mut sample = || -> Result<u8, ImageError> {
+        let value = if ascii {
+            tokens.number()?
+        } else if wide {
+            let pair = bytes.get(at..at + 2).ok_or(ImageError::Malformed)?;
+            at += 2;
+            u16::from_be_bytes([pair[0], pair[1]]) as usize
+        } else {
+            let value = *bytes.get(at).ok_or(ImageError::Malformed)?;
+            at += 1;
+            value as usize
+        };
+        Ok((value.min(max) * 255 / max) as u8)
+    };
+
+    let mut pixels = Vec::with_capacity(width * height);
+    for _ in 0..width * height {
+        let pixel = if channels == 1 {
+            gray(sample()?)
+        } else {
+            let (r, g, b) = (sample()?, sample()?, sample()?);
+            Pixel { b, g, r }
+        };
+        pixels.push(pixel);
+    }
+    Ok(Surface::from_pixels(width, height, pixels))
+}
+
+struct PnmTokens<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl PnmTokens<'_> {
+    /// Parses the next decimal number, skipping whitespace and `#` comments.
+    fn number(&mut self) -> Result<usize, ImageError> {
+        loop {
+            match self.bytes.get(self.at) {
+                Some(b'#') => {
+                    while self.bytes.get(self.at).is_some_and(|&b| b != b'\n') {
+                        self.at += 1;
+                    }
+                }
+                Some(b) if b.is_ascii_whitespace() => self.at += 1,
+                Some(b) if b.is_ascii_digit() => break,
+                _ => return Err(ImageError::Malformed),
+            }
+        }
+        let mut value: usize = 0;
+        while let Some(&b) = self.bytes.get(self.at).filter(|b| b.is_ascii_digit()) {
+            value = value
+                .checked_mul(10)
+                .and_then(|value| value.checked_add((b - b'0') as usize))
+                .ok_or(ImageError::Malformed)?;
+            self.at += 1;
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphics::Canvas;
+    use alloc::vec;
+
+    /// A 1x1 bmp with `extra` between the header and the pixel data.
+    fn bmp(bits: u16, compression: u32, colors: u32, extra: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut bytes = b"BM".to_vec();
+        let fields: [u32; 3] = [0, 0, 54 + extra.len() as u32];
+        fields
+            .iter()
+            .for_each(|field| bytes.extend_from_slice(&field.to_le_bytes()));
+        let fields: [u32; 3] = [40, 1, 1];
+        fields
+            .iter()
+            .for_each(|field| bytes.extend_from_slice(&field.to_le_bytes()));
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&bits.to_le_bytes());
+        let fields: [u32; 6] = [compression, 0, 0, 0, colors, 0];
+        fields
+            .iter()
+            .for_each(|field| bytes.extend_from_slice(&field.to_le_bytes()));
+        bytes.extend_from_slice(extra);
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    /// A 1x1 tga of `image_type` with an optional color map.
+    fn tga(image_type: u8, map: Option<(u8, &[u8])>, bits: u8, data: &[u8]) -> Vec<u8> {
+        let (map_bits, entries) = map.unwrap_or((0, &[]));
+        let map_len = entries.len() / (map_bits as usize).div_ceil(8).max(1);
+        let mut bytes = vec![0, map.is_some() as u8, image_type, 0, 0];
+        bytes.extend_from_slice(&(map_len as u16).to_le_bytes());
+        bytes.extend_from_slice(&[map_bits, 0, 0, 0, 0, 1, 0, 1, 0, bits, 0]);
+        bytes.extend_from_slice(entries);
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn only_pixel(image: Result<Surface, ImageError>) -> Pixel {
+        let image = image.unwrap();
+        assert_eq!(image.dim(), (1, 1));
+        image.pixel(0, 0)
+    }
+
+    const RGB: Pixel = Pixel {
+        b: 30,
+        g: 20,
+        r: 10,
+    };
+
+    #[test_case]
+    fn unknown_format() {
+        assert_eq!(decode(&[]).err(), Some(ImageError::UnknownFormat));
+        assert_eq!(decode(b"hello").err(), Some(ImageError::UnknownFormat));
+        // looks like a tga but is too short for its header
+        assert_eq!(decode(&[0, 0, 2, 0]).err(), Some(ImageError::UnknownFormat));
+    }
+
+    #[test_case]
+    fn bmp_malformed() {
+        assert_eq!(decode(b"BM\0\0").err(), Some(ImageError::Malformed));
+        let mut truncated = bmp(24, 0, 0, &[], &[30, 20, 10, 0]);
+        truncated.pop();
+        assert_eq!(decode(&truncated).err(), Some(ImageError::Malformed));
+        // palette index past the two colors
+        let palette = [0; 8];
+        let image = bmp(8, 0, 2, &palette, &[5, 0, 0, 0]);
+        assert_eq!(decode(&image).err(), Some(ImageError::Malformed));
+        assert_eq!(
+            decode(&bmp(7, 0, 2, &palette, &[0; 4])).err(),
+            Some(ImageError::Unsupported)
+        );
+    }
+
+    #[test_case]
+    fn bmp_bitfields() {
+        let masks = |masks: [u32; 3]| {
+            masks
+                .iter()
+                .flat_map(|mask| mask.to_le_bytes())
+                .collect::<Vec<_>>()
+        };
+        let usual = masks([0x00ff_0000, 0x0000_ff00, 0x0000_00ff]);
+        assert_eq!(
+            only_pixel(decode(&bmp(32, 3, 0, &usual, &[30, 20, 10, 0]))),
+            RGB
+        );
+        let swapped = masks([0x0000_00ff, 0x0000_ff00, 0x00ff_0000]);
+        let image = bmp(32, 3, 0, &swapped, &[30, 20, 10, 0]);
+        assert_eq!(decode(&image).err(), Some(ImageError::Unsupported));
+        assert_eq!(
+            decode(&bmp(16, 3, 0, &usual, &[0; 4])).err(),
+            Some(ImageError::Unsupported)
+        );
+    }
+
+    #[test_case]
+    fn tga_color_map() {
+        let map: &[u8] = &[0, 0, 0, 30, 20, 10];
+        assert_eq!(only_pixel(decode(&tga(1, Some((24, map)), 8, &[1]))), RGB);
+        assert_eq!(
+            decode(&tga(1, Some((24, map)), 8, &[2])).err(),
+            Some(ImageError::Malformed)
+        );
+        for map_bits in [0, 1, 7, 12, 64] {
+            let image = tga(1, Some((map_bits, map)), 8, &[0]);
+            assert_eq!(decode(&image).err(), Some(ImageError::Unsupported));
+        }
+    }
+
+    #[test_case]
+    fn tga_malformed() {
+        assert_eq!(only_pixel(decode(&tga(2, None, 24, &[30, 20, 10]))), RGB);
+        assert_eq!(
+            decode(&tga(2, None, 24, &[30, 20])).err(),
+            Some(ImageError::Malformed)
+        );
+        assert_eq!(
+            decode(&tga(2, None, 12, &[0, 0])).err(),
+            Some(ImageError::Unsupported)
+        );
+        // color mapped without a map
+        assert_eq!(
+            decode(&tga(1, None, 8, &[0])).err(),
+            Some(ImageError::Malformed)
+        );
+    }
+
+    #[test_case]
+    fn pnm_malformed() {
+        assert_eq!(only_pixel(decode(b"P3 1 1 # comment\n255 10 20 30")), RGB);
+        assert_eq!(decode(b"P2 1 1 0 0").err(), Some(ImageError::Malformed));
+        assert_eq!(decode(b"P2 1 1 70000 0").err(), Some(ImageError::Malformed));
+        assert_eq!(
+            decode(b"P3 1 1 255 10 20").err(),
+            Some(ImageError::Malformed)
+        );
+        assert_eq!(
+            decode(b"P5 2 1 255\n\x00").err(),
+            Some(ImageError::Malformed)
+        );
+        assert_eq!(
+            decode(b"P6 99999999999999999999 1 255\n").err(),
+            Some(ImageError::Malformed)
+        );
+        assert_eq!(
+            decode(b"P5 9999 9999 255\n").err(),
+            Some(ImageError::Malformed)
+        );
+        assert_eq!(decode(b"P2 x").err(), Some(ImageError::Malformed));
+    }
+}
@@ -1,73 +1,285 @@
+use crate::backtrace::Backtrace;
 use crate::gdt::DOUBLE_FAULT_1ST_INDEX;
-use crate::{print, println};
+use crate::lockdep::SpinLock;
+use crate::shell::{self, Command, CommandError};
+use crate::{
+    apic, console, crash, debugreg, executor, gdbstub, keyboard, logger, serial, smp, thread,
+};
+use alloc::format;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
 use pic8259::ChainedPics;
-use spin::{Mutex, Once};
+use spin::Once;
 use x86_64::instructions::interrupts;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::instructions::port::Port;
+use x86_64::registers::control::Cr2;
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 
 const PIC1_OFFSET: u8 = 32;
 const PIC2_OFFSET: u8 = PIC1_OFFSET + 8;
 static IDT: Once<InterruptDescriptorTable> = Once::new();
-static PICS: Mutex<ChainedPics> = Mutex::new(unsafe { ChainedPics::new(PIC1_OFFSET, PIC2_OFFSET) });
+static PICS: SpinLock<ChainedPics> = SpinLock::new("PICS", unsafe {
+    ChainedPics::new(PIC1_OFFSET, PIC2_OFFSET)
+});
+
+pub const TIMER_HZ: u64 = 100;
+const PIT_FREQUENCY: u64 = 1_193_182;
+const CURSOR_BLINK_TICKS: u64 = TIMER_HZ / 2;
+static TICKS: AtomicU64 = AtomicU64::new(0);
+crate::cpu_local! {
+    /// Interrupts taken per PIC line.
+    static IRQ_COUNTS: [AtomicU64; 16] = [const { AtomicU64::new(0) }; 16];
+}
+
+/// Timer interrupts since boot, `TIMER_HZ` per second.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC1_OFFSET,
     Keyboard,
+    Com1 = PIC1_OFFSET + 4,
+}
+
+/// Interrupts taken on PIC line `irq` since boot.
+pub fn irq_count(irq: usize) -> u64 {
+    (0..smp::cpu_count())
+        .map(|cpu| irq_count_on(cpu, irq))
+        .sum()
+}
+
+/// Interrupts taken on PIC line `irq` by CPU `cpu`.
+pub fn irq_count_on(cpu: usize, irq: usize) -> u64 {
+    IRQ_COUNTS.for_cpu(cpu)[irq].load(Ordering::Relaxed)
 }
 
 pub fn init_idt() {
     IDT.call_once(|| {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        if gdbstub::is_enabled() {
+            unsafe {
+                idt.breakpoint.set_handler_addr(gdbstub::breakpoint_entry());
+                idt.debug.set_handler_addr(gdbstub::debug_entry());
+            }
+        } else {
+            idt.breakpoint.set_handler_fn(breakpoint_handler);
+            idt.debug.set_handler_fn(debug_handler);
+        }
         unsafe {
             idt.double_fault
                 .set_handler_fn(double_handler)
                 .set_stack_index(DOUBLE_FAULT_1ST_INDEX);
         }
         idt.general_protection_fault.set_handler_fn(general_handler);
+        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
         idt[InterruptIndex::Timer as u8].set_handler_fn(timer_handler);
         idt[InterruptIndex::Keyboard as u8].set_handler_fn(keyboard_interrupt);
+        idt[InterruptIndex::Com1 as u8].set_handler_fn(com1_interrupt);
+        idt[smp::CALL_VECTOR].set_handler_fn(call_interrupt);
+        idt[apic::SPURIOUS_VECTOR].set_handler_fn(spurious_interrupt);
         idt
     });
-    IDT.get().expect("failed to get IDT").load();
+    load_idt();
     unsafe {
+        // channel 0, lobyte/hibyte, rate generator
+        let divisor = (PIT_FREQUENCY / TIMER_HZ) as u16;
+        Port::<u8>::new(0x43).write(0x34);
+        Port::<u8>::new(0x40).write(divisor as u8);
+        Port::<u8>::new(0x40).write((divisor >> 8) as u8);
+
         let mut pics = PICS.lock();
         pics.initialize();
-        // only handle timer interrupts
-        pics.write_masks(0b1111_1110, 0b1111_1111);
+        // only handle timer, keyboard and COM1 interrupts
+        pics.write_masks(0b1110_1100, 0b1111_1111);
     }
+    shell::register(&IrqCommand);
+    shell::register(&UptimeCommand);
     interrupts::enable();
 }
 
+/// Loads the IDT built by `init_idt` on the calling CPU.
+pub fn load_idt() {
+    IDT.get().expect("failed to get IDT").load();
+}
+
+// Fatal exceptions go straight to the panic path, which reports them without
+// the logging locks the faulting code may hold and unwinds through the
+// interrupt frame for the backtrace.
+
 extern "x86-interrupt" fn general_handler(stack_frame: InterruptStackFrame, error_code: u64) {
-    println!(
+    panic!(
         "EXCEPTION: GENERAL_PROTECTION({})\n{:#?}",
         error_code, stack_frame
     );
-    panic!();
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    panic!(
+        "EXCEPTION: PAGE FAULT at {:#x} ({:?})\n{:#?}",
+        Cr2::read_raw(),
+        error_code,
+        stack_frame
+    );
 }
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    logger::warn_unlocked(
+        module_path!(),
+        format_args!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame),
+    );
+}
+
+extern "x86-interrupt" fn debug_handler(mut stack_frame: InterruptStackFrame) {
+    let backtrace = Backtrace::from_interrupt(&stack_frame);
+    for (slot, watchpoint) in debugreg::triggered() {
+        // may have stopped code holding the locks `warn!` takes
+        logger::warn_unlocked(
+            module_path!(),
+            format_args!(
+                "WATCHPOINT {}: {} hit at {:#x}\n{}",
+                slot,
+                watchpoint,
+                stack_frame.instruction_pointer.as_u64(),
+                backtrace
+            ),
+        );
+    }
+    debugreg::acknowledge();
+    // execute breakpoints fault before the instruction runs, resume past them
+    unsafe {
+        stack_frame
+            .as_mut()
+            .update(|frame| frame.cpu_flags |= RFlags::RESUME_FLAG)
+    };
 }
 
 extern "x86-interrupt" fn double_handler(stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
-    println!("EXCEPTION: DOUBLE FAULT:{:#?}", stack_frame);
-    loop {}
+    // most likely from a page fault that couldn't push its frame
+    let overflow = thread::StackOverflow(Cr2::read_raw());
+    if overflow.is_guard_page() {
+        panic!("EXCEPTION: DOUBLE FAULT, {}\n{:#?}", overflow, stack_frame);
+    }
+    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn timer_handler(_stack_frame: InterruptStackFrame) {
+    IRQ_COUNTS.get()[0].fetch_add(1, Ordering::Relaxed);
+    let ticks = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    if ticks.is_multiple_of(CURSOR_BLINK_TICKS) {
+        console::blink();
+    }
+    executor::wake_sleepers(ticks);
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer as u8)
     }
+    thread::tick(ticks);
+}
+
+extern "x86-interrupt" fn call_interrupt(_stack_frame: InterruptStackFrame) {
+    smp::run_calls();
+    apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn spurious_interrupt(_stack_frame: InterruptStackFrame) {}
+
+extern "x86-interrupt" fn nmi_handler(_stack_frame: InterruptStackFrame) {
+    if smp::is_stopping() {
+        smp::stopped();
+        crash::halt();
+    }
+    smp::hold_if_paused();
+    // nothing else sends them, and logging could deadlock on whatever lock
+    // the interrupted code holds
 }
 
 extern "x86-interrupt" fn keyboard_interrupt(_stack_frame: InterruptStackFrame) {
+    IRQ_COUNTS.get()[1].fetch_add(1, Ordering::Relaxed);
+    keyboard::handle_interrupt();
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Keyboard as u8);
     }
 }
+
+extern "x86-interrupt" fn com1_interrupt(_stack_frame: InterruptStackFrame) {
+    IRQ_COUNTS.get()[4].fetch_add(1, Ordering::Relaxed);
+    serial::handle_interrupt();
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Com1 as u8);
+    }
+}
+
+pub struct IrqCommand;
+
+impl Command for IrqCommand {
+    fn name(&self) -> &'static str {
+        "irq"
+    }
+
+    fn help(&self) -> &'static str {
+        "show interrupt counts"
+    }
+
+    fn run(&self, _args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+        let cpus = smp::cpu_count();
+        write!(out, "    ")?;
+        for cpu in 0..cpus {
+            write!(out, " {:>12}", format!("CPU{}", cpu))?;
+        }
+        writeln!(out)?;
+        for irq in 0..16 {
+            let name = match irq {
+                0 => "timer",
+                1 => "keyboard",
+                4 => "com1",
+                _ => "",
+            };
+            let count = irq_count(irq);
+            if count > 0 || !name.is_empty() {
+                write!(out, "  {:>2}", irq)?;
+                for cpu in 0..cpus {
+                    write!(out, " {:>12}", irq_count_on(cpu, irq))?;
+                }
+                writeln!(out, "  {}", name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct UptimeCommand;
+
+impl Command for UptimeCommand {
+    fn name(&self) -> &'static str {
+        "uptime"
+    }
+
+    fn help(&self) -> &'static str {
+        "show the time since boot"
+    }
+
+    fn run(&self, _args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+        let ticks = ticks();
+        let secs = ticks / TIMER_HZ;
+        writeln!(
+            out,
+            "up {}:{:02}:{:02}.{:02} ({} ticks)",
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60,
+            ticks % TIMER_HZ * 100 / TIMER_HZ,
+            ticks
+        )?;
+        Ok(())
+    }
+}
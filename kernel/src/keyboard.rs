@@ -0,0 +1,99 @@
+use crate::{console, splash};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+const DATA_PORT: u16 = 0x60;
+const EXTENDED_PREFIX: u8 = 0xe0;
+const RELEASED: u8 = 0x80;
+
+// scan code set 1, US layout, indexed by make code
+const NORMAL: &[u8; 0x3a] =
+    b"\0\x1b1234567890-=\x7f\tqwertyuiop[]\n\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+const SHIFTED: &[u8; 0x3a] =
+    b"\0\x1b!@#$%^&*()_+\x7f\tQWERTYUIOP{}\n\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ";
+
+const LEFT_CTRL: u8 = 0x1d;
+const LEFT_SHIFT: u8 = 0x2a;
+const RIGHT_SHIFT: u8 = 0x36;
+const LEFT_ALT: u8 = 0x38;
+const CAPS_LOCK: u8 = 0x3a;
+const F1: u8 = 0x3b;
+
+static KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard {
+    extended: false,
+    shift: false,
+    ctrl: false,
+    alt: false,
+    caps_lock: false,
+});
+
+struct Keyboard {
+    extended: bool,
+    shift: bool,
+    ctrl: bool,
+    alt: bool,
+    caps_lock: bool,
+}
+
+/// Reads the pending scan code and turns it into console input. Called from
+/// the keyboard interrupt.
+pub fn handle_interrupt() {
+    let scancode: u8 = unsafe { Port::new(DATA_PORT).read() };
+    KEYBOARD.lock().handle_scancode(scancode);
+}
+
+impl Keyboard {
+    fn handle_scancode(&mut self, scancode: u8) {
+        if scancode == EXTENDED_PREFIX {
+            self.extended = true;
+            return;
+        }
+        let extended = core::mem::take(&mut self.extended);
+        let pressed = scancode & RELEASED == 0;
+        let code = scancode & !RELEASED;
+        match code {
+            // right ctrl and alt share codes with the left ones
+            LEFT_CTRL => self.ctrl = pressed,
+            LEFT_ALT => self.alt = pressed,
+            LEFT_SHIFT | RIGHT_SHIFT if !extended => self.shift = pressed,
+            CAPS_LOCK if pressed => self.caps_lock = !self.caps_lock,
+            _ if !pressed => {}
+            // the first key press only dismisses the boot splash
+            _ if splash::is_active() => splash::finish(),
+            F1..=0x40 if self.alt => console::switch((code - F1) as usize),
+            _ if extended => {
+                let sequence: &[u8] = match code {
+                    0x48 => b"\x1b[A",
+                    0x50 => b"\x1b[B",
+                    0x4d => b"\x1b[C",
+                    0x4b => b"\x1b[D",
+                    0x47 => b"\x1b[H",
+                    0x4f => b"\x1b[F",
+                    0x53 => b"\x1b[3~",
+                    0x1c => b"\n",
+                    _ => b"",
+                };
+                console::push_input(sequence);
+            }
+            _ => {
+                let Some(&byte) = NORMAL.get(code as usize) else {
+                    return;
+                };
+                let shifted = self.shift ^ (self.caps_lock && byte.is_ascii_lowercase());
+                let byte = if shifted {
+                    SHIFTED[code as usize]
+                } else {
+                    byte
+                };
+                let byte = if self.ctrl && byte.is_ascii_alphabetic() {
+                    byte & 0x1f
+                } else {
+                    byte
+                };
+                if byte != 0 {
+                    console::push_input(&[byte]);
+                }
+            }
+        }
+    }
+}
@@ -5,33 +5,108 @@
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
+extern crate alloc;
+
+pub mod acpi;
+pub mod allocator;
+pub mod ansi;
+pub mod apic;
+pub mod backtrace;
+pub mod cmdline;
+pub mod console;
+pub mod crash;
+pub mod debugreg;
+pub mod demangle;
+pub mod dmesg;
+pub mod executor;
 mod font;
 pub mod framebuffer;
+pub mod gdbstub;
 mod gdt;
+pub mod graphics;
+pub mod image;
 pub mod interrupt;
-mod serial;
+mod keyboard;
+pub mod lockdep;
+pub mod logger;
+pub mod memory;
+pub mod pci;
+pub mod percpu;
+pub mod psf;
+pub mod ramdisk;
+pub mod sched;
+pub mod serial;
+pub mod shell;
+pub mod smp;
+pub mod splash;
+pub mod sync;
+pub mod thread;
+pub mod unwind;
+pub mod utf8;
 
-use bootloader_api::{info::BootInfo, BootloaderConfig};
+use bootloader_api::{config::Mapping, info::BootInfo, BootloaderConfig};
+use core::fmt::Write;
 use core::panic::PanicInfo;
 use framebuffer::{FrameBuffer, BLACK, FRAMEBUFFER};
 use gdt::init_gdt;
 use interrupt::init_idt;
-use serial::SERIAL1;
-use x86_64::instructions::{self, port::Port};
+use psf::Font;
+use serial::{RawSerial, SERIAL1};
+use splash::Stage;
+use x86_64::instructions::{self, interrupts, port::Port};
 
 pub const fn bootloader_config() -> BootloaderConfig {
     let mut config = BootloaderConfig::new_default();
     config.mappings.dynamic_range_start = Some(0xffff_8000_0000_0000);
     config.mappings.dynamic_range_end = Some(0xffff_ffff_ffff_ffff);
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
     config
 }
 
 pub fn init(boot_info: &'static mut BootInfo) {
-    *FRAMEBUFFER.lock() = FrameBuffer::new(&mut boot_info.framebuffer);
-    FRAMEBUFFER.lock().fill(BLACK);
-    SERIAL1.lock().init();
+    percpu::init(0);
+    let physical_memory_offset = boot_info
+        .physical_memory_offset
+        .into_option()
+        .expect("physical memory is not mapped");
+    memory::init(physical_memory_offset, &boot_info.memory_regions);
+    allocator::init_heap();
+    acpi::init(boot_info.rsdp_addr.into_option());
+    ramdisk::init(boot_info.ramdisk_addr, boot_info.ramdisk_len);
+    backtrace::init(
+        boot_info.kernel_addr,
+        boot_info.kernel_len,
+        boot_info.kernel_image_offset,
+    );
+    cmdline::init();
+    logger::init();
+    let mut framebuffer = FRAMEBUFFER.lock();
+    *framebuffer = FrameBuffer::new(&mut boot_info.framebuffer);
+    framebuffer.fill(BLACK);
+    if let Some(font) = ramdisk::file("font.psf").and_then(|bytes| Font::parse(bytes).ok()) {
+        framebuffer.set_font(font);
+    }
+    framebuffer.flush();
+    let term_dim = framebuffer.term_dim;
+    drop(framebuffer);
+    console::init(term_dim);
+    splash::start();
+    splash::advance(Stage::Memory);
     init_gdt();
     init_idt();
+    thread::init();
+    smp::init();
+    splash::advance(Stage::Interrupts);
+    // the port raises its first interrupt before the lock is released
+    interrupts::without_interrupts(|| SERIAL1.lock().init());
+    gdbstub::init();
+    pci::init();
+    debugreg::init();
+    dmesg::init();
+    crash::init();
+    shell::init();
+    splash::advance(Stage::Drivers);
+    splash::finish();
 }
 
 pub fn test_runner(tests: &[&dyn Testable]) {
@@ -42,18 +117,60 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 }
 
 pub fn panic_test(info: &PanicInfo) -> ! {
-    serial_println!("[failed]");
-    serial_println!("info: {}", info);
+    // SERIAL1 may be held by whatever panicked
+    let _ = writeln!(RawSerial, "[failed]");
+    let _ = writeln!(RawSerial, "info: {}", info);
     exit_qemu(QemuExitCode::Failed);
     loop {
         instructions::hlt();
     }
 }
 
+/// Checks a condition of an integration test, failing the test with
+/// `message` if it doesn't hold.
+pub fn check(ok: bool, message: &str) {
+    if !ok {
+        serial_println!("[failed]");
+        serial_println!("{}", message);
+        exit_qemu(QemuExitCode::Failed);
+        loop {
+            instructions::hlt();
+        }
+    }
+}
+
+/// Declares the entry point of a test kernel, which boots with the kernel's
+/// own config, and its panic handler, `panic_test` unless the test brings
+/// one. Tests are run by the `os` runner, see `.cargo/config.toml`.
+#[macro_export]
+macro_rules! test_entry_point {
+    ($main:path) => {
+        $crate::test_entry_point!($main, $crate::panic_test);
+    };
+    ($main:path, $panic:path) => {
+        const CONFIG: bootloader_api::BootloaderConfig = $crate::bootloader_config();
+
+        bootloader_api::entry_point!($main, config = &CONFIG);
+
+        #[panic_handler]
+        fn panic_handler(info: &core::panic::PanicInfo) -> ! {
+            $panic(info)
+        }
+    };
+}
+
+#[cfg(test)]
+test_entry_point!(test_kernel_main);
+
+/// Runs the unit tests when the library is built as its own test kernel.
 #[cfg(test)]
-#[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
-    panic_test(info);
+fn test_kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    init(boot_info);
+    test_main();
+    exit_qemu(QemuExitCode::Success);
+    loop {
+        instructions::hlt();
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
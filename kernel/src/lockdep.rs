@@ -0,0 +1,364 @@
+use crate::backtrace::Backtrace;
+use crate::serial::RawSerial;
+use crate::{percpu, thread};
+use core::cell::Cell;
+use core::fmt::{self, Write};
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+
+const MAX_CLASSES: usize = 32;
+const MAX_EDGES: usize = 128;
+/// Locks a CPU can hold at once and still have their order checked.
+const MAX_HELD: usize = 8;
+/// How long `lock` spins before giving up on the lock ever being released,
+/// in TSC cycles, which is a few seconds on anything recent.
+const SPIN_TIMEOUT: u64 = 10_000_000_000;
+const UNREGISTERED: usize = usize::MAX;
+/// For locks beyond `MAX_CLASSES`, which only get the owner checks.
+const UNTRACKED: usize = usize::MAX - 1;
+const NO_OWNER: usize = usize::MAX;
+
+static ENABLED: AtomicBool = AtomicBool::new(cfg!(debug_assertions));
+/// The lock classes and every order they were seen taken in. Locks taken by
+/// interrupt handlers are checked against it too, so `check_order` and
+/// `class` hold it with interrupts off.
+static GRAPH: spin::Mutex<Graph> = spin::Mutex::new(Graph {
+    names: [""; MAX_CLASSES],
+    classes: 0,
+    edges: [const { None }; MAX_EDGES],
+});
+
+crate::cpu_local! {
+    /// The checked locks each CPU holds, with the thread holding them.
+    static HELD: Cell<[Option<(usize, u64)>; MAX_HELD]> = Cell::new([None; MAX_HELD]);
+}
+
+/// Turns the checks off for good, for the panic path, which forces locks
+/// open and takes them in whatever order it has to.
+pub fn disable() {
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+/// A spin lock that, in debug builds, remembers who holds it and where it
+/// was taken, panics instead of deadlocking on itself or spinning forever,
+/// and checks that locks are always taken in the same order. Every lock is
+/// its own class, which fits locks that are statics.
+pub struct SpinLock<T> {
+    inner: spin::Mutex<T>,
+    name: &'static str,
+    class: AtomicUsize,
+    owner_cpu: AtomicUsize,
+    owner_thread: AtomicU64,
+    owner_site: AtomicPtr<Location<'static>>,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(name: &'static str, value: T) -> SpinLock<T> {
+        SpinLock {
+            inner: spin::Mutex::new(value),
+            name,
+            class: AtomicUsize::new(UNREGISTERED),
+            owner_cpu: AtomicUsize::new(NO_OWNER),
+            owner_thread: AtomicU64::new(0),
+            owner_site: AtomicPtr::new(core::ptr::null_mut()),
+        }
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return SpinLockGuard {
+                lock: self,
+                guard: self.inner.lock(),
+                tracked: false,
+            };
+        }
+        let site = Location::caller();
+        let (cpu, thread) = (percpu::id(), thread::current().as_u64());
+        if self.owner_cpu.load(Ordering::Relaxed) == cpu
+            && self.owner_thread.load(Ordering::Relaxed) == thread
+        {
+            panic!(
+                "{} taken again at {} by thread {}, which has held it since {}",
+                self.name,
+                site,
+                thread,
+                self.owner_site()
+            );
+        }
+        let class = self.class();
+        if class != UNTRACKED {
+            interrupts::without_interrupts(|| check_order(class, site));
+        }
+        let start = rdtsc();
+        let guard = loop {
+            if let Some(guard) = self.inner.try_lock() {
+                break guard;
+            }
+            if rdtsc() - start > SPIN_TIMEOUT {
+                panic!(
+                    "gave up on {} at {}, held by thread {} on cpu {} since {}",
+                    self.name,
+                    site,
+                    self.owner_thread.load(Ordering::Relaxed),
+                    self.owner_cpu.load(Ordering::Relaxed),
+                    self.owner_site()
+                );
+            }
+            core::hint::spin_loop();
+        };
+        self.acquired(class, cpu, thread, site);
+        SpinLockGuard {
+            lock: self,
+            guard,
+            tracked: true,
+        }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let guard = self.inner.try_lock()?;
+        let tracked = ENABLED.load(Ordering::Relaxed);
+        if tracked {
+            let (cpu, thread) = (percpu::id(), thread::current().as_u64());
+            self.acquired(self.class(), cpu, thread, Location::caller());
+        }
+        Some(SpinLockGuard {
+            lock: self,
+            guard,
+            tracked,
+        })
+    }
+
+    /// Unlocks the lock whoever holds it.
+    ///
+    /// # Safety
+    ///
+    /// Whoever held it must never touch it again.
+    pub unsafe fn force_unlock(&self) {
+        self.owner_cpu.store(NO_OWNER, Ordering::Relaxed);
+        unsafe { self.inner.force_unlock() };
+    }
+
+    fn class(&self) -> usize {
+        match self.class.load(Ordering::Acquire) {
+            UNREGISTERED => interrupts::without_interrupts(|| {
+                let mut graph = GRAPH.lock();
+                // someone else may have registered it meanwhile
+                match self.class.load(Ordering::Acquire) {
+                    UNREGISTERED => {
+                        let class = graph.register(self.name);
+                        self.class.store(class, Ordering::Release);
+                        class
+                    }
+                    class => class,
+                }
+            }),
+            class => class,
+        }
+    }
+
+    fn owner_site(&self) -> &'static dyn fmt::Display {
+        let site = self.owner_site.load(Ordering::Relaxed);
+        if site.is_null() {
+            &"?"
+        } else {
+            unsafe { &*site }
+        }
+    }
+
+    fn acquired(&self, class: usize, cpu: usize, thread: u64, site: &'static Location<'static>) {
+        self.owner_thread.store(thread, Ordering::Relaxed);
+        self.owner_site
+            .store(site as *const _ as *mut _, Ordering::Relaxed);
+        self.owner_cpu.store(cpu, Ordering::Relaxed);
+        if class == UNTRACKED {
+            return;
+        }
+        HELD.with(|held| {
+            let mut entries = held.get();
+            if let Some(free) = entries.iter_mut().find(|entry| entry.is_none()) {
+                *free = Some((class, thread));
+            }
+            held.set(entries);
+        });
+    }
+
+    fn released(&self) {
+        self.owner_cpu.store(NO_OWNER, Ordering::Relaxed);
+        let class = self.class.load(Ordering::Relaxed);
+        HELD.with(|held| {
+            let mut entries = held.get();
+            if let Some(entry) = entries
+                .iter_mut()
+                .rev()
+                .find(|entry| matches!(entry, Some((c, _)) if *c == class))
+            {
+                *entry = None;
+            }
+            held.set(entries);
+        });
+    }
+}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    guard: spin::MutexGuard<'a, T>,
+    tracked: bool,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        // runs before `guard` unlocks
+        if self.tracked {
+            self.lock.released();
+        }
+    }
+}
+
+/// That a thread took `to` while holding `from`.
+#[derive(Clone, Copy)]
+struct Edge {
+    from: usize,
+    to: usize,
+    site: &'static Location<'static>,
+    backtrace: Backtrace,
+}
+
+/// Which locks have been taken while holding which.
+struct Graph {
+    names: [&'static str; MAX_CLASSES],
+    classes: usize,
+    edges: [Option<Edge>; MAX_EDGES],
+}
+
+impl Graph {
+    fn register(&mut self, name: &'static str) -> usize {
+        if self.classes == MAX_CLASSES {
+            return UNTRACKED;
+        }
+        self.names[self.classes] = name;
+        self.classes += 1;
+        self.classes - 1
+    }
+
+    fn edges(&self) -> impl Iterator<Item = (usize, &Edge)> {
+        self.edges
+            .iter()
+            .enumerate()
+            .filter_map(|(i, edge)| Some((i, edge.as_ref()?)))
+    }
+
+    /// The edges of a path from `from` to `to`, last first, and its length.
+    fn path(&self, from: usize, to: usize) -> Option<([usize; MAX_CLASSES], usize)> {
+        // breadth first, remembering the edge each class was reached by
+        let mut via = [None; MAX_CLASSES];
+        let mut queue = [0; MAX_CLASSES];
+        let (mut head, mut tail) = (0, 1);
+        queue[0] = from;
+        while head < tail && via[to].is_none() {
+            let class = queue[head];
+            head += 1;
+            for (i, edge) in self.edges().filter(|(_, edge)| edge.from == class) {
+                if edge.to != from && via[edge.to].is_none() {
+                    via[edge.to] = Some(i);
+                    queue[tail] = edge.to;
+                    tail += 1;
+                }
+            }
+        }
+        let mut path = [0; MAX_CLASSES];
+        let mut len = 0;
+        let mut class = to;
+        while class != from {
+            let edge = via[class]?;
+            path[len] = edge;
+            len += 1;
+            class = self.edges[edge].as_ref().unwrap().from;
+        }
+        Some((path, len))
+    }
+}
+
+/// Checks taking `class` now against the locks the current thread holds on
+/// this CPU, and records the new orderings.
+fn check_order(class: usize, site: &'static Location<'static>) {
+    let thread = thread::current().as_u64();
+    let held = HELD.with(Cell::get);
+    let held = held.into_iter().flatten().filter(|&(_, t)| t == thread);
+    if held.clone().next().is_none() {
+        return;
+    }
+    let mut graph = GRAPH.lock();
+    for (from, _) in held {
+        if from == class || graph.edges().any(|(_, e)| e.from == from && e.to == class) {
+            continue;
+        }
+        if let Some((path, len)) = graph.path(class, from) {
+            report_inversion(&graph, from, class, site, &path[..len]);
+        }
+        let edge = Edge {
+            from,
+            to: class,
+            site,
+            backtrace: Backtrace::capture(),
+        };
+        // past that, orderings just go unchecked
+        if let Some(free) = graph.edges.iter_mut().find(|edge| edge.is_none()) {
+            *free = Some(edge);
+        }
+    }
+}
+
+/// Prints both sides of the cycle straight to serial, as the locks it is
+/// about may well be the ones printing needs, and panics.
+fn report_inversion(
+    graph: &Graph,
+    held: usize,
+    class: usize,
+    site: &'static Location<'static>,
+    path: &[usize],
+) -> ! {
+    disable();
+    let names = &graph.names;
+    let out = &mut RawSerial;
+    let _ = writeln!(
+        out,
+        "\nlock order inversion: {} taken at {} while holding {}",
+        names[class], site, names[held]
+    );
+    let _ = write!(out, "{}", Backtrace::capture());
+    let _ = writeln!(out, "but it was taken the other way round before:");
+    for edge in path.iter().rev().map(|&i| graph.edges[i].as_ref().unwrap()) {
+        let _ = writeln!(
+            out,
+            "{} taken at {} while holding {}",
+            names[edge.to], edge.site, names[edge.from]
+        );
+        let _ = write!(out, "{}", edge.backtrace);
+    }
+    panic!(
+        "lock order inversion between {} and {}",
+        names[held], names[class]
+    );
+}
+
+fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
@@ -0,0 +1,223 @@
+use crate::console::{self, LOG_CONSOLE};
+use crate::interrupt::{ticks, TIMER_HZ};
+use crate::serial::{RawSerial, SERIAL1};
+use crate::{cmdline, dmesg, percpu, splash};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, Ordering};
+use log::{LevelFilter, Log, Metadata, Record};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+const LINE_SIZE: usize = 512;
+
+static LOGGER: KernelLogger = KernelLogger;
+static SINKS: AtomicU8 = AtomicU8::new(Sinks::ALL.0);
+static FILTERS: Mutex<Filters> = Mutex::new(Filters {
+    default: LevelFilter::Info,
+    modules: Vec::new(),
+});
+
+/// Where log records are written, as a set of flags. `RING` is the dmesg
+/// buffer, which is replayed to the console once it is up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sinks(u8);
+
+impl Sinks {
+    pub const NONE: Sinks = Sinks(0);
+    pub const CONSOLE: Sinks = Sinks(1 << 0);
+    pub const SERIAL: Sinks = Sinks(1 << 1);
+    pub const RING: Sinks = Sinks(1 << 2);
+    pub const ALL: Sinks = Sinks(0b111);
+
+    pub fn contains(self, other: Sinks) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for Sinks {
+    type Output = Sinks;
+
+    fn bitor(self, other: Sinks) -> Sinks {
+        Sinks(self.0 | other.0)
+    }
+}
+
+/// Installs the kernel logger. Filters come from the `log` boot option, e.g.
+/// `log=info,kernel::gdt=trace`, and sinks from `log_sinks=serial,ring`.
+pub fn init() {
+    if let Some(spec) = cmdline::get("log") {
+        for directive in spec.split(',') {
+            match directive.split_once('=') {
+                Some((module, level)) => {
+                    if let Ok(level) = level.parse() {
+                        set_module_level(module, level);
+                    }
+                }
+                None => {
+                    if let Ok(level) = directive.parse() {
+                        set_default_level(level);
+                    }
+                }
+            }
+        }
+    }
+    if let Some(spec) = cmdline::get("log_sinks") {
+        let sinks = spec.split(',').fold(Sinks::NONE, |sinks, sink| {
+            sinks
+                | match sink {
+                    "console" => Sinks::CONSOLE,
+                    "serial" => Sinks::SERIAL,
+                    "ring" => Sinks::RING,
+                    _ => Sinks::NONE,
+                }
+        });
+        set_sinks(sinks);
+    }
+    log::set_logger(&LOGGER).expect("logger already set");
+    log::set_max_level(LevelFilter::Trace);
+}
+
+pub fn set_sinks(sinks: Sinks) {
+    SINKS.store(sinks.0, Ordering::Relaxed);
+}
+
+pub fn sinks() -> Sinks {
+    Sinks(SINKS.load(Ordering::Relaxed))
+}
+
+pub fn set_default_level(level: LevelFilter) {
+    interrupts::without_interrupts(|| FILTERS.lock().default = level);
+}
+
+/// Sets the level for `module` and everything below it, overriding the
+/// default and any filter on a parent module.
+pub fn set_module_level(module: &str, level: LevelFilter) {
+    interrupts::without_interrupts(|| {
+        let mut filters = FILTERS.lock();
+        filters.modules.retain(|(name, _)| name != module);
+        filters.modules.push((module.to_string(), level));
+    });
+}
+
+struct Filters {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Filters {
+    /// The level of the most specific filter matching `target`.
+    fn level(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target
+                    .strip_prefix(module.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default, |&(_, level)| level)
+    }
+}
+
+/// One formatted record. Anything past `LINE_SIZE` bytes, counting the
+/// newline, is cut off, at a character boundary.
+struct Line {
+    buf: [u8; LINE_SIZE],
+    len: usize,
+}
+
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // the last byte is kept for the newline
+        let mut take = s.len().min(LINE_SIZE - 1 - self.len);
+        while !s.is_char_boundary(take) {
+            take -= 1;
+        }
+        self.buf[self.len..self.len + take].copy_from_slice(&s.as_bytes()[..take]);
+        self.len += take;
+        Ok(())
+    }
+}
+
+impl Line {
+    /// `args` with a timestamp, the CPU, `level` and `target` in front and a
+    /// newline at the end.
+    fn format(level: log::Level, target: &str, args: &fmt::Arguments) -> Line {
+        let ticks = ticks();
+        let mut line = Line {
+            buf: [0; LINE_SIZE],
+            len: 0,
+        };
+        let _ = write!(
+            line,
+            "[{:>5}.{:03}] cpu{} {:<5} {}: {}",
+            ticks / TIMER_HZ,
+            ticks % TIMER_HZ * 1000 / TIMER_HZ,
+            percpu::id(),
+            level,
+            target,
+            args
+        );
+        line.buf[line.len] = b'\n';
+        line.len += 1;
+        line
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+/// Logs a warning without taking a lock, for exception handlers that may
+/// have interrupted code holding the console, serial, dmesg or filter locks.
+/// It skips the filters and the console, writes to serial past its lock and
+/// to dmesg only if the buffer is free.
+pub fn warn_unlocked(target: &str, args: fmt::Arguments) {
+    let line = Line::format(log::Level::Warn, target, &args);
+    let sinks = sinks();
+    if sinks.contains(Sinks::SERIAL) {
+        let _ = RawSerial.write_str(core::str::from_utf8(line.as_bytes()).unwrap_or_default());
+    }
+    if sinks.contains(Sinks::RING) {
+        dmesg::try_write(line.as_bytes());
+    }
+}
+
+struct KernelLogger;
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let level = interrupts::without_interrupts(|| FILTERS.lock().level(metadata.target()));
+        metadata.level() <= level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = Line::format(record.level(), record.target(), record.args());
+        let line = line.as_bytes();
+
+        if record.level() == log::Level::Error {
+            splash::finish();
+        }
+        let sinks = sinks();
+        if sinks.contains(Sinks::CONSOLE) {
+            console::write(LOG_CONSOLE, line);
+        }
+        interrupts::without_interrupts(|| {
+            if sinks.contains(Sinks::SERIAL) {
+                for &byte in line {
+                    SERIAL1.lock().send(byte);
+                }
+            }
+            if sinks.contains(Sinks::RING) {
+                dmesg::write(line);
+            }
+        });
+    }
+
+    fn flush(&self) {}
+}
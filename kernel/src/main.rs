@@ -7,8 +7,7 @@
 
 use bootloader_api::{entry_point, info::BootInfo, BootloaderConfig};
 use core::panic::PanicInfo;
-use kernel::{bootloader_config, init, print, println};
-use x86_64::instructions;
+use kernel::{bootloader_config, init, print};
 
 const CONFIG: BootloaderConfig = bootloader_config();
 
@@ -20,15 +19,11 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     #[cfg(test)]
     test_main();
 
-    loop {
-        instructions::hlt()
-    }
+    kernel::shell::start();
+    kernel::executor::run()
 }
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
-    loop {
-        instructions::hlt();
-    }
+    kernel::crash::panic(info)
 }
@@ -0,0 +1,377 @@
+use crate::allocator::{self, HEAP_SIZE};
+use crate::shell::{self, Command, CommandError};
+use crate::smp;
+use bootloader_api::info::{MemoryRegion, MemoryRegionKind};
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Mutex, Once};
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::mapper::{MapToError, TranslateResult};
+use x86_64::structures::paging::page::PageRangeInclusive;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+    Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+const IA32_PAT: u32 = 0x277;
+// PAT entry selected by PWT=1, PCD=0, PAT=0
+const PAT_WC_INDEX: u64 = 1;
+const PAT_WRITE_COMBINING: u64 = 0x01;
+/// Shootdowns of at least this many pages flush whole TLBs instead.
+const SHOOTDOWN_FLUSH_ALL: u64 = 32;
+/// Where device memory is mapped.
+const MMIO_START: u64 = 0x_6666_0000_0000;
+
+static PHYS_OFFSET: Once<VirtAddr> = Once::new();
+pub static MAPPER: Once<Mutex<OffsetPageTable<'static>>> = Once::new();
+pub static FRAME_ALLOCATOR: Once<Mutex<BootInfoFrameAllocator>> = Once::new();
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_START);
+
+pub fn init(physical_memory_offset: u64, memory_regions: &'static [MemoryRegion]) {
+    let offset = *PHYS_OFFSET.call_once(|| VirtAddr::new(physical_memory_offset));
+    MAPPER.call_once(|| {
+        let (level_4_frame, _) = Cr3::read();
+        let level_4_table: *mut PageTable =
+            (offset + level_4_frame.start_address().as_u64()).as_mut_ptr();
+        Mutex::new(unsafe { OffsetPageTable::new(&mut *level_4_table, offset) })
+    });
+    FRAME_ALLOCATOR.call_once(|| Mutex::new(BootInfoFrameAllocator::new(memory_regions)));
+    init_pat();
+    shell::register(&MemCommand);
+    shell::register(&FramesCommand);
+}
+
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    *PHYS_OFFSET.get().expect("memory not initialized") + addr.as_u64()
+}
+
+/// Whether `addr` is mapped. Says no rather than wait if the page tables are
+/// being changed, as it is used from exception handlers.
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    page_flags(addr).is_some()
+}
+
+/// The flags of the page mapping `addr`, with the same caveat as `is_mapped`.
+pub fn page_flags(addr: VirtAddr) -> Option<PageTableFlags> {
+    match MAPPER.get()?.try_lock()?.translate(addr) {
+        TranslateResult::Mapped { flags, .. } => Some(flags),
+        _ => None,
+    }
+}
+
+/// Maps `len` bytes starting at `start` to freshly allocated frames.
+pub fn map_range(
+    start: VirtAddr,
+    len: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    // the last byte of an empty range would be before `start`
+    if len == 0 {
+        return Ok(());
+    }
+    let pages = Page::range_inclusive(
+        Page::<Size4KiB>::containing_address(start),
+        Page::containing_address(start + len - 1u64),
+    );
+    let mut mapper = MAPPER.get().expect("memory not initialized").lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+    for page in pages {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe {
+            mapper
+                .map_to(page, frame, flags, &mut *frame_allocator)?
+                .flush()
+        };
+    }
+    Ok(())
+}
+
+/// Maps `len` bytes at `start` to the physical memory at `phys`.
+pub fn map_physical(
+    start: VirtAddr,
+    phys: PhysAddr,
+    len: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    if len == 0 {
+        return Ok(());
+    }
+    let pages = Page::<Size4KiB>::range_inclusive(
+        Page::containing_address(start),
+        Page::containing_address(start + len - 1u64),
+    );
+    let mut mapper = MAPPER.get().expect("memory not initialized").lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+    for (i, page) in pages.enumerate() {
+        let frame = PhysFrame::containing_address(phys + i as u64 * 4096);
+        unsafe {
+            mapper
+                .map_to(page, frame, flags, &mut *frame_allocator)?
+                .flush()
+        };
+    }
+    Ok(())
+}
+
+/// Unmaps the pages of a range, leaving the frames they mapped alone. No CPU
+/// uses the old mappings once this returns.
+pub fn unmap_range(start: VirtAddr, len: u64) {
+    if len == 0 {
+        return;
+    }
+    let pages = Page::<Size4KiB>::range_inclusive(
+        Page::containing_address(start),
+        Page::containing_address(start + len - 1u64),
+    );
+    {
+        let mut mapper = MAPPER.get().expect("memory not initialized").lock();
+        for page in pages {
+            if let Ok((_, flush)) = mapper.unmap(page) {
+                flush.flush();
+            }
+        }
+    }
+    shootdown(pages);
+}
+
+/// Changes the flags of the mapped pages of a range, on every CPU by the
+/// time this returns.
+pub fn protect_range(start: VirtAddr, len: u64, flags: PageTableFlags) {
+    if len == 0 {
+        return;
+    }
+    let pages = Page::<Size4KiB>::range_inclusive(
+        Page::containing_address(start),
+        Page::containing_address(start + len - 1u64),
+    );
+    {
+        let mut mapper = MAPPER.get().expect("memory not initialized").lock();
+        for page in pages {
+            if let Ok(flush) = unsafe { mapper.update_flags(page, flags) } {
+                flush.flush();
+            }
+        }
+    }
+    shootdown(pages);
+}
+
+/// Has the other CPUs drop what their TLBs hold for `pages`, which the
+/// calling CPU remapped and flushed from its own. Called after the page
+/// tables are unlocked, as the other CPUs may be waiting for them with
+/// interrupts off.
+fn shootdown(pages: PageRangeInclusive<Size4KiB>) {
+    smp::call_on_others(|| {
+        if pages.end - pages.start >= SHOOTDOWN_FLUSH_ALL {
+            tlb::flush_all();
+        } else {
+            for page in pages {
+                tlb::flush(page.start_address());
+            }
+        }
+    });
+}
+
+/// Maps `len` bytes of device registers at `phys` uncached and returns where
+/// they ended up.
+pub fn map_mmio(phys: PhysAddr, len: u64) -> VirtAddr {
+    let offset = phys.as_u64() % 4096;
+    let size = x86_64::align_up(offset + len, 4096);
+    let start = VirtAddr::new(NEXT_MMIO.fetch_add(size, Ordering::Relaxed));
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+    map_physical(start, phys.align_down(4096u64), size, flags).expect("failed to map mmio");
+    start + offset
+}
+
+/// A frame below 1 MiB that is never allocated, for code that has to run in
+/// real mode, like the trampoline that starts the other CPUs.
+pub fn low_frame() -> Option<PhysFrame> {
+    let low_frame = FRAME_ALLOCATOR.get()?.lock().low_frame?;
+    Some(PhysFrame::containing_address(PhysAddr::new(low_frame)))
+}
+
+/// Remaps an already mapped range so writes to it are combined, which is what
+/// video memory wants. Ranges mapped with huge pages are left untouched.
+pub fn set_write_combining(start: VirtAddr, len: u64) {
+    if len == 0 {
+        return;
+    }
+    let pages = Page::<Size4KiB>::range_inclusive(
+        Page::containing_address(start),
+        Page::containing_address(start + len - 1u64),
+    );
+    {
+        let mut mapper = MAPPER.get().expect("memory not initialized").lock();
+        for page in pages {
+            let TranslateResult::Mapped { flags, .. } = mapper.translate(page.start_address())
+            else {
+                continue;
+            };
+            let flags = (flags | PageTableFlags::WRITE_THROUGH) - PageTableFlags::NO_CACHE;
+            if let Ok(flush) = unsafe { mapper.update_flags(page, flags) } {
+                flush.flush();
+            }
+        }
+    }
+    shootdown(pages);
+}
+
+/// Points the PAT entry used by `set_write_combining` at the write-combining
+/// memory type. Has to run on every CPU, the PAT is not shared.
+pub fn init_pat() {
+    let mut pat = Msr::new(IA32_PAT);
+    unsafe {
+        let value = pat.read() & !(0xff << (PAT_WC_INDEX * 8));
+        pat.write(value | PAT_WRITE_COMBINING << (PAT_WC_INDEX * 8));
+        core::arch::asm!("wbinvd", options(nostack, preserves_flags));
+    }
+    tlb::flush_all();
+}
+
+pub struct BootInfoFrameAllocator {
+    memory_regions: &'static [MemoryRegion],
+    region: usize,
+    next: u64,
+    allocated: u64,
+    /// Kept back for `low_frame`.
+    low_frame: Option<u64>,
+}
+
+impl BootInfoFrameAllocator {
+    fn new(memory_regions: &'static [MemoryRegion]) -> BootInfoFrameAllocator {
+        // the highest one, as the first page holds the real mode interrupt table
+        let low_frame = memory_regions
+            .iter()
+            .filter(|region| region.kind == MemoryRegionKind::Usable)
+            .filter_map(|region| {
+                let end = x86_64::align_down(region.end.min(0x10_0000), 4096);
+                let start = x86_64::align_up(region.start.max(0x1000), 4096);
+                (start < end).then(|| end - 4096)
+            })
+            .max();
+        BootInfoFrameAllocator {
+            memory_regions,
+            region: 0,
+            next: 0,
+            allocated: 0,
+            low_frame,
+        }
+    }
+
+    /// Returns `(allocated, usable)` frames.
+    pub fn usage(&self) -> (u64, u64) {
+        let usable = self
+            .memory_regions
+            .iter()
+            .filter(|region| region.kind == MemoryRegionKind::Usable)
+            .map(|region| {
+                let start = x86_64::align_up(region.start, 4096);
+                region.end.saturating_sub(start) / 4096
+            })
+            .sum();
+        (self.allocated, usable)
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        while let Some(region) = self.memory_regions.get(self.region) {
+            let start = x86_64::align_up(region.start.max(self.next), 4096);
+            if region.kind == MemoryRegionKind::Usable && start + 4096 <= region.end {
+                self.next = start + 4096;
+                if Some(start) == self.low_frame {
+                    continue;
+                }
+                self.allocated += 1;
+                return Some(PhysFrame::containing_address(PhysAddr::new(start)));
+            }
+            self.region += 1;
+        }
+        None
+    }
+}
+
+pub struct MemCommand;
+
+impl Command for MemCommand {
+    fn name(&self) -> &'static str {
+        "mem"
+    }
+
+    fn help(&self) -> &'static str {
+        "show the memory map and heap usage"
+    }
+
+    fn run(&self, _args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+        let regions = FRAME_ALLOCATOR.get().unwrap().lock().memory_regions;
+        for region in regions {
+            writeln!(
+                out,
+                "  {:#014x}-{:#014x} {:>8} KiB  {:?}",
+                region.start,
+                region.end,
+                (region.end - region.start) / 1024,
+                region.kind
+            )?;
+        }
+        let (used, free) = allocator::usage();
+        writeln!(
+            out,
+            "heap: {} KiB used, {} KiB free of {} KiB",
+            used / 1024,
+            free / 1024,
+            HEAP_SIZE / 1024
+        )?;
+        Ok(())
+    }
+}
+
+pub struct FramesCommand;
+
+impl Command for FramesCommand {
+    fn name(&self) -> &'static str {
+        "frames"
+    }
+
+    fn help(&self) -> &'static str {
+        "show physical frame usage"
+    }
+
+    fn run(&self, _args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+        let (allocated, usable) = FRAME_ALLOCATOR.get().unwrap().lock().usage();
+        writeln!(
+            out,
+            "{} of {} frames allocated ({} of {} MiB)",
+            allocated,
+            usable,
+            (allocated * 4096) >> 20,
+            (usable * 4096) >> 20
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn empty_ranges_are_left_alone() {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        // mid-page, where an empty range used to cover the whole page
+        let start = VirtAddr::new(0x1234_5678_9010);
+        assert!(map_range(start, 0, flags).is_ok());
+        assert!(!is_mapped(start));
+        // and at 0, where the last byte used to underflow
+        unmap_range(VirtAddr::zero(), 0);
+        protect_range(VirtAddr::zero(), 0, flags);
+    }
+}
@@ -0,0 +1,138 @@
+use crate::shell::{self, Command, CommandError};
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+const NO_DEVICE: u16 = 0xffff;
+const MULTIFUNCTION: u8 = 0x80;
+
+/// The address and data ports, which have to be used as a pair.
+static CONFIG: Mutex<(Port<u32>, Port<u32>)> =
+    Mutex::new((Port::new(CONFIG_ADDRESS), Port::new(CONFIG_DATA)));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Device {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+}
+
+impl Device {
+    pub fn class_name(&self) -> &'static str {
+        match self.class {
+            0x01 => "storage",
+            0x02 => "network",
+            0x03 => "display",
+            0x04 => "multimedia",
+            0x05 => "memory",
+            0x06 => "bridge",
+            0x07 => "communication",
+            0x08 => "system peripheral",
+            0x09 => "input",
+            0x0c => "serial bus",
+            0x0d => "wireless",
+            _ => "other",
+        }
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:02x}.{} {:04x}:{:04x} {:02x}{:02x}{:02x} {}",
+            self.bus,
+            self.device,
+            self.function,
+            self.vendor_id,
+            self.device_id,
+            self.class,
+            self.subclass,
+            self.prog_if,
+            self.class_name()
+        )
+    }
+}
+
+pub fn init() {
+    shell::register(&PciCommand);
+}
+
+/// Reads the configuration dword at `offset`, which is rounded down to a
+/// multiple of four.
+pub fn read_config(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    let address = 1 << 31
+        | (bus as u32) << 16
+        | (device as u32) << 11
+        | (function as u32) << 8
+        | (offset as u32 & 0xfc);
+    interrupts::without_interrupts(|| {
+        let mut config = CONFIG.lock();
+        unsafe {
+            config.0.write(address);
+            config.1.read()
+        }
+    })
+}
+
+/// Every function on every bus, found by trying them all.
+pub fn devices() -> Vec<Device> {
+    let mut devices = Vec::new();
+    for bus in 0..=255 {
+        for device in 0..32 {
+            for function in 0..8 {
+                let id = read_config(bus, device, function, 0x00);
+                if id as u16 == NO_DEVICE {
+                    if function == 0 {
+                        break;
+                    }
+                    continue;
+                }
+                let class = read_config(bus, device, function, 0x08);
+                devices.push(Device {
+                    bus,
+                    device,
+                    function,
+                    vendor_id: id as u16,
+                    device_id: (id >> 16) as u16,
+                    class: (class >> 24) as u8,
+                    subclass: (class >> 16) as u8,
+                    prog_if: (class >> 8) as u8,
+                });
+                let header_type = (read_config(bus, device, 0, 0x0c) >> 16) as u8;
+                if function == 0 && header_type & MULTIFUNCTION == 0 {
+                    break;
+                }
+            }
+        }
+    }
+    devices
+}
+
+pub struct PciCommand;
+
+impl Command for PciCommand {
+    fn name(&self) -> &'static str {
+        "pci"
+    }
+
+    fn help(&self) -> &'static str {
+        "list pci devices"
+    }
+
+    fn run(&self, _args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+        for device in devices() {
+            writeln!(out, "  {}", device)?;
+        }
+        Ok(())
+    }
+}
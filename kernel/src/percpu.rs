@@ -0,0 +1,90 @@
+use crate::smp::MAX_CPUS;
+use core::arch::asm;
+use core::mem::offset_of;
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::GsBase;
+use x86_64::VirtAddr;
+
+/// What `GS` points at on each CPU.
+#[repr(C)]
+struct Cpu {
+    id: usize,
+}
+
+static CPUS: [Cpu; MAX_CPUS] = {
+    let mut cpus = [const { Cpu { id: 0 } }; MAX_CPUS];
+    let mut i = 0;
+    while i < MAX_CPUS {
+        cpus[i].id = i;
+        i += 1;
+    }
+    cpus
+};
+
+/// Points the calling CPU's `GS` base at its per-CPU area. Has to come
+/// before anything that uses `id` or a `cpu_local!` static. Kernel code runs
+/// with this `GS`, so entries from user mode, once there is one, will have to
+/// `swapgs` first.
+pub fn init(cpu: usize) {
+    GsBase::write(VirtAddr::from_ptr(&CPUS[cpu]));
+}
+
+/// The calling CPU's number, the bootstrap processor being 0. Only stays
+/// right while the caller can't move to another CPU.
+pub fn id() -> usize {
+    let id;
+    unsafe {
+        asm!(
+            "mov {}, gs:[{}]",
+            out(reg) id,
+            const offset_of!(Cpu, id),
+            options(nostack, readonly, preserves_flags),
+        );
+    }
+    id
+}
+
+/// A value per CPU, declared with `cpu_local!`.
+pub struct CpuLocal<T>([T; MAX_CPUS]);
+
+// a CPU only gets at other CPUs' values through `for_cpu`, which needs T: Sync
+unsafe impl<T: Send> Sync for CpuLocal<T> {}
+
+impl<T> CpuLocal<T> {
+    #[doc(hidden)]
+    pub const fn new(values: [T; MAX_CPUS]) -> CpuLocal<T> {
+        CpuLocal(values)
+    }
+
+    /// Calls `f` with the calling CPU's value. Interrupts are off meanwhile,
+    /// so nothing else on this CPU gets at it and the caller stays put.
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        interrupts::without_interrupts(|| f(&self.0[id()]))
+    }
+}
+
+impl<T: Sync> CpuLocal<T> {
+    /// The calling CPU's value, for ones that are fine to share, like
+    /// counters.
+    pub fn get(&self) -> &T {
+        &self.0[id()]
+    }
+
+    /// CPU `cpu`'s value.
+    pub fn for_cpu(&self, cpu: usize) -> &T {
+        &self.0[cpu]
+    }
+}
+
+/// Declares statics that hold a value per CPU, each starting out as the
+/// initializer, which has to be a constant.
+#[macro_export]
+macro_rules! cpu_local {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::percpu::CpuLocal<$ty> =
+                $crate::percpu::CpuLocal::new([const { $init }; $crate::smp::MAX_CPUS]);
+        )*
+    };
+}
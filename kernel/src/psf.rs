@@ -0,0 +1,316 @@
+use alloc::collections::BTreeMap;
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_MODE512: u8 = 0x01;
+const PSF1_MODEHASTAB: u8 = 0x02;
+const PSF1_MODESEQ: u8 = 0x04;
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+/// What glyphs 128 to 255 show in code page 437, the builtin font's layout.
+const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', //
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', //
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', //
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐', //
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧', //
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀', //
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', //
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsfError {
+    BadMagic,
+    Truncated,
+    /// Glyphs with no pixels, which nothing could be drawn with.
+    EmptyGlyphs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsfVersion {
+    Psf1,
+    Psf2,
+}
+
+/// A bitmap font in PC Screen Font format, borrowed from the bytes it was
+/// parsed from.
+#[derive(Clone)]
+pub struct Font<'a> {
+    version: PsfVersion,
+    width: usize,
+    height: usize,
+    num_glyphs: usize,
+    glyph_size: usize,
+    glyphs: &'a [u8],
+    unicode_table: Option<&'a [u8]>,
+    /// Which glyph shows each character, from the unicode table.
+    glyph_map: BTreeMap<char, usize>,
+}
+
+impl<'a> Font<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Font<'a>, PsfError> {
+        if bytes.starts_with(&PSF1_MAGIC) {
+            Self::parse_psf1(bytes)
+        } else if bytes.starts_with(&PSF2_MAGIC) {
+            Self::parse_psf2(bytes)
+        } else {
+            Err(PsfError::BadMagic)
+        }
+    }
+
+    fn parse_psf1(bytes: &'a [u8]) -> Result<Font<'a>, PsfError> {
+        let mode = *bytes.get(2).ok_or(PsfError::Truncated)?;
+        let glyph_size = *bytes.get(3).ok_or(PsfError::Truncated)? as usize;
+        if glyph_size == 0 {
+            return Err(PsfError::EmptyGlyphs);
+        }
+        let num_glyphs = if mode & PSF1_MODE512 > 0 { 512 } else { 256 };
+        let glyphs_end = 4 + num_glyphs * glyph_size;
+        let glyphs = bytes.get(4..glyphs_end).ok_or(PsfError::Truncated)?;
+        let unicode_table =
+            (mode & (PSF1_MODEHASTAB | PSF1_MODESEQ) > 0).then(|| &bytes[glyphs_end..]);
+        Ok(Font {
+            version: PsfVersion::Psf1,
+            width: 8,
+            height: glyph_size,
+            num_glyphs,
+            glyph_size,
+            glyphs,
+            unicode_table,
+            glyph_map: BTreeMap::new(),
+        }
+        .with_glyph_map())
+    }
+
+    fn parse_psf2(bytes: &'a [u8]) -> Result<Font<'a>, PsfError> {
+        let field = |i: usize| -> Result<usize, PsfError> {
+            let field = bytes.get(i * 4..i * 4 + 4).ok_or(PsfError::Truncated)?;
+            Ok(u32::from_le_bytes(field.try_into().unwrap()) as usize)
+        };
+        let header_size = field(2)?;
+        let flags = field(3)? as u32;
+        let num_glyphs = field(4)?;
+        let glyph_size = field(5)?;
+        let height = field(6)?;
+        let width = field(7)?;
+        if glyph_size == 0 || height == 0 || width == 0 {
+            return Err(PsfError::EmptyGlyphs);
+        }
+        if height
+            .checked_mul(width.div_ceil(8))
+            .is_none_or(|size| glyph_size < size)
+        {
+            return Err(PsfError::Truncated);
+        }
+        let glyphs_end = num_glyphs
+            .checked_mul(glyph_size)
+            .and_then(|size| size.checked_add(header_size))
+            .ok_or(PsfError::Truncated)?;
+        let glyphs = bytes
+            .get(header_size..glyphs_end)
+            .ok_or(PsfError::Truncated)?;
+        let unicode_table = (flags & PSF2_HAS_UNICODE_TABLE > 0).then(|| &bytes[glyphs_end..]);
+        Ok(Font {
+            version: PsfVersion::Psf2,
+            width,
+            height,
+            num_glyphs,
+            glyph_size,
+            glyphs,
+            unicode_table,
+            glyph_map: BTreeMap::new(),
+        }
+        .with_glyph_map())
+    }
+
+    fn with_glyph_map(mut self) -> Font<'a> {
+        let Some(table) = self.unicode_table else {
+            // without a table, assume the glyphs are in code page 437 order
+            let chars = (0..128u8).map(char::from).chain(CP437_HIGH);
+            self.glyph_map = chars.zip(0..self.num_glyphs).collect();
+            return self;
+        };
+        let mut add = |glyph: usize, c: char| {
+            // the first glyph listing a character wins
+            self.glyph_map.entry(c).or_insert(glyph);
+        };
+        match self.version {
+            // u16 code points per glyph, 0xfffe starts multi-char sequences
+            // and 0xffff ends the glyph's entry
+            PsfVersion::Psf1 => {
+                let mut entries = table
+                    .chunks_exact(2)
+                    .map(|pair| u16::from_le_bytes([pair[0], pair[1]]));
+                for glyph in 0..self.num_glyphs {
+                    let mut sequence = false;
+                    for point in entries.by_ref().take_while(|&point| point != 0xffff) {
+                        sequence |= point == 0xfffe;
+                        // surrogates aren't characters
+                        match char::from_u32(point as u32) {
+                            Some(c) if !sequence => add(glyph, c),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            // same layout with utf-8 strings, 0xfe and 0xff as separators
+            PsfVersion::Psf2 => {
+                let entries = table.split(|&b| b == 0xff).take(self.num_glyphs);
+                for (glyph, entry) in entries.enumerate() {
+                    let singles = entry.split(|&b| b == 0xfe).next().unwrap_or(&[]);
+                    // a malformed entry maps nothing
+                    let singles = core::str::from_utf8(singles).unwrap_or("");
+                    singles.chars().for_each(|c| add(glyph, c));
+                }
+            }
+        }
+        self
+    }
+
+    /// Finds the glyph for `c`.
+    pub fn glyph_for(&self, c: char) -> Option<usize> {
+        self.glyph_map.get(&c).copied()
+    }
+
+    pub fn dim(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    pub fn num_glyphs(&self) -> usize {
+        self.num_glyphs
+    }
+
+    pub fn version(&self) -> PsfVersion {
+        self.version
+    }
+
+    pub fn unicode_table(&self) -> Option<&'a [u8]> {
+        self.unicode_table
+    }
+
+    /// Whether pixel `(x, y)` of glyph `index` is set. Out of range glyphs are
+    /// blank.
+    pub fn pixel(&self, index: usize, (x, y): (usize, usize)) -> bool {
+        if index >= self.num_glyphs {
+            return false;
+        }
+        let bytes_per_row = self.width.div_ceil(8);
+        let byte = self.glyphs[index * self.glyph_size + y * bytes_per_row + x / 8];
+        byte & (0x80 >> (x % 8)) > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    fn psf2(fields: [u32; 6], glyph_bytes: usize) -> Vec<u8> {
+        let mut bytes = PSF2_MAGIC.to_vec();
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        for field in fields {
+            bytes.extend_from_slice(&field.to_le_bytes());
+        }
+        bytes.resize(bytes.len() + glyph_bytes, 0xff);
+        bytes
+    }
+
+    #[test_case]
+    fn bad_magic() {
+        assert_eq!(Font::parse(&[]).err(), Some(PsfError::BadMagic));
+        assert_eq!(Font::parse(b"\x36\x05").err(), Some(PsfError::BadMagic));
+    }
+
+    #[test_case]
+    fn truncated() {
+        assert_eq!(Font::parse(&PSF1_MAGIC).err(), Some(PsfError::Truncated));
+        let mut psf1 = vec![0x36, 0x04, 0, 8];
+        psf1.resize(4 + 255 * 8, 0);
+        assert_eq!(Font::parse(&psf1).err(), Some(PsfError::Truncated));
+        assert_eq!(
+            Font::parse(&psf2([32, 0, 2, 8, 8, 8], 0)[..20]).err(),
+            Some(PsfError::Truncated)
+        );
+        assert_eq!(
+            Font::parse(&psf2([32, 0, 2, 8, 8, 8], 15)).err(),
+            Some(PsfError::Truncated)
+        );
+        // glyphs smaller than their rows
+        assert_eq!(
+            Font::parse(&psf2([32, 0, 2, 7, 8, 8], 14)).err(),
+            Some(PsfError::Truncated)
+        );
+        assert_eq!(
+            Font::parse(&psf2([32, 0, u32::MAX, u32::MAX, 1, 8], 0)).err(),
+            Some(PsfError::Truncated)
+        );
+    }
+
+    #[test_case]
+    fn empty_glyphs() {
+        assert_eq!(
+            Font::parse(&[0x36, 0x04, 0, 0]).err(),
+            Some(PsfError::EmptyGlyphs)
+        );
+        for fields in [
+            [32, 0, 2, 0, 8, 8],
+            [32, 0, 2, 8, 0, 8],
+            [32, 0, 2, 8, 8, 0],
+        ] {
+            assert_eq!(
+                Font::parse(&psf2(fields, 16)).err(),
+                Some(PsfError::EmptyGlyphs)
+            );
+        }
+    }
+
+    #[test_case]
+    fn parses_psf2() {
+        let bytes = psf2([32, 0, 2, 8, 8, 8], 16);
+        let font = Font::parse(&bytes).unwrap();
+        assert_eq!(font.dim(), (8, 8));
+        assert_eq!(font.num_glyphs(), 2);
+        assert!(font.pixel(1, (7, 7)));
+        assert!(!font.pixel(2, (0, 0)));
+    }
+
+    #[test_case]
+    fn cp437_without_table() {
+        let font = Font::parse(&crate::font::DEFAULT_FONT).unwrap();
+        assert_eq!(font.glyph_for('A'), Some(0x41));
+        assert_eq!(font.glyph_for('é'), Some(0x82));
+        assert_eq!(font.glyph_for('│'), Some(0xb3));
+        assert_eq!(font.glyph_for('\u{a0}'), Some(0xff));
+        assert_eq!(font.glyph_for('€'), None);
+    }
+
+    #[test_case]
+    fn psf1_table() {
+        let mut bytes = vec![0x36, 0x04, PSF1_MODEHASTAB, 1];
+        bytes.resize(4 + 256, 0);
+        // glyph 0 is 'a' and a sequence, glyph 1 a surrogate, then 'é' and 'a'
+        let table: [u16; 9] = [
+            0x61, 0xfffe, 0x62, 0xffff, 0xd800, 0xffff, 0xe9, 0x61, 0xffff,
+        ];
+        table
+            .iter()
+            .for_each(|point| bytes.extend_from_slice(&point.to_le_bytes()));
+        let font = Font::parse(&bytes).unwrap();
+        assert_eq!(font.glyph_for('a'), Some(0));
+        assert_eq!(font.glyph_for('b'), None);
+        assert_eq!(font.glyph_for('é'), Some(2));
+        assert_eq!(font.glyph_for('c'), None);
+    }
+
+    #[test_case]
+    fn psf2_table() {
+        let mut bytes = psf2([32, PSF2_HAS_UNICODE_TABLE, 2, 8, 8, 8], 16);
+        // a malformed entry, then 'é' with a sequence
+        bytes.extend_from_slice(b"\xc3\xff\xc3\xa9\xfeab\xff");
+        let font = Font::parse(&bytes).unwrap();
+        assert_eq!(font.glyph_for('é'), Some(1));
+        assert_eq!(font.glyph_for('a'), None);
+        assert_eq!(font.glyph_for('\u{fffd}'), None);
+    }
+}
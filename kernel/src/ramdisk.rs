@@ -0,0 +1,65 @@
+use bootloader_api::info::Optional;
+use core::str;
+use spin::Once;
+
+const BLOCK_SIZE: usize = 512;
+
+static RAMDISK: Once<&'static [u8]> = Once::new();
+
+/// Picks up the ramdisk the bootloader loaded, which is expected to be an
+/// uncompressed ustar archive.
+pub fn init(addr: Optional<u64>, len: u64) {
+    if let Some(addr) = addr.into_option() {
+        RAMDISK
+            .call_once(|| unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) });
+    }
+}
+
+pub fn file(name: &str) -> Option<&'static [u8]> {
+    files()
+        .find(|&(path, _)| path == name)
+        .map(|(_, data)| data)
+}
+
+pub fn files() -> Files {
+    Files {
+        archive: RAMDISK.get().copied().unwrap_or(&[]),
+    }
+}
+
+pub struct Files {
+    archive: &'static [u8],
+}
+
+impl Iterator for Files {
+    type Item = (&'static str, &'static [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let header = self.archive.get(..BLOCK_SIZE)?;
+            if header[0] == 0 || &header[257..262] != b"ustar" {
+                return None;
+            }
+            let size = octal(&header[124..136])?;
+            let data_end = BLOCK_SIZE + size;
+            let data = self.archive.get(BLOCK_SIZE..data_end)?;
+            let next = data_end.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+            self.archive = self.archive.get(next..).unwrap_or(&[]);
+            // only regular files, skip directories and links
+            if matches!(header[156], b'0' | 0) {
+                let name = cstr(&header[..100]);
+                return Some((name.strip_prefix("./").unwrap_or(name), data));
+            }
+        }
+    }
+}
+
+fn cstr(bytes: &[u8]) -> &str {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    str::from_utf8(&bytes[..len]).unwrap_or("")
+}
+
+fn octal(bytes: &[u8]) -> Option<usize> {
+    let digits = cstr(bytes).trim_matches(|c| c == ' ' || c == '\0');
+    usize::from_str_radix(digits, 8).ok()
+}
@@ -0,0 +1,319 @@
+use crate::cmdline;
+use crate::interrupt::ticks;
+use crate::thread::ThreadId;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeSet, VecDeque};
+use alloc::vec::Vec;
+
+pub const NUM_PRIORITIES: u8 = 8;
+/// Priorities go from 0, the most urgent, to `NUM_PRIORITIES - 1`.
+pub const DEFAULT_PRIORITY: u8 = 3;
+/// Timer ticks a thread runs before others of the same standing get a turn.
+pub const TIME_SLICE: u64 = 2;
+/// Ticks a thread waits to have its priority raised by one.
+const AGING_TICKS: u64 = 10;
+/// CPU share of each priority under `Fair`, relative to the default 1024.
+const WEIGHTS: [u64; NUM_PRIORITIES as usize] = [4096, 2560, 1600, 1024, 640, 400, 250, 156];
+
+/// What a policy gets to see of a thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entity {
+    pub id: ThreadId,
+    pub priority: u8,
+    /// Timer ticks spent running.
+    pub runtime: u64,
+    /// Runtime scaled by priority, in 1024ths of a tick at the default one.
+    pub vruntime: u64,
+}
+
+impl Entity {
+    pub fn new(id: ThreadId) -> Entity {
+        Entity {
+            id,
+            priority: DEFAULT_PRIORITY,
+            runtime: 0,
+            vruntime: 0,
+        }
+    }
+}
+
+/// A scheduling policy. It holds the ready threads and decides which runs
+/// next, while the thread module does the switching. Its methods run with
+/// interrupts off, some of them in the timer interrupt.
+pub trait Scheduler: Send {
+    fn name(&self) -> &'static str;
+
+    /// Adds a thread that became ready, being new, woken or preempted.
+    fn enqueue(&mut self, entity: &mut Entity);
+
+    /// Removes and returns the thread to run next.
+    fn pick_next(&mut self) -> Option<ThreadId>;
+
+    fn is_empty(&self) -> bool;
+
+    /// Called on every timer tick with the running thread and how long it
+    /// has been running. Returns whether to preempt it.
+    fn tick(&mut self, current: &mut Entity, slice: u64) -> bool;
+
+    /// Called when the running thread yields, before it is enqueued again.
+    /// Policies that would pick it right back can move it behind the others.
+    fn yield_current(&mut self, _current: &mut Entity) {}
+}
+
+/// The policy chosen with the `sched` boot option, round-robin by default.
+pub fn from_cmdline() -> Box<dyn Scheduler> {
+    match cmdline::get("sched") {
+        Some("priority") => Box::new(Priority::default()),
+        Some("fair") => Box::new(Fair::default()),
+        _ => Box::new(RoundRobin::default()),
+    }
+}
+
+/// Runs threads in turn, a time slice each, ignoring priorities.
+#[derive(Default)]
+pub struct RoundRobin {
+    ready: VecDeque<ThreadId>,
+}
+
+impl Scheduler for RoundRobin {
+    fn name(&self) -> &'static str {
+        "round-robin"
+    }
+
+    fn enqueue(&mut self, entity: &mut Entity) {
+        self.ready.push_back(entity.id);
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        self.ready.pop_front()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ready.is_empty()
+    }
+
+    fn tick(&mut self, _current: &mut Entity, slice: u64) -> bool {
+        slice >= TIME_SLICE
+    }
+}
+
+/// Always runs the most urgent thread, round-robin among equals. Waiting
+/// threads gain a level every `AGING_TICKS` so they can't starve.
+#[derive(Default)]
+pub struct Priority {
+    /// Thread, priority and when it started waiting, in arrival order.
+    ready: Vec<(ThreadId, u8, u64)>,
+}
+
+impl Priority {
+    fn effective(priority: u8, since: u64, now: u64) -> u8 {
+        let aged = (now - since) / AGING_TICKS;
+        priority.saturating_sub(aged.min(u8::MAX as u64) as u8)
+    }
+
+    /// The most urgent waiting thread's index and effective priority.
+    fn best(&self) -> Option<(usize, u8)> {
+        let now = ticks();
+        self.ready
+            .iter()
+            .enumerate()
+            .map(|(i, &(_, priority, since))| (i, Priority::effective(priority, since, now)))
+            .min_by_key(|&(i, priority)| (priority, i))
+    }
+}
+
+impl Scheduler for Priority {
+    fn name(&self) -> &'static str {
+        "priority"
+    }
+
+    fn enqueue(&mut self, entity: &mut Entity) {
+        self.ready.push((entity.id, entity.priority, ticks()));
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        let (index, _) = self.best()?;
+        Some(self.ready.remove(index).0)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ready.is_empty()
+    }
+
+    fn tick(&mut self, current: &mut Entity, slice: u64) -> bool {
+        self.best().is_some_and(|(_, priority)| {
+            priority < current.priority || (priority == current.priority && slice >= TIME_SLICE)
+        })
+    }
+}
+
+/// Gives every thread a share of the CPU by its priority's weight, running
+/// whichever has had the least weighted runtime, like Linux's CFS.
+#[derive(Default)]
+pub struct Fair {
+    ready: BTreeSet<(u64, ThreadId)>,
+    /// Never goes backwards, so threads that slept don't come back owed all
+    /// the time they missed.
+    min_vruntime: u64,
+}
+
+impl Scheduler for Fair {
+    fn name(&self) -> &'static str {
+        "fair"
+    }
+
+    fn enqueue(&mut self, entity: &mut Entity) {
+        entity.vruntime = entity.vruntime.max(self.min_vruntime);
+        self.ready.insert((entity.vruntime, entity.id));
+    }
+
+    fn pick_next(&mut self) -> Option<ThreadId> {
+        let (vruntime, id) = self.ready.pop_first()?;
+        self.min_vruntime = self.min_vruntime.max(vruntime);
+        Some(id)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ready.is_empty()
+    }
+
+    fn tick(&mut self, current: &mut Entity, slice: u64) -> bool {
+        current.vruntime += 1024 * 1024 / WEIGHTS[current.priority as usize];
+        self.ready
+            .first()
+            .is_some_and(|&(vruntime, _)| vruntime < current.vruntime && slice >= 1)
+    }
+
+    fn yield_current(&mut self, current: &mut Entity) {
+        if let Some(&(last, _)) = self.ready.last() {
+            current.vruntime = current.vruntime.max(last + 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entity(id: u64, priority: u8) -> Entity {
+        Entity {
+            priority,
+            ..Entity::new(ThreadId::from_u64(id))
+        }
+    }
+
+    fn drain(policy: &mut dyn Scheduler) -> Vec<u64> {
+        core::iter::from_fn(|| policy.pick_next())
+            .map(ThreadId::as_u64)
+            .collect()
+    }
+
+    #[test_case]
+    fn round_robin_in_turn() {
+        let mut policy = RoundRobin::default();
+        assert!(policy.is_empty());
+        assert_eq!(policy.pick_next(), None);
+        for (id, priority) in [(1, 7), (2, 0), (3, 3)] {
+            policy.enqueue(&mut entity(id, priority));
+        }
+        assert!(!policy.is_empty());
+        assert_eq!(drain(&mut policy), [1, 2, 3]);
+        assert!(policy.is_empty());
+
+        let mut current = entity(1, 0);
+        assert!(!policy.tick(&mut current, TIME_SLICE - 1));
+        assert!(policy.tick(&mut current, TIME_SLICE));
+    }
+
+    #[test_case]
+    fn priority_most_urgent_first() {
+        let mut policy = Priority::default();
+        assert_eq!(policy.pick_next(), None);
+        for (id, priority) in [(1, 5), (2, 1), (3, 5), (4, 3), (5, 1)] {
+            policy.enqueue(&mut entity(id, priority));
+        }
+        // equals keep their arrival order
+        assert_eq!(drain(&mut policy), [2, 5, 4, 1, 3]);
+        assert!(policy.is_empty());
+    }
+
+    #[test_case]
+    fn priority_preemption() {
+        let mut policy = Priority::default();
+        let mut current = entity(1, 3);
+        assert!(!policy.tick(&mut current, TIME_SLICE));
+
+        policy.enqueue(&mut entity(2, 5));
+        assert!(!policy.tick(&mut current, TIME_SLICE));
+
+        policy.enqueue(&mut entity(3, 3));
+        assert!(!policy.tick(&mut current, TIME_SLICE - 1));
+        assert!(policy.tick(&mut current, TIME_SLICE));
+
+        policy.enqueue(&mut entity(4, 0));
+        assert!(policy.tick(&mut current, 0));
+    }
+
+    #[test_case]
+    fn priority_aging() {
+        assert_eq!(Priority::effective(5, 100, 100), 5);
+        assert_eq!(Priority::effective(5, 100, 100 + AGING_TICKS - 1), 5);
+        assert_eq!(Priority::effective(5, 100, 100 + AGING_TICKS), 4);
+        assert_eq!(Priority::effective(5, 100, 100 + 3 * AGING_TICKS), 2);
+        assert_eq!(Priority::effective(5, 0, u64::MAX), 0);
+    }
+
+    #[test_case]
+    fn fair_least_vruntime_first() {
+        let mut policy = Fair::default();
+        assert_eq!(policy.pick_next(), None);
+        for (id, vruntime) in [(1, 300), (2, 100), (3, 200)] {
+            policy.enqueue(&mut Entity {
+                vruntime,
+                ..entity(id, DEFAULT_PRIORITY)
+            });
+        }
+        assert_eq!(drain(&mut policy), [2, 3, 1]);
+
+        // a thread that was away is brought up to the others
+        let mut late = entity(4, DEFAULT_PRIORITY);
+        policy.enqueue(&mut late);
+        assert_eq!(late.vruntime, 300);
+        assert_eq!(drain(&mut policy), [4]);
+    }
+
+    #[test_case]
+    fn fair_weights() {
+        let mut policy = Fair::default();
+        let mut current = entity(1, DEFAULT_PRIORITY);
+        assert!(!policy.tick(&mut current, 1));
+        assert_eq!(current.vruntime, 1024);
+
+        let mut urgent = entity(2, 0);
+        let mut lax = entity(3, NUM_PRIORITIES - 1);
+        policy.tick(&mut urgent, 1);
+        policy.tick(&mut lax, 1);
+        assert!(urgent.vruntime < current.vruntime);
+        assert!(lax.vruntime > current.vruntime);
+
+        // preempted once a waiting thread is behind, but not before a tick
+        policy.enqueue(&mut entity(4, DEFAULT_PRIORITY));
+        assert!(!policy.tick(&mut current, 0));
+        assert!(policy.tick(&mut current, 1));
+    }
+
+    #[test_case]
+    fn fair_yield_goes_last() {
+        let mut policy = Fair::default();
+        for (id, vruntime) in [(1, 100), (2, 500)] {
+            policy.enqueue(&mut Entity {
+                vruntime,
+                ..entity(id, DEFAULT_PRIORITY)
+            });
+        }
+        let mut current = entity(3, DEFAULT_PRIORITY);
+        policy.yield_current(&mut current);
+        policy.enqueue(&mut current);
+        assert_eq!(drain(&mut policy), [1, 2, 3]);
+    }
+}
@@ -1,15 +1,80 @@
-use core::fmt::Write;
+use crate::console::InputQueue;
+use crate::executor::WakerSlot;
+use crate::lockdep::SpinLock;
+use core::fmt::{self, Write};
+use core::future::poll_fn;
+use core::task::Poll;
 use spin::Mutex;
 use uart_16550::SerialPort;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
 
-pub static SERIAL1: Mutex<SerialPort> = Mutex::new(unsafe { SerialPort::new(0x3F8) });
+const COM1: u16 = 0x3F8;
+const LINE_STATUS: u16 = 5;
+const TRANSMIT_EMPTY: u8 = 1 << 5;
+/// Polls of the line status before a byte is sent regardless, so a missing
+/// port can't hang the panic path.
+const TRANSMIT_SPINS: usize = 100_000;
+
+pub static SERIAL1: SpinLock<SerialPort> =
+    SpinLock::new("SERIAL1", unsafe { SerialPort::new(COM1) });
+static INPUT: Mutex<InputQueue> = Mutex::new(InputQueue::new());
+static INPUT_WAKER: WakerSlot = WakerSlot::new();
+
+/// Moves received bytes to the input queue. Called from the COM1 interrupt.
+pub fn handle_interrupt() {
+    let mut serial = SERIAL1.lock();
+    let mut input = INPUT.lock();
+    while let Ok(byte) = serial.try_receive() {
+        input.push(byte);
+    }
+    drop((serial, input));
+    INPUT_WAKER.wake();
+}
+
+pub fn read_input() -> Option<u8> {
+    interrupts::without_interrupts(|| INPUT.lock().pop())
+}
+
+/// Waits for a byte received on COM1.
+pub async fn next_input() -> u8 {
+    poll_fn(|cx| {
+        INPUT_WAKER.register(cx.waker());
+        read_input().map_or(Poll::Pending, Poll::Ready)
+    })
+    .await
+}
+
+/// Writes straight to COM1's registers without `SERIAL1`, whose lock may
+/// never be released if its holder panicked. For the panic path only, as
+/// output can interleave with anyone holding the lock.
+pub struct RawSerial;
+
+impl Write for RawSerial {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut line_status = Port::<u8>::new(COM1 + LINE_STATUS);
+        let mut data = Port::<u8>::new(COM1);
+        for byte in s.bytes() {
+            for _ in 0..TRANSMIT_SPINS {
+                if unsafe { line_status.read() } & TRANSMIT_EMPTY != 0 {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+            unsafe { data.write(byte) };
+        }
+        Ok(())
+    }
+}
 
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
-    SERIAL1
-        .lock()
-        .write_fmt(args)
-        .expect("Printing to serial failed");
+    interrupts::without_interrupts(|| {
+        SERIAL1
+            .lock()
+            .write_fmt(args)
+            .expect("Printing to serial failed");
+    });
 }
 
 /// Prints to the host through the serial interface.
@@ -0,0 +1,757 @@
+use crate::console::{self, SHELL_CONSOLE};
+use crate::serial::{self, SERIAL1};
+use crate::{executor, memory};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::x86_64::{__cpuid_count, CpuidResult};
+use core::fmt::{self, Write};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+const PROMPT: &str = "> ";
+const HISTORY_SIZE: usize = 64;
+const MAX_COMMANDS: usize = 32;
+const PEEK_DEFAULT: u64 = 64;
+const PEEK_MAX: u64 = 4096;
+
+/// Sorted by name, for `help` and tab completion. Not on the heap, as
+/// subsystems register their commands as they come up, some before it.
+static COMMANDS: Mutex<Registry> = Mutex::new(Registry {
+    commands: [None; MAX_COMMANDS],
+    len: 0,
+});
+
+/// A shell command. Subsystems implement it for their own commands and hand
+/// them to `register`.
+pub trait Command: Sync {
+    fn name(&self) -> &'static str;
+
+    /// The arguments, shown after the name by `help` and on usage errors.
+    fn usage(&self) -> &'static str {
+        ""
+    }
+
+    /// One line for `help`.
+    fn help(&self) -> &'static str;
+
+    fn run(&self, args: &[&str], out: &mut dyn Write) -> Result<(), CommandError>;
+}
+
+#[derive(Debug)]
+pub enum CommandError {
+    /// Wrong arguments, answered with the usage line.
+    Usage,
+    Failed(String),
+}
+
+impl From<fmt::Error> for CommandError {
+    fn from(_: fmt::Error) -> CommandError {
+        CommandError::Failed(String::from("output failed"))
+    }
+}
+
+struct Registry {
+    commands: [Option<&'static dyn Command>; MAX_COMMANDS],
+    len: usize,
+}
+
+impl Registry {
+    fn iter(&self) -> impl Iterator<Item = &'static dyn Command> + '_ {
+        self.commands[..self.len].iter().flatten().copied()
+    }
+}
+
+/// Adds a command to the shell. Subsystems call it for their own commands
+/// from their `init`.
+pub fn register(command: &'static dyn Command) {
+    let mut registry = COMMANDS.lock();
+    assert!(registry.len < MAX_COMMANDS, "too many shell commands");
+    let index = registry
+        .iter()
+        .take_while(|other| other.name() < command.name())
+        .count();
+    let len = registry.len;
+    registry.commands.copy_within(index..len, index + 1);
+    registry.commands[index] = Some(command);
+    registry.len += 1;
+}
+
+/// Registers the shell's own commands.
+pub fn init() {
+    register(&HelpCommand);
+    register(&ClearCommand);
+    register(&PeekCommand);
+    register(&PokeCommand);
+    register(&CpuidCommand);
+}
+
+/// Starts a shell on the shell console and another on serial, as tasks.
+pub fn start() {
+    executor::spawn(session(Terminal::Console(SHELL_CONSOLE)));
+    executor::spawn(session(Terminal::Serial));
+}
+
+async fn session(terminal: Terminal) {
+    let mut session = Session::new(terminal);
+    session.redraw();
+    loop {
+        let byte = terminal.read().await;
+        session.input(byte);
+    }
+}
+
+/// Parses a decimal or `0x` prefixed hexadecimal argument.
+pub fn parse_number(arg: &str) -> Result<u64, CommandError> {
+    let arg = arg.replace('_', "");
+    match arg.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => arg.parse(),
+    }
+    .map_err(|_| CommandError::Failed(format!("bad number '{}'", arg)))
+}
+
+fn commands() -> Vec<&'static dyn Command> {
+    COMMANDS.lock().iter().collect()
+}
+
+fn execute(line: &str, out: &mut dyn Write) {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let Some((&name, args)) = args.split_first() else {
+        return;
+    };
+    let Some(command) = commands()
+        .into_iter()
+        .find(|command| command.name() == name)
+    else {
+        let _ = writeln!(out, "unknown command '{}', try help", name);
+        return;
+    };
+    let _ = match command.run(args, out) {
+        Ok(()) => Ok(()),
+        Err(CommandError::Usage) => writeln!(out, "usage: {} {}", name, command.usage()),
+        Err(CommandError::Failed(message)) => writeln!(out, "{}: {}", name, message),
+    };
+}
+
+/// Where a shell reads and writes.
+#[derive(Clone, Copy)]
+enum Terminal {
+    Console(usize),
+    Serial,
+}
+
+impl Terminal {
+    async fn read(&self) -> u8 {
+        match *self {
+            Terminal::Console(tty) => console::next_input(tty).await,
+            Terminal::Serial => serial::next_input().await,
+        }
+    }
+}
+
+impl Write for Terminal {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match *self {
+            Terminal::Console(tty) => console::write(tty, s.as_bytes()),
+            // serial terminals are raw and need the carriage return
+            Terminal::Serial => interrupts::without_interrupts(|| {
+                let mut serial = SERIAL1.lock();
+                for byte in s.bytes() {
+                    if byte == b'\n' {
+                        serial.send_raw(b'\r');
+                    }
+                    serial.send_raw(byte);
+                }
+            }),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Escape {
+    None,
+    Started,
+    Csi(u16),
+    Ss3,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Key {
+    Char(u8),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    Tab,
+    Cancel,
+    KillToStart,
+    ClearScreen,
+}
+
+/// A line editor with history and completion of command names, which echoes
+/// and runs commands on `out`.
+struct Session<W: Write> {
+    out: W,
+    line: Vec<u8>,
+    cursor: usize,
+    history: Vec<Vec<u8>>,
+    // the history entry being shown and the line typed before going there
+    browsing: Option<usize>,
+    draft: Vec<u8>,
+    escape: Escape,
+    after_cr: bool,
+}
+
+impl<W: Write> Session<W> {
+    fn new(out: W) -> Session<W> {
+        Session {
+            out,
+            line: Vec::new(),
+            cursor: 0,
+            history: Vec::new(),
+            browsing: None,
+            draft: Vec::new(),
+            escape: Escape::None,
+            after_cr: false,
+        }
+    }
+
+    fn input(&mut self, byte: u8) {
+        let escape = core::mem::replace(&mut self.escape, Escape::None);
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+        let key = match (escape, byte) {
+            (Escape::None, 0x1b) => {
+                self.escape = Escape::Started;
+                return;
+            }
+            (Escape::None, _) => match byte {
+                // the second half of a serial terminal's \r\n
+                b'\n' if after_cr => return,
+                b'\r' | b'\n' => Key::Enter,
+                0x7f | 0x08 => Key::Backspace,
+                b'\t' => Key::Tab,
+                0x01 => Key::Home,
+                0x02 => Key::Left,
+                0x03 => Key::Cancel,
+                0x04 => Key::Delete,
+                0x05 => Key::End,
+                0x06 => Key::Right,
+                0x0c => Key::ClearScreen,
+                0x0e => Key::Down,
+                0x10 => Key::Up,
+                0x15 => Key::KillToStart,
+                0x20..=0x7e => Key::Char(byte),
+                _ => return,
+            },
+            (Escape::Started, b'[') => {
+                self.escape = Escape::Csi(0);
+                return;
+            }
+            (Escape::Started, b'O') => {
+                self.escape = Escape::Ss3;
+                return;
+            }
+            (Escape::Started, _) => return,
+            (Escape::Csi(param), b'0'..=b'9') => {
+                let param = param
+                    .saturating_mul(10)
+                    .saturating_add((byte - b'0') as u16);
+                self.escape = Escape::Csi(param);
+                return;
+            }
+            // modifiers come after a ';', which don't change what the key does
+            (Escape::Csi(_), b';') => {
+                self.escape = Escape::Csi(0);
+                return;
+            }
+            (Escape::Csi(param), b'~') => match param {
+                1 | 7 => Key::Home,
+                3 => Key::Delete,
+                4 | 8 => Key::End,
+                _ => return,
+            },
+            (Escape::Csi(_) | Escape::Ss3, _) => match byte {
+                b'A' => Key::Up,
+                b'B' => Key::Down,
+                b'C' => Key::Right,
+                b'D' => Key::Left,
+                b'H' => Key::Home,
+                b'F' => Key::End,
+                _ => return,
+            },
+        };
+        self.key(key);
+    }
+
+    fn key(&mut self, key: Key) {
+        match key {
+            Key::Char(byte) => {
+                self.line.insert(self.cursor, byte);
+                self.cursor += 1;
+            }
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+            }
+            Key::Delete if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+            }
+            Key::Left => self.cursor = self.cursor.saturating_sub(1),
+            Key::Right => self.cursor = (self.cursor + 1).min(self.line.len()),
+            Key::Home => self.cursor = 0,
+            Key::End => self.cursor = self.line.len(),
+            Key::Up => self.recall(true),
+            Key::Down => self.recall(false),
+            Key::KillToStart => {
+                self.line.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            Key::Tab => self.complete(),
+            Key::ClearScreen => {
+                let _ = self.out.write_str("\x1b[2J\x1b[H");
+            }
+            Key::Cancel => {
+                let _ = self.out.write_str("^C\n");
+                self.reset();
+            }
+            Key::Enter => {
+                let _ = self.out.write_str("\n");
+                let line = core::mem::take(&mut self.line);
+                self.reset();
+                if line.iter().any(|byte| !byte.is_ascii_whitespace())
+                    && self.history.last() != Some(&line)
+                {
+                    if self.history.len() == HISTORY_SIZE {
+                        self.history.remove(0);
+                    }
+                    self.history.push(line.clone());
+                }
+                // only printable ascii gets into the line
+                execute(core::str::from_utf8(&line).unwrap_or(""), &mut self.out);
+            }
+            Key::Backspace | Key::Delete => {}
+        }
+        self.redraw();
+    }
+
+    fn reset(&mut self) {
+        self.line.clear();
+        self.cursor = 0;
+        self.browsing = None;
+        self.draft.clear();
+    }
+
+    /// Replaces the line with an older history entry, or a newer one and
+    /// finally what was being typed.
+    fn recall(&mut self, older: bool) {
+        let browsing = match (self.browsing, older) {
+            (None, true) if !self.history.is_empty() => {
+                self.draft = core::mem::take(&mut self.line);
+                Some(self.history.len() - 1)
+            }
+            (Some(index), true) => Some(index.saturating_sub(1)),
+            (Some(index), false) if index + 1 < self.history.len() => Some(index + 1),
+            (Some(_), false) => None,
+            (None, _) => return,
+        };
+        self.browsing = browsing;
+        self.line = match browsing {
+            Some(index) => self.history[index].clone(),
+            None => core::mem::take(&mut self.draft),
+        };
+        self.cursor = self.line.len();
+    }
+
+    /// Completes the command name before the cursor as far as it is
+    /// unambiguous, listing the candidates if that is no further.
+    fn complete(&mut self) {
+        let prefix = &self.line[..self.cursor];
+        if prefix.contains(&b' ') {
+            return;
+        }
+        let names: Vec<&'static str> = commands()
+            .iter()
+            .map(|command| command.name())
+            .filter(|name| name.as_bytes().starts_with(prefix))
+            .collect();
+        let Some(first) = names.first() else {
+            return;
+        };
+        let common = names.iter().fold(first.len(), |len, name| {
+            first
+                .bytes()
+                .zip(name.bytes())
+                .take(len)
+                .take_while(|(a, b)| a == b)
+                .count()
+        });
+        let mut completion = first.as_bytes()[self.cursor..common].to_vec();
+        if names.len() == 1 {
+            completion.push(b' ');
+        } else if completion.is_empty() {
+            let _ = writeln!(self.out, "\n{}", names.join("  "));
+        }
+        let len = completion.len();
+        self.line.splice(self.cursor..self.cursor, completion);
+        self.cursor += len;
+    }
+
+    /// Redraws the prompt and line and puts the cursor back.
+    fn redraw(&mut self) {
+        let line = core::str::from_utf8(&self.line).unwrap_or("");
+        let _ = write!(self.out, "\r{}{}\x1b[K", PROMPT, line);
+        let back = self.line.len() - self.cursor;
+        if back > 0 {
+            let _ = write!(self.out, "\x1b[{}D", back);
+        }
+    }
+}
+
+struct HelpCommand;
+
+impl Command for HelpCommand {
+    fn name(&self) -> &'static str {
+        "help"
+    }
+
+    fn help(&self) -> &'static str {
+        "list commands"
+    }
+
+    fn run(&self, _args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+        for command in commands() {
+            let usage = format!("{} {}", command.name(), command.usage());
+            writeln!(out, "  {:<32}{}", usage, command.help())?;
+        }
+        Ok(())
+    }
+}
+
+struct ClearCommand;
+
+impl Command for ClearCommand {
+    fn name(&self) -> &'static str {
+        "clear"
+    }
+
+    fn help(&self) -> &'static str {
+        "clear the screen"
+    }
+
+    fn run(&self, _args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+        Ok(out.write_str("\x1b[2J\x1b[H")?)
+    }
+}
+
+fn parse_address(arg: &str) -> Result<VirtAddr, CommandError> {
+    let addr = parse_number(arg)?;
+    VirtAddr::try_new(addr)
+        .map_err(|_| CommandError::Failed(format!("{:#x} is not canonical", addr)))
+}
+
+/// Fails unless every page of `len` bytes at `addr` is mapped with `flags`.
+fn check_mapped(addr: VirtAddr, len: u64, flags: PageTableFlags) -> Result<(), CommandError> {
+    if len == 0 {
+        return Ok(());
+    }
+    // walked as plain numbers, as the range may run off the end of the
+    // address space or into the non-canonical hole
+    let last = addr
+        .as_u64()
+        .checked_add(len - 1)
+        .filter(|&last| VirtAddr::try_new(last).is_ok())
+        .ok_or_else(|| {
+            CommandError::Failed(format!(
+                "{:#x}+{:#x} is not a valid range",
+                addr.as_u64(),
+                len
+            ))
+        })?;
+    for page in (addr.as_u64() & !0xfff..=last).step_by(4096) {
+        let mapped = VirtAddr::try_new(page)
+            .ok()
+            .and_then(memory::page_flags)
+            .is_some_and(|mapped| mapped.contains(flags));
+        if !mapped {
+            return Err(CommandError::Failed(format!(
+                "{:#x} is not mapped {:?}",
+                page, flags
+            )));
+        }
+    }
+    Ok(())
+}
+
+struct PeekCommand;
+
+impl Command for PeekCommand {
+    fn name(&self) -> &'static str {
+        "peek"
+    }
+
+    fn usage(&self) -> &'static str {
+        "<addr> [bytes]"
+    }
+
+    fn help(&self) -> &'static str {
+        "dump kernel memory"
+    }
+
+    fn run(&self, args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+        let (addr, len) = match *args {
+            [addr] => (parse_address(addr)?, PEEK_DEFAULT),
+            [addr, len] => (parse_address(addr)?, parse_number(len)?.min(PEEK_MAX)),
+            _ => return Err(CommandError::Usage),
+        };
+        check_mapped(addr, len, PageTableFlags::PRESENT)?;
+        let bytes = unsafe { core::slice::from_raw_parts(addr.as_ptr::<u8>(), len as usize) };
+        for (i, row) in bytes.chunks(16).enumerate() {
+            write!(out, "{:016x}:", addr.as_u64() + i as u64 * 16)?;
+            for byte in row {
+                write!(out, " {:02x}", byte)?;
+            }
+            write!(out, "{:width$}  ", "", width = (16 - row.len()) * 3)?;
+            for &byte in row {
+                let c = if byte.is_ascii_graphic() {
+                    byte as char
+                } else {
+                    '.'
+                };
+                out.write_char(c)?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+}
+
+struct PokeCommand;
+
+impl Command for PokeCommand {
+    fn name(&self) -> &'static str {
+        "poke"
+    }
+
+    fn usage(&self) -> &'static str {
+        "<addr> <value> [1|2|4|8]"
+    }
+
+    fn help(&self) -> &'static str {
+        "write kernel memory"
+    }
+
+    fn run(&self, args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+        let (addr, value, size) = match *args {
+            [addr, value] => (parse_address(addr)?, parse_number(value)?, 8),
+            [addr, value, size] => (
+                parse_address(addr)?,
+                parse_number(value)?,
+                parse_number(size)?,
+            ),
+            _ => return Err(CommandError::Usage),
+        };
+        if !matches!(size, 1 | 2 | 4 | 8) {
+            return Err(CommandError::Usage);
+        }
+        if !addr.is_aligned(size) {
+            return Err(CommandError::Failed(format!(
+                "{:#x} is not {} byte aligned",
+                addr.as_u64(),
+                size
+            )));
+        }
+        check_mapped(
+            addr,
+            size,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        )?;
+        unsafe {
+            match size {
+                1 => addr.as_mut_ptr::<u8>().write_volatile(value as u8),
+                2 => addr.as_mut_ptr::<u16>().write_volatile(value as u16),
+                4 => addr.as_mut_ptr::<u32>().write_volatile(value as u32),
+                _ => addr.as_mut_ptr::<u64>().write_volatile(value),
+            }
+        }
+        writeln!(out, "wrote {:#x} to {:#x}", value, addr.as_u64())?;
+        Ok(())
+    }
+}
+
+/// Feature bits of leaf 1 ecx and edx worth knowing about.
+const CPUID_FEATURES: [(&str, bool, u32); 16] = [
+    ("fpu", false, 0),
+    ("tsc", false, 4),
+    ("msr", false, 5),
+    ("apic", false, 9),
+    ("pat", false, 16),
+    ("sse", false, 25),
+    ("sse2", false, 26),
+    ("sse3", true, 0),
+    ("ssse3", true, 9),
+    ("sse4.1", true, 19),
+    ("sse4.2", true, 20),
+    ("x2apic", true, 21),
+    ("xsave", true, 26),
+    ("avx", true, 28),
+    ("rdrand", true, 30),
+    ("hypervisor", true, 31),
+];
+
+struct CpuidCommand;
+
+impl Command for CpuidCommand {
+    fn name(&self) -> &'static str {
+        "cpuid"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[leaf [subleaf]]"
+    }
+
+    fn help(&self) -> &'static str {
+        "describe the cpu, or show a raw cpuid leaf"
+    }
+
+    fn run(&self, args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+        let (leaf, subleaf) = match *args {
+            [] => return describe_cpu(out),
+            [leaf] => (parse_number(leaf)?, 0),
+            [leaf, subleaf] => (parse_number(leaf)?, parse_number(subleaf)?),
+            _ => return Err(CommandError::Usage),
+        };
+        let CpuidResult { eax, ebx, ecx, edx } = __cpuid_count(leaf as u32, subleaf as u32);
+        writeln!(
+            out,
+            "eax={:#010x} ebx={:#010x} ecx={:#010x} edx={:#010x}",
+            eax, ebx, ecx, edx
+        )?;
+        Ok(())
+    }
+}
+
+fn describe_cpu(out: &mut dyn Write) -> Result<(), CommandError> {
+    let leaf0 = __cpuid_count(0, 0);
+    let mut vendor = [0u8; 12];
+    vendor[..4].copy_from_slice(&leaf0.ebx.to_le_bytes());
+    vendor[4..8].copy_from_slice(&leaf0.edx.to_le_bytes());
+    vendor[8..].copy_from_slice(&leaf0.ecx.to_le_bytes());
+    writeln!(out, "vendor:   {}", String::from_utf8_lossy(&vendor))?;
+    if __cpuid_count(0x8000_0000, 0).eax >= 0x8000_0004 {
+        let mut brand = Vec::new();
+        for leaf in 0x8000_0002..=0x8000_0004 {
+            let CpuidResult { eax, ebx, ecx, edx } = __cpuid_count(leaf, 0);
+            for register in [eax, ebx, ecx, edx] {
+                brand.extend_from_slice(&register.to_le_bytes());
+            }
+        }
+        let brand = String::from_utf8_lossy(&brand);
+        writeln!(
+            out,
+            "brand:    {}",
+            brand.trim_matches(|c| c == '\0' || c == ' ')
+        )?;
+    }
+    let leaf1 = __cpuid_count(1, 0);
+    let mut family = leaf1.eax >> 8 & 0xf;
+    let mut model = leaf1.eax >> 4 & 0xf;
+    if family == 0xf {
+        family += leaf1.eax >> 20 & 0xff;
+    }
+    if family >= 0x6 {
+        model |= (leaf1.eax >> 16 & 0xf) << 4;
+    }
+    writeln!(
+        out,
+        "family {:#x}, model {:#x}, stepping {}",
+        family,
+        model,
+        leaf1.eax & 0xf
+    )?;
+    write!(out, "features:")?;
+    for (name, in_ecx, bit) in CPUID_FEATURES {
+        let register = if in_ecx { leaf1.ecx } else { leaf1.edx };
+        if register & 1 << bit != 0 {
+            write!(out, " {}", name)?;
+        }
+    }
+    writeln!(out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(input: &str) -> Session<String> {
+        let mut session = Session::new(String::new());
+        for byte in input.bytes() {
+            session.input(byte);
+        }
+        session
+    }
+
+    fn line(session: &Session<String>) -> &str {
+        core::str::from_utf8(&session.line).unwrap()
+    }
+
+    #[test_case]
+    fn escapes_move_the_cursor() {
+        assert_eq!(line(&session("abc\x1b[D\x1b[DX")), "aXbc");
+        assert_eq!(line(&session("abc\x1bOHX")), "Xabc");
+        assert_eq!(line(&session("abc\x1b[1~\x1b[3~")), "bc");
+        // ctrl+left, with a modifier parameter
+        assert_eq!(line(&session("abc\x1b[1;5D!")), "ab!c");
+        // unknown sequences are dropped whole
+        assert_eq!(line(&session("abc\x1b[5~\x1bxd")), "abcd");
+    }
+
+    #[test_case]
+    fn control_keys_edit() {
+        let killed = session("abc\x02\x15");
+        assert_eq!((line(&killed), killed.cursor), ("c", 0));
+        let cancelled = session("abc\x03");
+        assert_eq!(line(&cancelled), "");
+        assert!(cancelled.out.contains("^C"));
+        assert_eq!(line(&session("ab\x7f\x01\x04x")), "x");
+    }
+
+    #[test_case]
+    fn history_is_recalled() {
+        let mut shell = session("one\rtwo\r\x1b[A");
+        assert_eq!(line(&shell), "two");
+        for byte in "\x1b[A\x1b[A".bytes() {
+            shell.input(byte);
+        }
+        assert_eq!(line(&shell), "one");
+        for byte in "\x1b[B".bytes() {
+            shell.input(byte);
+        }
+        assert_eq!(line(&shell), "two");
+        // going past the newest entry brings back what was being typed
+        assert_eq!(line(&session("one\rdraft\x1b[A\x1b[B")), "draft");
+        // repeats and blank lines aren't kept, and \r\n is one enter
+        assert_eq!(session("one\r\none\r  \r").history.len(), 1);
+    }
+
+    #[test_case]
+    fn command_names_complete() {
+        let unique = session("he\t");
+        assert_eq!((line(&unique), unique.cursor), ("help ", 5));
+        let ambiguous = session("p\t");
+        assert_eq!(line(&ambiguous), "p");
+        assert!(ambiguous.out.contains("peek") && ambiguous.out.contains("poke"));
+        assert_eq!(line(&session("pe\t")), "peek ");
+        // only the command name completes
+        assert_eq!(line(&session("help he\t")), "help he");
+    }
+}
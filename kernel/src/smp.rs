@@ -0,0 +1,462 @@
+use crate::apic::Destination;
+use crate::interrupt::{self, ticks};
+use crate::memory::{self, phys_to_virt};
+use crate::{acpi, apic, debugreg, gdt, percpu};
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::mem::offset_of;
+use core::sync::atomic::{fence, AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use log::{info, warn};
+use spin::Mutex;
+use x86_64::instructions::{self, interrupts};
+use x86_64::registers::control::{Cr0, Cr3, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+pub const MAX_CPUS: usize = 16;
+/// Tells a CPU that other CPUs queued calls for it.
+pub const CALL_VECTOR: u8 = 0xf0;
+const STACK_SIZE: u64 = 64 * 1024;
+const GUARD_SIZE: u64 = 4096;
+/// Each CPU's first stack, above an unmapped guard page.
+const STACKS_START: u64 = 0x_5556_0000_0000;
+/// How long a CPU gets to check in, in timer ticks.
+const STARTUP_TIMEOUT: u64 = 100;
+/// How long `stop_others` waits for the other CPUs, in TSC cycles. Timer
+/// ticks don't count with interrupts off.
+const STOP_TIMEOUT: u64 = 1_000_000_000;
+const NO_CPU: u32 = u32::MAX;
+const NOT_STARTING: usize = usize::MAX;
+
+/// Local APIC ids by CPU number, the bootstrap processor being 0, stored by
+/// each CPU itself when it checks in.
+static APIC_IDS: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(NO_CPU) }; MAX_CPUS];
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+static STOPPING: AtomicBool = AtomicBool::new(false);
+/// CPUs that took the NMI of `stop_others` and halted.
+static STOPPED: AtomicUsize = AtomicUsize::new(0);
+static PAUSING: AtomicBool = AtomicBool::new(false);
+/// CPUs held in their NMI handler by `pause_others`.
+static PAUSED: AtomicUsize = AtomicUsize::new(0);
+/// The CPU `start_cpu` waits for, until it claims its number in `ap_main` or
+/// `start_cpu` gives up on it, whichever comes first.
+static STARTING: AtomicUsize = AtomicUsize::new(NOT_STARTING);
+
+/// A function another CPU is waiting for this one to run.
+struct Call {
+    run: unsafe fn(*const ()),
+    f: *const (),
+    /// The CPUs the caller is still waiting for.
+    pending: *const AtomicUsize,
+}
+
+// the caller keeps both alive until `pending` drops to zero
+unsafe impl Send for Call {}
+
+crate::cpu_local! {
+    /// Pushed to by other CPUs and drained by `run_calls`, which the
+    /// `CALL_VECTOR` handler runs on this one, so interrupts are off around
+    /// every use.
+    static CALLS: Mutex<Vec<Call>> = Mutex::new(Vec::new());
+}
+
+/// What the trampoline needs, filled in by `start_cpus` behind its code.
+#[repr(C)]
+struct Params {
+    gdt: [u64; 2],
+    _pad: [u16; 3],
+    gdt_limit: u16,
+    gdt_base: u64,
+    far_jump_offset: u32,
+    far_jump_selector: u16,
+    _pad2: u16,
+    cr0: u64,
+    cr3: u64,
+    cr4: u64,
+    efer: u64,
+    stack: u64,
+    entry: u64,
+    cpu: u64,
+}
+
+// Copied to a page below 1 MiB, where a starting CPU runs it in real mode
+// with cs:ip at page:0. Goes straight to long mode on the kernel's page
+// tables, which map the page to itself, and calls `ap_main`.
+global_asm!(
+    ".global smp_trampoline",
+    ".global smp_trampoline_long_mode",
+    ".global smp_trampoline_params",
+    ".global smp_trampoline_end",
+    ".balign 16",
+    ".code16",
+    "smp_trampoline:",
+    "cli",
+    "cld",
+    "mov %cs, %ax",
+    "mov %ax, %ds",
+    "movl smp_trampoline_params - smp_trampoline + {cr4}, %eax",
+    "mov %eax, %cr4",
+    "movl smp_trampoline_params - smp_trampoline + {cr3}, %eax",
+    "mov %eax, %cr3",
+    "movl $0xc0000080, %ecx",
+    "movl smp_trampoline_params - smp_trampoline + {efer}, %eax",
+    "xorl %edx, %edx",
+    "wrmsr",
+    "lgdtl smp_trampoline_params - smp_trampoline + {gdt_limit}",
+    // turns on protected mode and paging at once, which enters long mode
+    "movl smp_trampoline_params - smp_trampoline + {cr0}, %eax",
+    "mov %eax, %cr0",
+    "ljmpl *smp_trampoline_params - smp_trampoline + {far_jump}",
+    ".code64",
+    "smp_trampoline_long_mode:",
+    "xorl %eax, %eax",
+    "mov %ax, %ds",
+    "mov %ax, %es",
+    "mov %ax, %ss",
+    "movq smp_trampoline_params + {stack}(%rip), %rsp",
+    "movq smp_trampoline_params + {cpu}(%rip), %rdi",
+    "xorl %ebp, %ebp",
+    "callq *smp_trampoline_params + {entry}(%rip)",
+    "ud2",
+    ".balign 8",
+    "smp_trampoline_params:",
+    ".skip {params_size}",
+    "smp_trampoline_end:",
+    cr0 = const offset_of!(Params, cr0),
+    cr3 = const offset_of!(Params, cr3),
+    cr4 = const offset_of!(Params, cr4),
+    efer = const offset_of!(Params, efer),
+    gdt_limit = const offset_of!(Params, gdt_limit),
+    far_jump = const offset_of!(Params, far_jump_offset),
+    stack = const offset_of!(Params, stack),
+    entry = const offset_of!(Params, entry),
+    cpu = const offset_of!(Params, cpu),
+    params_size = const size_of::<Params>(),
+    options(att_syntax),
+);
+
+extern "C" {
+    fn smp_trampoline();
+    fn smp_trampoline_long_mode();
+    fn smp_trampoline_params();
+    fn smp_trampoline_end();
+}
+
+/// CPUs that have checked in.
+pub fn cpu_count() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// The local APIC id of CPU `cpu`, if it is online.
+pub fn apic_id(cpu: usize) -> Option<u8> {
+    let id = APIC_IDS.get(cpu)?.load(Ordering::Acquire);
+    (id != NO_CPU).then_some(id as u8)
+}
+
+/// Runs `f` on CPU `cpu`, if it is online, and waits for it to finish.
+pub fn call_on(cpu: usize, f: impl Fn() + Sync) {
+    interrupts::without_interrupts(|| {
+        if cpu == percpu::id() {
+            f();
+        } else {
+            call_many(core::iter::once(cpu), &f);
+        }
+    });
+}
+
+/// Runs `f` on every other online CPU and waits for all of them to finish.
+pub fn call_on_others(f: impl Fn() + Sync) {
+    interrupts::without_interrupts(|| {
+        let current = percpu::id();
+        call_many((0..cpu_count()).filter(|&cpu| cpu != current), &f);
+    });
+}
+
+/// Runs `f` on every online CPU, the calling one included.
+pub fn call_on_all(f: impl Fn() + Sync) {
+    call_on_others(&f);
+    interrupts::without_interrupts(&f);
+}
+
+/// Queues `f` for `cpus`, which don't include the calling one, and waits.
+/// Interrupts must be off so the caller stays on its CPU.
+fn call_many<F: Fn() + Sync>(cpus: impl Iterator<Item = usize>, f: &F) {
+    unsafe fn run<F: Fn()>(f: *const ()) {
+        unsafe { (*(f as *const F))() }
+    }
+    let pending = AtomicUsize::new(0);
+    for cpu in cpus {
+        let Some(apic_id) = apic_id(cpu) else {
+            continue;
+        };
+        pending.fetch_add(1, Ordering::Relaxed);
+        CALLS.for_cpu(cpu).lock().push(Call {
+            run: run::<F>,
+            f: f as *const F as *const (),
+            pending: &pending,
+        });
+        apic::send_fixed(Destination::Apic(apic_id), CALL_VECTOR);
+    }
+    while pending.load(Ordering::Acquire) != 0 {
+        // whoever we wait for may be waiting for us too
+        run_calls();
+        core::hint::spin_loop();
+    }
+}
+
+/// Runs the calls other CPUs queued for this one. Called by the
+/// `CALL_VECTOR` handler.
+pub fn run_calls() {
+    while let Some(call) = CALLS.with(|calls| calls.lock().pop()) {
+        unsafe {
+            (call.run)(call.f);
+            (*call.pending).fetch_sub(1, Ordering::Release);
+        }
+    }
+}
+
+/// Halts the other CPUs with an NMI, which gets through even with their
+/// interrupts off, so a panicking CPU can have the machine to itself. Waits
+/// until they have stopped, but not forever, as one that is already in an
+/// NMI handler won't take another.
+pub fn stop_others() {
+    let others = cpu_count() - 1;
+    if others == 0 {
+        return;
+    }
+    STOPPING.store(true, Ordering::SeqCst);
+    apic::send_nmi(Destination::Others);
+    wait_until(|| STOPPED.load(Ordering::SeqCst) >= others);
+}
+
+/// Holds the other CPUs in their NMI handler until `resume_others`, for
+/// the debugger, which looks at memory they could be changing. Waits for
+/// them the way `stop_others` does.
+pub fn pause_others() {
+    let others = cpu_count() - 1;
+    if others == 0 {
+        return;
+    }
+    PAUSING.store(true, Ordering::SeqCst);
+    apic::send_nmi(Destination::Others);
+    wait_until(|| PAUSED.load(Ordering::SeqCst) >= others);
+}
+
+/// Lets the CPUs held by `pause_others` go, and waits until they have left
+/// the NMI handler, so the next pause doesn't count them twice.
+pub fn resume_others() {
+    if PAUSING.swap(false, Ordering::SeqCst) {
+        wait_until(|| PAUSED.load(Ordering::SeqCst) == 0);
+    }
+}
+
+/// Holds the calling CPU while `pause_others` says so, returning whether it
+/// did. Called by the NMI handler.
+pub fn hold_if_paused() -> bool {
+    if !PAUSING.load(Ordering::SeqCst) {
+        return false;
+    }
+    PAUSED.fetch_add(1, Ordering::SeqCst);
+    while PAUSING.load(Ordering::SeqCst) {
+        core::hint::spin_loop();
+    }
+    PAUSED.fetch_sub(1, Ordering::SeqCst);
+    true
+}
+
+/// Spins until `done`, for up to `STOP_TIMEOUT`.
+fn wait_until(done: impl Fn() -> bool) {
+    let start = rdtsc();
+    while !done() && rdtsc() - start < STOP_TIMEOUT {
+        core::hint::spin_loop();
+    }
+}
+
+/// Whether `stop_others` was called, which is what NMIs mean then.
+pub fn is_stopping() -> bool {
+    STOPPING.load(Ordering::SeqCst)
+}
+
+/// Tells `stop_others` the calling CPU is about to halt for good. Called by
+/// the NMI handler.
+pub fn stopped() {
+    STOPPED.fetch_add(1, Ordering::SeqCst);
+}
+
+fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Starts the other CPUs the MADT lists, one at a time, and waits for each
+/// to check in. They halt after setting themselves up.
+pub fn init() {
+    let Some(madt) = acpi::madt() else {
+        warn!("no MADT, running on one CPU");
+        return;
+    };
+    apic::init(madt.local_apic_addr);
+    let bsp = apic::id();
+    APIC_IDS[0].store(bsp as u32, Ordering::Release);
+    let aps: Vec<u8> = madt.apic_ids.into_iter().filter(|&id| id != bsp).collect();
+    if aps.is_empty() {
+        return;
+    }
+    if aps.len() >= MAX_CPUS {
+        warn!("only starting {} of {} CPUs", MAX_CPUS, aps.len() + 1);
+    }
+    // the trampoline loads CR3 in 32-bit mode
+    let (page_table, _) = Cr3::read();
+    if page_table.start_address().as_u64() >= 1 << 32 {
+        warn!("page tables above 4 GiB, out of reach of the AP trampoline, running on one CPU");
+        return;
+    }
+    let Some(frame) = memory::low_frame() else {
+        warn!("no memory below 1 MiB for the AP trampoline");
+        return;
+    };
+    let base = frame.start_address();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    if memory::map_physical(VirtAddr::new(base.as_u64()), base, 4096, flags).is_err() {
+        warn!("can't map the AP trampoline at {:#x}", base.as_u64());
+        return;
+    }
+    let params = install_trampoline(base.as_u64());
+    // the stack of the next CPU number, kept when a CPU fails to start
+    let mut stack = None;
+    for &apic_id in &aps {
+        let cpu = cpu_count();
+        if cpu == MAX_CPUS {
+            break;
+        }
+        unsafe {
+            (*params).stack = *stack.get_or_insert_with(|| map_stack(cpu));
+            (*params).cpu = cpu as u64;
+        }
+        if start_cpu(cpu, apic_id, (base.as_u64() >> 12) as u8) {
+            stack = None;
+        } else {
+            warn!("cpu with APIC id {} didn't start", apic_id);
+        }
+    }
+    memory::unmap_range(VirtAddr::new(base.as_u64()), 4096);
+    info!("{} CPUs online", cpu_count());
+}
+
+/// Copies the trampoline to `base` and fills in everything but the stack
+/// and CPU number.
+fn install_trampoline(base: u64) -> *mut Params {
+    let start = smp_trampoline as *const () as u64;
+    let len = smp_trampoline_end as *const () as u64 - start;
+    assert!(len <= 4096, "AP trampoline too big");
+    let (cr3, _) = Cr3::read();
+    let cr3 = cr3.start_address().as_u64();
+    let code = phys_to_virt(x86_64::PhysAddr::new(base)).as_mut_ptr::<u8>();
+    unsafe { core::ptr::copy_nonoverlapping(start as *const u8, code, len as usize) };
+    let params_offset = smp_trampoline_params as *const () as u64 - start;
+    let params = unsafe { code.add(params_offset as usize) } as *mut Params;
+    let long_mode = smp_trampoline_long_mode as *const () as u64 - start;
+    unsafe {
+        params.write(Params {
+            // null and 64-bit code
+            gdt: [0, 0x00af_9a00_0000_ffff],
+            _pad: [0; 3],
+            gdt_limit: 15,
+            gdt_base: base + params_offset + offset_of!(Params, gdt) as u64,
+            far_jump_offset: (base + long_mode) as u32,
+            far_jump_selector: 8,
+            _pad2: 0,
+            cr0: Cr0::read_raw(),
+            cr3,
+            // PCID can only be turned on in long mode
+            cr4: (Cr4::read() - Cr4Flags::PCID).bits(),
+            efer: (Efer::read() - EferFlags::LONG_MODE_ACTIVE).bits(),
+            stack: 0,
+            entry: ap_main as *const () as u64,
+            cpu: 0,
+        })
+    };
+    params
+}
+
+fn map_stack(cpu: usize) -> u64 {
+    let base = STACKS_START + cpu as u64 * (GUARD_SIZE + STACK_SIZE);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    memory::map_range(VirtAddr::new(base + GUARD_SIZE), STACK_SIZE, flags)
+        .expect("failed to map AP stack");
+    base + GUARD_SIZE + STACK_SIZE
+}
+
+/// Sends INIT and up to two STARTUPs, as Intel's MP spec says, and returns
+/// whether the CPU checked in. One that doesn't in time, before or after
+/// getting into the kernel, is sent back to waiting with another INIT, so it
+/// can't come up later on the stack that goes to the next CPU.
+fn start_cpu(cpu: usize, apic_id: u8, page: u8) -> bool {
+    let claimed = || STARTING.load(Ordering::Acquire) != cpu;
+    STARTING.store(cpu, Ordering::Release);
+    fence(Ordering::SeqCst);
+    apic::send_init(apic_id);
+    wait_ticks(2);
+    apic::send_startup(apic_id, page);
+    wait_ticks(1);
+    if !claimed() {
+        apic::send_startup(apic_id, page);
+    }
+    let deadline = ticks() + STARTUP_TIMEOUT;
+    while !claimed() && ticks() < deadline {
+        core::hint::spin_loop();
+    }
+    let given_up = STARTING
+        .compare_exchange(cpu, NOT_STARTING, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok();
+    if given_up {
+        apic::send_init(apic_id);
+        return false;
+    }
+    // it is running kernel code by now, but may still hang setting up
+    let deadline = ticks() + STARTUP_TIMEOUT;
+    while cpu_count() <= cpu && ticks() < deadline {
+        core::hint::spin_loop();
+    }
+    if cpu_count() > cpu {
+        return true;
+    }
+    apic::send_init(apic_id);
+    // it may have checked in just before INIT took it down again, and only
+    // this CPU starts others, so nothing else changes the count meanwhile
+    let _ = ONLINE.compare_exchange(cpu + 1, cpu, Ordering::AcqRel, Ordering::Acquire);
+    false
+}
+
+/// Waits for `n` full timer ticks.
+fn wait_ticks(n: u64) {
+    let end = ticks() + n + 1;
+    while ticks() < end {
+        instructions::hlt();
+    }
+}
+
+/// Where the other CPUs come in from the trampoline, on their own stack.
+extern "C" fn ap_main(cpu: usize) -> ! {
+    let late = STARTING
+        .compare_exchange(cpu, NOT_STARTING, Ordering::AcqRel, Ordering::Acquire)
+        .is_err();
+    if late {
+        // `start_cpu` gave up on us and is about to send INIT
+        loop {
+            instructions::hlt();
+        }
+    }
+    percpu::init(cpu);
+    gdt::init_gdt();
+    interrupt::load_idt();
+    // both are per CPU
+    memory::init_pat();
+    debugreg::load();
+    apic::enable();
+    APIC_IDS[cpu].store(apic::id() as u32, Ordering::Release);
+    ONLINE.fetch_add(1, Ordering::AcqRel);
+    loop {
+        interrupts::enable_and_hlt();
+    }
+}
@@ -0,0 +1,125 @@
+use crate::framebuffer::{Pixel, BLACK, FRAMEBUFFER, GREEN};
+use crate::graphics::Canvas;
+use crate::{cmdline, console, image};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+const BAR_DIM: (usize, usize) = (320, 12);
+const LOGO_RADIUS: usize = 64;
+const DARK_GREEN: Pixel = Pixel {
+    b: 0x00,
+    g: 0x40,
+    r: 0x00,
+};
+
+static SPLASH: Mutex<Option<Splash>> = Mutex::new(None);
+
+/// Steps of `kernel::init` the progress bar advances through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Memory,
+    Interrupts,
+    Drivers,
+}
+
+const STAGES: usize = 3;
+
+struct Splash {
+    bar: (isize, isize),
+}
+
+/// Takes over the screen with a logo and progress bar when booted with the
+/// `splash` option. Console output is held back until `finish`.
+pub fn start() {
+    if !cmdline::flag("splash") {
+        return;
+    }
+    console::suspend();
+    // SPLASH is never taken with FRAMEBUFFER held, so set it once the guard
+    // is gone
+    let bar = interrupts::without_interrupts(|| {
+        let mut framebuffer = FRAMEBUFFER.lock();
+        framebuffer.fill(BLACK);
+        let (width, height) = framebuffer.dim();
+        let center = (width as isize / 2, height as isize / 2);
+        match image::load("splash.bmp") {
+            Ok(logo) => {
+                let (logo_width, logo_height) = logo.dim();
+                framebuffer.blit(
+                    &logo,
+                    (
+                        center.0 - logo_width as isize / 2,
+                        center.1 - logo_height as isize,
+                    ),
+                );
+            }
+            Err(_) => draw_logo(
+                &mut *framebuffer,
+                (center.0, center.1 - LOGO_RADIUS as isize),
+            ),
+        }
+        let bar = (
+            center.0 - BAR_DIM.0 as isize / 2,
+            center.1 + LOGO_RADIUS as isize / 2,
+        );
+        framebuffer.draw_rect(
+            (bar.0 - 2, bar.1 - 2),
+            (BAR_DIM.0 + 4, BAR_DIM.1 + 4),
+            GREEN,
+        );
+        framebuffer.fill_rect(bar, BAR_DIM, DARK_GREEN);
+        framebuffer.flush();
+        bar
+    });
+    interrupts::without_interrupts(|| *SPLASH.lock() = Some(Splash { bar }));
+}
+
+/// Marks `stage` as done on the progress bar.
+pub fn advance(stage: Stage) {
+    interrupts::without_interrupts(|| {
+        // copied out, so SPLASH isn't held while waiting for FRAMEBUFFER
+        let Some(bar) = SPLASH.lock().as_ref().map(|splash| splash.bar) else {
+            return;
+        };
+        let done = BAR_DIM.0 * (stage as usize + 1) / STAGES;
+        let mut framebuffer = FRAMEBUFFER.lock();
+        framebuffer.fill_rect(bar, (done, BAR_DIM.1), GREEN);
+        framebuffer.flush();
+    });
+}
+
+pub fn is_active() -> bool {
+    interrupts::without_interrupts(|| SPLASH.lock().is_some())
+}
+
+/// Drops the splash and brings back the text console. Called once init is
+/// done, on the first error, and when a key is pressed.
+pub fn finish() {
+    let shown = interrupts::without_interrupts(|| SPLASH.lock().take().is_some());
+    if shown {
+        console::resume();
+    }
+}
+
+/// Lets the panic handler take down the splash even if it panicked while
+/// drawing it.
+///
+/// # Safety
+/// Nothing else may be using the splash.
+pub unsafe fn force_unlock() {
+    SPLASH.force_unlock();
+}
+
+fn draw_logo(canvas: &mut impl Canvas, (x, y): (isize, isize)) {
+    let radius = LOGO_RADIUS as isize;
+    canvas.fill_circle((x, y), LOGO_RADIUS, GREEN);
+    canvas.fill_circle((x, y), LOGO_RADIUS * 3 / 4, BLACK);
+    canvas.fill_polygon(
+        &[
+            (x - radius / 3, y - radius / 2),
+            (x + radius / 2, y),
+            (x - radius / 3, y + radius / 2),
+        ],
+        GREEN,
+    );
+}
@@ -0,0 +1,675 @@
+use crate::interrupt::{ticks, TIMER_HZ};
+use crate::memory;
+use crate::sched::{self, Entity, Scheduler, NUM_PRIORITIES};
+use crate::shell::{self, Command, CommandError};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::cell::Cell;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+const STACK_SIZE: u64 = 64 * 1024;
+const GUARD_SIZE: u64 = 4096;
+/// Each stack sits above an unmapped guard page, so overflowing it faults
+/// instead of overwriting whatever is below.
+const STACK_SLOT: u64 = GUARD_SIZE + STACK_SIZE;
+const STACKS_START: u64 = 0x_5555_0000_0000;
+pub const MAX_THREADS: u64 = 4096;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+static NEXT_STACK: AtomicU64 = AtomicU64::new(0);
+static FREE_STACKS: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+static STARTED: AtomicBool = AtomicBool::new(false);
+/// Every thread and the policy holding the ready ones. `tick` takes it in
+/// the timer interrupt, which would spin forever on a CPU that already held
+/// it, so interrupts are off whenever it is held.
+static THREADS: Mutex<Threads> = Mutex::new(Threads {
+    threads: BTreeMap::new(),
+    policy: None,
+    idle: ThreadId(0),
+    dead: Vec::new(),
+    slice_start: 0,
+});
+
+crate::cpu_local! {
+    /// The thread each CPU is running.
+    static CURRENT: Cell<ThreadId> = Cell::new(ThreadId(0));
+}
+
+// thread_switch(old_rsp: *mut u64, new_rsp: u64, old_fpu: *mut FpuState,
+//               new_fpu: *const FpuState)
+//
+// Saves the callee-saved registers and FPU state of the current thread and
+// resumes the other one where it left off, or in `thread_trampoline` if it
+// is new.
+global_asm!(
+    ".global thread_switch",
+    "thread_switch:",
+    "fxsave64 [rdx]",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "fxrstor64 [rcx]",
+    "ret",
+    ".global thread_trampoline",
+    "thread_trampoline:",
+    "mov rdi, r12",
+    "call {start}",
+    "ud2",
+    start = sym thread_start,
+);
+
+extern "C" {
+    fn thread_switch(
+        old_rsp: *mut u64,
+        new_rsp: u64,
+        old_fpu: *mut FpuState,
+        new_fpu: *const FpuState,
+    );
+    fn thread_trampoline();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    /// For tests of code that only passes ids around.
+    #[cfg(test)]
+    pub(crate) const fn from_u64(id: u64) -> ThreadId {
+        ThreadId(id)
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    Ready,
+    /// Until the given tick.
+    Sleeping(u64),
+    Blocked,
+    Exited,
+}
+
+impl State {
+    pub fn name(self) -> &'static str {
+        match self {
+            State::Running => "running",
+            State::Ready => "ready",
+            State::Sleeping(_) => "sleeping",
+            State::Blocked => "blocked",
+            State::Exited => "exited",
+        }
+    }
+}
+
+/// The `fxsave` area.
+#[repr(C, align(16))]
+struct FpuState([u8; 512]);
+
+impl FpuState {
+    /// The state after `fninit`, with all exceptions masked.
+    fn new() -> FpuState {
+        let mut state = FpuState([0; 512]);
+        state.0[0..2].copy_from_slice(&0x037fu16.to_le_bytes());
+        state.0[24..28].copy_from_slice(&0x1f80u32.to_le_bytes());
+        state
+    }
+}
+
+struct Stack {
+    base: u64,
+}
+
+impl Stack {
+    fn new() -> Stack {
+        let free = interrupts::without_interrupts(|| FREE_STACKS.lock().pop());
+        let base = free.unwrap_or_else(|| {
+            let index = NEXT_STACK.fetch_add(1, Ordering::Relaxed);
+            assert!(index < MAX_THREADS, "out of thread stacks");
+            let base = STACKS_START + index * STACK_SLOT;
+            let flags =
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+            memory::map_range(VirtAddr::new(base + GUARD_SIZE), STACK_SIZE, flags)
+                .expect("failed to map thread stack");
+            base
+        });
+        Stack { base }
+    }
+
+    fn top(&self) -> u64 {
+        self.base + STACK_SLOT
+    }
+}
+
+impl Drop for Stack {
+    // stacks stay mapped for the next thread
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| FREE_STACKS.lock().push(self.base));
+    }
+}
+
+/// A fault at `addr`, described as a stack overflow if it is in a thread's
+/// guard page, for the double fault that follows one. Formatting it leaves
+/// the thread out rather than wait for the scheduler.
+pub struct StackOverflow(pub u64);
+
+impl StackOverflow {
+    pub fn is_guard_page(&self) -> bool {
+        let addr = self.0;
+        (STACKS_START..STACKS_START + MAX_THREADS * STACK_SLOT).contains(&addr)
+            && (addr - STACKS_START) % STACK_SLOT < GUARD_SIZE
+    }
+}
+
+impl fmt::Display for StackOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let addr = self.0;
+        if !self.is_guard_page() {
+            return write!(f, "fault at {:#x}", addr);
+        }
+        write!(f, "kernel stack overflow at {:#x}", addr)?;
+        let base = addr - (addr - STACKS_START) % STACK_SLOT;
+        let Some(threads) = THREADS.try_lock() else {
+            return Ok(());
+        };
+        let thread = threads.threads.values().find(|thread| {
+            thread
+                .stack
+                .as_ref()
+                .is_some_and(|stack| stack.base == base)
+        });
+        match thread {
+            Some(thread) => write!(f, " in thread {} ({})", thread.entity.id, thread.name),
+            None => Ok(()),
+        }
+    }
+}
+
+struct Thread {
+    entity: Entity,
+    name: String,
+    state: State,
+    /// Set by an `unblock` that came before the thread got to `block`, which
+    /// then returns right away.
+    wakeup_pending: bool,
+    rsp: u64,
+    /// None for the boot thread, which keeps the bootloader's stack.
+    stack: Option<Stack>,
+    fpu: Box<FpuState>,
+}
+
+struct Threads {
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    /// Holds the ready threads other than idle, which runs when there are
+    /// none. Set by `init`.
+    policy: Option<Box<dyn Scheduler>>,
+    idle: ThreadId,
+    /// Exited threads, freed by the next thread to run since they are still
+    /// on their stacks when they switch away.
+    dead: Vec<ThreadId>,
+    /// When the current thread was last switched to.
+    slice_start: u64,
+}
+
+impl Threads {
+    fn policy(&mut self) -> &mut dyn Scheduler {
+        self.policy.as_deref_mut().expect("threads not initialized")
+    }
+
+    /// Picks the thread to run next and returns what `thread_switch` needs
+    /// to go there, or None to keep running the current one.
+    fn switch_next(&mut self) -> Option<(*mut u64, u64, *mut FpuState, *const FpuState)> {
+        let current = current();
+        self.slice_start = ticks();
+        // a running thread competes with the ready ones, and may win again
+        match self.threads[&current].state {
+            State::Running if current != self.idle => self.make_ready(current),
+            State::Exited => self.dead.push(current),
+            _ => {}
+        }
+        let next = self.policy().pick_next().unwrap_or(self.idle);
+        if next == current {
+            self.threads.get_mut(&current).unwrap().state = State::Running;
+            return None;
+        }
+        let old = self.threads.get_mut(&current).unwrap();
+        if old.state == State::Running {
+            old.state = State::Ready;
+        }
+        let old_rsp = &mut old.rsp as *mut u64;
+        let old_fpu = &mut *old.fpu as *mut FpuState;
+        let new = self.threads.get_mut(&next).unwrap();
+        new.state = State::Running;
+        CURRENT.with(|current| current.set(next));
+        Some((old_rsp, new.rsp, old_fpu, &*new.fpu))
+    }
+
+    fn make_ready(&mut self, id: ThreadId) {
+        if let Some(thread) = self.threads.get_mut(&id) {
+            thread.state = State::Ready;
+            let policy = self.policy.as_deref_mut().expect("threads not initialized");
+            policy.enqueue(&mut thread.entity);
+        }
+    }
+}
+
+/// Turns the boot code into the first thread and starts preempting it.
+pub fn init() {
+    let boot = Box::new(Thread {
+        entity: Entity::new(ThreadId(0)),
+        name: String::from("main"),
+        state: State::Running,
+        wakeup_pending: false,
+        rsp: 0,
+        stack: None,
+        fpu: Box::new(FpuState::new()),
+    });
+    let idle = new_thread("idle", Box::new(idle));
+    let policy = sched::from_cmdline();
+    interrupts::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        threads.threads.insert(ThreadId(0), boot);
+        threads.idle = idle.entity.id;
+        threads.threads.insert(idle.entity.id, idle);
+        threads.policy = Some(policy);
+        threads.slice_start = ticks();
+    });
+    STARTED.store(true, Ordering::Release);
+    shell::register(&PsCommand);
+}
+
+fn idle() {
+    loop {
+        interrupts::disable();
+        if THREADS.lock().policy().is_empty() {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+        yield_now();
+    }
+}
+
+/// Charges the tick to the current thread, wakes threads whose sleep is over
+/// and preempts the current one if the policy says so. Called from the timer
+/// interrupt after its end of interrupt, as the next interrupt may come from
+/// another thread.
+pub fn tick(now: u64) {
+    if !STARTED.load(Ordering::Acquire) {
+        return;
+    }
+    let preempt = {
+        let mut threads = THREADS.lock();
+        let threads = &mut *threads;
+        let policy = threads.policy.as_deref_mut().unwrap();
+        for thread in threads.threads.values_mut() {
+            if matches!(thread.state, State::Sleeping(until) if until <= now) {
+                thread.state = State::Ready;
+                policy.enqueue(&mut thread.entity);
+            }
+        }
+        let id = current();
+        let current = threads.threads.get_mut(&id).unwrap();
+        current.entity.runtime += 1;
+        if id == threads.idle {
+            !policy.is_empty()
+        } else {
+            policy.tick(&mut current.entity, now - threads.slice_start)
+        }
+    };
+    if preempt {
+        schedule();
+    }
+}
+
+/// Switches to the next thread. Interrupts must be off.
+fn schedule() {
+    let switch = THREADS.lock().switch_next();
+    if let Some((old_rsp, new_rsp, old_fpu, new_fpu)) = switch {
+        unsafe { thread_switch(old_rsp, new_rsp, old_fpu, new_fpu) };
+        finish_switch();
+    }
+}
+
+/// Runs on the thread that was switched to.
+fn finish_switch() {
+    let dead: Vec<Box<Thread>> = {
+        let mut threads = THREADS.lock();
+        let ids = core::mem::take(&mut threads.dead);
+        ids.iter()
+            .filter_map(|id| threads.threads.remove(id))
+            .collect()
+    };
+    drop(dead);
+}
+
+extern "C" fn thread_start(entry: *mut Box<dyn FnOnce() + Send>) -> ! {
+    finish_switch();
+    interrupts::enable();
+    let entry = unsafe { Box::from_raw(entry) };
+    entry();
+    exit()
+}
+
+fn new_thread(name: &str, entry: Box<dyn FnOnce() + Send>) -> Box<Thread> {
+    let stack = Stack::new();
+    // what thread_switch pops, with r12 carrying the entry to the trampoline
+    // and a zero rbp ending backtraces. The trampoline's call then sees the
+    // aligned stack a call expects.
+    let rsp = stack.top() - 7 * 8;
+    let frame = rsp as *mut u64;
+    unsafe {
+        frame.write_bytes(0, 7);
+        frame.add(3).write(Box::into_raw(Box::new(entry)) as u64);
+        frame.add(6).write(thread_trampoline as *const () as u64);
+    }
+    Box::new(Thread {
+        entity: Entity::new(ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))),
+        name: String::from(name),
+        state: State::Ready,
+        wakeup_pending: false,
+        rsp,
+        stack: Some(stack),
+        fpu: Box::new(FpuState::new()),
+    })
+}
+
+/// Starts a kernel thread running `f`.
+pub fn spawn<F, T>(name: &str, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let state = Arc::new(Mutex::new(JoinState {
+        output: None,
+        joiner: None,
+    }));
+    let thread_state = state.clone();
+    let thread = new_thread(
+        name,
+        Box::new(move || {
+            let output = f();
+            let joiner = interrupts::without_interrupts(|| {
+                let mut state = thread_state.lock();
+                state.output = Some(output);
+                state.joiner.take()
+            });
+            if let Some(joiner) = joiner {
+                unblock(joiner);
+            }
+        }),
+    );
+    let id = thread.entity.id;
+    interrupts::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        assert!(
+            (threads.threads.len() as u64) < MAX_THREADS,
+            "too many threads"
+        );
+        threads.threads.insert(id, thread);
+        threads.make_ready(id);
+    });
+    JoinHandle { id, state }
+}
+
+pub fn current() -> ThreadId {
+    CURRENT.with(Cell::get)
+}
+
+/// Lets the other ready threads run first.
+pub fn yield_now() {
+    interrupts::without_interrupts(|| {
+        {
+            let mut threads = THREADS.lock();
+            let threads = &mut *threads;
+            let id = current();
+            if id != threads.idle {
+                let current = threads.threads.get_mut(&id).unwrap();
+                let policy = threads.policy.as_deref_mut().unwrap();
+                policy.yield_current(&mut current.entity);
+            }
+        }
+        schedule();
+    });
+}
+
+/// Blocks the current thread for at least `ticks` timer ticks.
+pub fn sleep(ticks: u64) {
+    interrupts::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        let current = current();
+        let until = crate::interrupt::ticks() + ticks;
+        threads.threads.get_mut(&current).unwrap().state = State::Sleeping(until);
+        drop(threads);
+        schedule();
+    });
+}
+
+/// Ends the current thread.
+pub fn exit() -> ! {
+    interrupts::disable();
+    {
+        let mut threads = THREADS.lock();
+        let current = current();
+        threads.threads.get_mut(&current).unwrap().state = State::Exited;
+    }
+    schedule();
+    unreachable!("exited thread was resumed");
+}
+
+/// Stops running the current thread until someone calls `unblock` on it,
+/// or returns right away if someone already did since it last blocked, so
+/// callers must check what they wait for again. Interrupts must be off from
+/// when the thread made itself known to whoever unblocks it until here, so
+/// the wakeup can't come from this CPU too early.
+pub fn block() {
+    assert!(!interrupts::are_enabled(), "blocking with interrupts on");
+    {
+        let mut threads = THREADS.lock();
+        let current = threads.threads.get_mut(&current()).unwrap();
+        if core::mem::take(&mut current.wakeup_pending) {
+            return;
+        }
+        current.state = State::Blocked;
+    }
+    schedule();
+}
+
+/// Makes a thread stopped by `block` ready to run again. One that hasn't
+/// blocked yet, like one that was woken on another CPU on its way to
+/// `block`, has the wakeup saved for when it does.
+pub fn unblock(id: ThreadId) {
+    interrupts::without_interrupts(|| {
+        let mut threads = THREADS.lock();
+        match threads.threads.get_mut(&id) {
+            Some(thread) if thread.state == State::Blocked => threads.make_ready(id),
+            Some(thread) if thread.state != State::Exited => thread.wakeup_pending = true,
+            _ => {}
+        }
+    });
+}
+
+/// Owns a thread's result.
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+struct JoinState<T> {
+    output: Option<T>,
+    joiner: Option<ThreadId>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Waits for the thread to finish and returns what it returned.
+    pub fn join(self) -> T {
+        loop {
+            let output = interrupts::without_interrupts(|| {
+                let mut state = self.state.lock();
+                if let Some(output) = state.output.take() {
+                    return Some(output);
+                }
+                state.joiner = Some(current());
+                drop(state);
+                block();
+                None
+            });
+            if let Some(output) = output {
+                return output;
+            }
+        }
+    }
+}
+
+/// Sets a thread's priority, from 0, the most urgent, to `NUM_PRIORITIES - 1`.
+/// Policies that order ready threads by it see the change the next time the
+/// thread becomes ready.
+pub fn set_priority(id: ThreadId, priority: u8) {
+    assert!(priority < NUM_PRIORITIES, "invalid priority {}", priority);
+    interrupts::without_interrupts(|| {
+        if let Some(thread) = THREADS.lock().threads.get_mut(&id) {
+            thread.entity.priority = priority;
+        }
+    });
+}
+
+/// The name of the scheduling policy in use.
+pub fn policy_name() -> &'static str {
+    interrupts::without_interrupts(|| THREADS.lock().policy().name())
+}
+
+pub struct ThreadInfo {
+    pub name: String,
+    pub state: State,
+    pub entity: Entity,
+}
+
+/// A snapshot of all threads, by id.
+pub fn list() -> Vec<ThreadInfo> {
+    interrupts::without_interrupts(|| snapshot(&THREADS.lock()))
+}
+
+/// Like `list`, but `None` instead of spinning if the thread table is locked,
+/// for the debugger, which may have stopped the code holding it.
+pub fn try_list() -> Option<Vec<ThreadInfo>> {
+    interrupts::without_interrupts(|| THREADS.try_lock().map(|threads| snapshot(&threads)))
+}
+
+/// What `thread_switch` saved of a thread that isn't running: the
+/// callee-saved registers and where it will resume. The others are lost.
+#[derive(Debug, Clone, Copy)]
+pub struct SavedRegisters {
+    pub rip: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+}
+
+/// The registers of thread `id` as of its last switch, for the debugger.
+/// `None` if it is running, gone, or the thread table is locked.
+pub fn saved_registers(id: ThreadId) -> Option<SavedRegisters> {
+    interrupts::without_interrupts(|| {
+        let threads = THREADS.try_lock()?;
+        let thread = threads.threads.get(&id)?;
+        if matches!(thread.state, State::Running | State::Exited) || thread.rsp == 0 {
+            return None;
+        }
+        // what `thread_switch` pushed, last first, then its return address
+        let frame = unsafe { core::slice::from_raw_parts(thread.rsp as *const u64, 7) };
+        Some(SavedRegisters {
+            r15: frame[0],
+            r14: frame[1],
+            r13: frame[2],
+            r12: frame[3],
+            rbx: frame[4],
+            rbp: frame[5],
+            rip: frame[6],
+            rsp: thread.rsp + 7 * 8,
+        })
+    })
+}
+
+fn snapshot(threads: &Threads) -> Vec<ThreadInfo> {
+    threads
+        .threads
+        .values()
+        .map(|thread| ThreadInfo {
+            name: thread.name.clone(),
+            state: thread.state,
+            entity: thread.entity,
+        })
+        .collect()
+}
+
+pub struct PsCommand;
+
+impl Command for PsCommand {
+    fn name(&self) -> &'static str {
+        "ps"
+    }
+
+    fn help(&self) -> &'static str {
+        "list threads and their runtime"
+    }
+
+    fn run(&self, _args: &[&str], out: &mut dyn Write) -> Result<(), CommandError> {
+        writeln!(out, "scheduler: {}", policy_name())?;
+        writeln!(
+            out,
+            "  {:>4}  {:<16} {:<10} {:>3} {:>10} {:>10}",
+            "ID", "NAME", "STATE", "PRI", "RUNTIME", "VRUNTIME"
+        )?;
+        for thread in list() {
+            let entity = thread.entity;
+            writeln!(
+                out,
+                "  {:>4}  {:<16} {:<10} {:>3} {:>7}.{:02} {:>10}",
+                entity.id,
+                thread.name,
+                thread.state.name(),
+                entity.priority,
+                entity.runtime / TIMER_HZ,
+                entity.runtime % TIMER_HZ * 100 / TIMER_HZ,
+                entity.vruntime
+            )?;
+        }
+        Ok(())
+    }
+}
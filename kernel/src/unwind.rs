@@ -0,0 +1,607 @@
+use core::arch::asm;
+use spin::Once;
+use x86_64::instructions::tables::sidt;
+
+/// DWARF numbers of the registers unwinding tracks, up to the return address
+/// column, which stands in for rip.
+const NUM_REGS: usize = 17;
+const RBP: usize = 6;
+const RSP: usize = 7;
+const RIP: usize = 16;
+/// Depth of `DW_CFA_remember_state` nesting supported.
+const STATE_STACK: usize = 4;
+const MAX_FRAME_SIZE: u64 = 1024 * 1024;
+
+const DW_EH_PE_OMIT: u8 = 0xff;
+const DW_EH_PE_PCREL: u8 = 0x10;
+const DW_EH_PE_INDIRECT: u8 = 0x80;
+
+static EH_FRAME: Once<EhFrame> = Once::new();
+
+/// Finds `.eh_frame` through the section headers of `elf`, the kernel's ELF
+/// file, and reads it from where the image is mapped.
+pub fn init(elf: &[u8], image_offset: u64) {
+    if let Some(section) = find_section(elf, b".eh_frame") {
+        let data = unsafe {
+            core::slice::from_raw_parts(
+                (image_offset + section.addr) as *const u8,
+                section.size as usize,
+            )
+        };
+        EH_FRAME.call_once(|| EhFrame { data });
+    }
+}
+
+pub fn is_available() -> bool {
+    EH_FRAME.get().is_some()
+}
+
+/// Calls `f` with the instruction pointer of every frame, starting at `regs`,
+/// until it returns false or the stack can't be unwound any further.
+pub fn unwind(mut regs: Registers, mut f: impl FnMut(u64) -> bool) {
+    let Some(eh_frame) = EH_FRAME.get() else {
+        return;
+    };
+    let mut exact = true;
+    while let Some(ip) = regs.0[RIP].filter(|&ip| ip != 0) {
+        if !f(ip) {
+            break;
+        }
+        // past the first frame ip is a return address, which may already be
+        // in the next function if the call was the last instruction
+        let pc = if exact { ip } else { ip - 1 };
+        match eh_frame.step(&regs, pc) {
+            // an interrupted frame's ip is where it was stopped, not a return
+            // address
+            Some((next, interrupted)) => (regs, exact) = (next, interrupted),
+            None => break,
+        }
+    }
+}
+
+/// Start of the function containing `pc`, according to its unwind info.
+pub fn function_start(pc: u64) -> Option<u64> {
+    Some(EH_FRAME.get()?.find_fde(pc)?.start)
+}
+
+/// Register values of a frame, where they are known.
+#[derive(Debug, Clone, Copy)]
+pub struct Registers([Option<u64>; NUM_REGS]);
+
+impl Registers {
+    /// The callee saved registers, stack pointer and instruction pointer at
+    /// the call site.
+    #[inline(always)]
+    pub fn capture() -> Registers {
+        let mut values = [0u64; NUM_REGS];
+        unsafe {
+            asm!(
+                "mov [rdi + 3 * 8], rbx",
+                "mov [rdi + 6 * 8], rbp",
+                "mov [rdi + 7 * 8], rsp",
+                "mov [rdi + 12 * 8], r12",
+                "mov [rdi + 13 * 8], r13",
+                "mov [rdi + 14 * 8], r14",
+                "mov [rdi + 15 * 8], r15",
+                "lea rax, [rip]",
+                "mov [rdi + 16 * 8], rax",
+                in("rdi") values.as_mut_ptr(),
+                out("rax") _,
+                options(nostack, preserves_flags),
+            );
+        }
+        let mut regs = Registers([None; NUM_REGS]);
+        for reg in [3, RBP, RSP, 12, 13, 14, 15, RIP] {
+            regs.0[reg] = Some(values[reg]);
+        }
+        regs
+    }
+
+    /// The state of interrupted code, from what the cpu pushed and its frame
+    /// pointer. Other registers may have been clobbered by the handler.
+    pub fn interrupted(ip: u64, sp: u64, rbp: u64) -> Registers {
+        let mut regs = Registers([None; NUM_REGS]);
+        regs.0[RIP] = Some(ip);
+        regs.0[RSP] = Some(sp);
+        regs.0[RBP] = Some(rbp);
+        regs
+    }
+}
+
+/// The vector of the loaded IDT whose handler starts at `start`, if any.
+fn interrupt_vector(start: u64) -> Option<u8> {
+    let idt = sidt();
+    let gates = unsafe {
+        core::slice::from_raw_parts(idt.base.as_ptr::<[u32; 4]>(), (idt.limit as usize + 1) / 16)
+    };
+    gates
+        .iter()
+        .position(|gate| {
+            let present = gate[1] & 1 << 15 != 0;
+            let offset =
+                (gate[0] & 0xffff) as u64 | (gate[1] & 0xffff_0000) as u64 | (gate[2] as u64) << 32;
+            present && offset == start
+        })
+        .map(|vector| vector as u8)
+}
+
+/// What a section header of the kernel's ELF file says about the section.
+pub(crate) struct Section {
+    /// Where the section is loaded, relative to the start of the image.
+    pub addr: u64,
+    /// Where the section is in the file.
+    pub offset: u64,
+    pub size: u64,
+    /// The index of a related section, like the names of a symbol table.
+    pub link: u32,
+}
+
+impl Section {
+    /// The bytes of the section in `elf`.
+    pub fn data<'a>(&self, elf: &'a [u8]) -> Option<&'a [u8]> {
+        elf.get(self.offset as usize..)?.get(..self.size as usize)
+    }
+}
+
+pub(crate) fn find_section(elf: &[u8], name: &[u8]) -> Option<Section> {
+    let names = section(elf, u16_at(elf, 0x3e)? as usize)?.offset as usize;
+    (0..u16_at(elf, 0x3c)? as usize).find_map(|index| {
+        let name_offset = u32_at(elf, section_header(elf, index)?)? as usize;
+        let section_name = elf.get(names.checked_add(name_offset)?..)?;
+        let matches = section_name.strip_prefix(name)?.first() == Some(&0);
+        matches.then(|| section(elf, index)).flatten()
+    })
+}
+
+pub(crate) fn section(elf: &[u8], index: usize) -> Option<Section> {
+    let header = section_header(elf, index)?;
+    Some(Section {
+        addr: u64_at(elf, header + 0x10)?,
+        offset: u64_at(elf, header + 0x18)?,
+        size: u64_at(elf, header + 0x20)?,
+        link: u32_at(elf, header + 0x28)?,
+    })
+}
+
+/// Where the header of section `index` is in `elf`.
+fn section_header(elf: &[u8], index: usize) -> Option<usize> {
+    if elf.get(..4)? != b"\x7fELF" {
+        return None;
+    }
+    let section_headers = u64_at(elf, 0x28)? as usize;
+    section_headers.checked_add(index * u16_at(elf, 0x3a)? as usize)
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        bytes.get(at..at.checked_add(2)?)?.try_into().ok()?,
+    ))
+}
+
+pub(crate) fn u32_at(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(at..at.checked_add(4)?)?.try_into().ok()?,
+    ))
+}
+
+pub(crate) fn u64_at(bytes: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        bytes.get(at..at.checked_add(8)?)?.try_into().ok()?,
+    ))
+}
+
+struct Reader {
+    data: &'static [u8],
+    pos: usize,
+}
+
+impl Reader {
+    /// Where the next byte lives in memory, for pc relative pointers.
+    fn address(&self) -> u64 {
+        self.data.as_ptr() as u64 + self.pos as u64
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let bytes = self.data.get(self.pos..self.pos + N)?.try_into().ok()?;
+        self.pos += N;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes()?))
+    }
+
+    fn uleb(&mut self) -> Option<u64> {
+        let (mut value, mut shift) = (0u64, 0);
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Option<i64> {
+        let (mut value, mut shift) = (0i64, 0);
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Some(value);
+            }
+        }
+    }
+
+    fn cstr(&mut self) -> Option<&'static [u8]> {
+        let len = self.data.get(self.pos..)?.iter().position(|&b| b == 0)?;
+        let s = &self.data[self.pos..self.pos + len];
+        self.pos += len + 1;
+        Some(s)
+    }
+
+    /// Reads a pointer in one of the `DW_EH_PE_*` encodings.
+    fn pointer(&mut self, encoding: u8) -> Option<u64> {
+        if encoding == DW_EH_PE_OMIT {
+            return None;
+        }
+        let base = match encoding & 0x70 {
+            0 => 0,
+            DW_EH_PE_PCREL => self.address(),
+            _ => return None,
+        };
+        let value = match encoding & 0x0f {
+            0x00 | 0x04 | 0x0c => self.u64()?,
+            0x01 => self.uleb()?,
+            0x02 => self.u16()? as u64,
+            0x03 => self.u32()? as u64,
+            0x09 => self.sleb()? as u64,
+            0x0a => self.u16()? as i16 as u64,
+            0x0b => self.u32()? as i32 as u64,
+            _ => return None,
+        };
+        let value = base.wrapping_add(value);
+        if encoding & DW_EH_PE_INDIRECT != 0 {
+            return Some(unsafe { *(value as *const u64) });
+        }
+        Some(value)
+    }
+}
+
+/// Common information entry, shared by the descriptions of many functions.
+struct Cie {
+    code_align: u64,
+    data_align: i64,
+    return_register: usize,
+    encoding: u8,
+    augmented: bool,
+    instructions: &'static [u8],
+}
+
+/// Frame description entry, how to unwind one function.
+struct Fde {
+    cie: Cie,
+    start: u64,
+    instructions: &'static [u8],
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Rule {
+    Undefined,
+    SameValue,
+    Offset(i64),
+    ValOffset(i64),
+    Register(usize),
+}
+
+/// One row of the call frame table: how to find the caller's registers.
+#[derive(Debug, Clone, Copy)]
+struct Row {
+    cfa_register: usize,
+    cfa_offset: i64,
+    rules: [Rule; NUM_REGS],
+}
+
+impl Row {
+    fn set(&mut self, reg: u64, rule: Rule) {
+        if let Some(slot) = self.rules.get_mut(reg as usize) {
+            *slot = rule;
+        }
+    }
+}
+
+struct EhFrame {
+    data: &'static [u8],
+}
+
+impl EhFrame {
+    fn find_fde(&self, pc: u64) -> Option<Fde> {
+        let mut reader = Reader {
+            data: self.data,
+            pos: 0,
+        };
+        while reader.pos < self.data.len() {
+            let length = reader.u32()?;
+            // 0 terminates the section, and 64 bit entries aren't generated
+            // for the kernel
+            if length == 0 || length == u32::MAX {
+                return None;
+            }
+            let id_pos = reader.pos;
+            let end = id_pos + length as usize;
+            let id = reader.u32()? as usize;
+            if id != 0 {
+                let cie = self.cie(id_pos.checked_sub(id)?)?;
+                let start = reader.pointer(cie.encoding)?;
+                let len = reader.pointer(cie.encoding & 0x0f)?;
+                if (start..start + len).contains(&pc) {
+                    if cie.augmented {
+                        let skip = reader.uleb()? as usize;
+                        reader.pos += skip;
+                    }
+                    let instructions = self.data.get(reader.pos..end)?;
+                    return Some(Fde {
+                        cie,
+                        start,
+                        instructions,
+                    });
+                }
+            }
+            reader.pos = end;
+        }
+        None
+    }
+
+    fn cie(&self, pos: usize) -> Option<Cie> {
+        let mut reader = Reader {
+            data: self.data,
+            pos,
+        };
+        let end = reader.u32()? as usize + reader.pos;
+        if reader.u32()? != 0 {
+            return None;
+        }
+        let version = reader.u8()?;
+        let augmentation = reader.cstr()?;
+        let augmented = augmentation.first() == Some(&b'z');
+        if !augmentation.is_empty() && !augmented {
+            return None;
+        }
+        let code_align = reader.uleb()?;
+        let data_align = reader.sleb()?;
+        let return_register = match version {
+            1 => reader.u8()? as usize,
+            _ => reader.uleb()? as usize,
+        };
+        let mut encoding = 0;
+        if augmented {
+            let len = reader.uleb()? as usize;
+            let data_end = reader.pos + len;
+            for &c in &augmentation[1..] {
+                match c {
+                    b'R' => encoding = reader.u8()?,
+                    // the personality routine, unused without unwinding panics
+                    b'P' => {
+                        let personality = reader.u8()?;
+                        reader.pointer(personality & !DW_EH_PE_INDIRECT)?;
+                    }
+                    b'L' => {
+                        reader.u8()?;
+                    }
+                    b'S' => {}
+                    _ => return None,
+                }
+            }
+            reader.pos = data_end;
+        }
+        Some(Cie {
+            code_align,
+            data_align,
+            return_register,
+            encoding,
+            augmented,
+            instructions: self.data.get(reader.pos..end)?,
+        })
+    }
+
+    /// Runs the call frame instructions up to `pc`, giving its row.
+    fn row(&self, fde: &Fde, pc: u64) -> Option<Row> {
+        let row = Row {
+            cfa_register: RSP,
+            cfa_offset: 8,
+            rules: [Rule::SameValue; NUM_REGS],
+        };
+        let initial = execute(&fde.cie, fde.cie.instructions, row, &row, 0, u64::MAX)?;
+        execute(&fde.cie, fde.instructions, initial, &initial, fde.start, pc)
+    }
+
+    /// Recovers the caller's registers from those of the frame at `pc`, and
+    /// whether the frame was an interrupt handler's, the caller being the
+    /// code it interrupted.
+    fn step(&self, regs: &Registers, pc: u64) -> Option<(Registers, bool)> {
+        let fde = self.find_fde(pc)?;
+        let row = self.row(&fde, pc)?;
+        let sp = regs.0[RSP]?;
+        let cfa = (*regs.0.get(row.cfa_register)?)?.checked_add_signed(row.cfa_offset)?;
+        // the caller's frame is always above ours, anything else is garbage
+        if cfa <= sp || cfa - sp > MAX_FRAME_SIZE {
+            return None;
+        }
+        let mut next = Registers([None; NUM_REGS]);
+        for (reg, rule) in row.rules.iter().enumerate() {
+            next.0[reg] = match *rule {
+                Rule::Undefined => None,
+                Rule::SameValue => regs.0[reg],
+                Rule::Offset(offset) => {
+                    Some(unsafe { *(cfa.wrapping_add_signed(offset) as *const u64) })
+                }
+                Rule::ValOffset(offset) => Some(cfa.wrapping_add_signed(offset)),
+                Rule::Register(from) => *regs.0.get(from)?,
+            };
+        }
+        if let Some(vector) = interrupt_vector(fde.start) {
+            // the unwind info takes the pushed rip, or the error code, for a
+            // return address, but the interrupted rsp is further up in the
+            // frame the cpu pushed
+            let error_code = matches!(vector, 8 | 10..=14 | 17 | 21 | 29 | 30);
+            let frame = cfa - 8 + if error_code { 8 } else { 0 };
+            let frame = unsafe { *(frame as *const [u64; 5]) };
+            next.0[RIP] = Some(frame[0]);
+            next.0[RSP] = Some(frame[3]);
+            return Some((next, true));
+        }
+        next.0[RSP] = Some(cfa);
+        next.0[RIP] = *next.0.get(fde.cie.return_register)?;
+        Some((next, false))
+    }
+}
+
+/// Interprets call frame instructions starting at `loc`, stopping before the
+/// first one that applies past `pc`.
+fn execute(
+    cie: &Cie,
+    instructions: &'static [u8],
+    mut row: Row,
+    initial: &Row,
+    mut loc: u64,
+    pc: u64,
+) -> Option<Row> {
+    let mut reader = Reader {
+        data: instructions,
+        pos: 0,
+    };
+    let mut stack = [row; STATE_STACK];
+    let mut depth = 0;
+    while reader.pos < instructions.len() {
+        let op = reader.u8()?;
+        let next_loc = match (op >> 6, op & 0x3f) {
+            // DW_CFA_advance_loc, DW_CFA_offset and DW_CFA_restore carry an
+            // operand in the low bits
+            (1, delta) => Some(loc + delta as u64 * cie.code_align),
+            (2, reg) => {
+                let offset = reader.uleb()? as i64 * cie.data_align;
+                row.set(reg as u64, Rule::Offset(offset));
+                None
+            }
+            (3, reg) => {
+                row.set(reg as u64, *initial.rules.get(reg as usize)?);
+                None
+            }
+            (_, 0x00) => None,
+            (_, 0x01) => Some(reader.pointer(cie.encoding)?),
+            (_, 0x02) => Some(loc + reader.u8()? as u64 * cie.code_align),
+            (_, 0x03) => Some(loc + reader.u16()? as u64 * cie.code_align),
+            (_, 0x04) => Some(loc + reader.u32()? as u64 * cie.code_align),
+            (_, 0x05) => {
+                let reg = reader.uleb()?;
+                let offset = reader.uleb()? as i64 * cie.data_align;
+                row.set(reg, Rule::Offset(offset));
+                None
+            }
+            (_, 0x06) => {
+                let reg = reader.uleb()?;
+                row.set(reg, *initial.rules.get(reg as usize)?);
+                None
+            }
+            (_, 0x07) => {
+                row.set(reader.uleb()?, Rule::Undefined);
+                None
+            }
+            (_, 0x08) => {
+                row.set(reader.uleb()?, Rule::SameValue);
+                None
+            }
+            (_, 0x09) => {
+                let reg = reader.uleb()?;
+                let from = reader.uleb()? as usize;
+                row.set(reg, Rule::Register(from));
+                None
+            }
+            (_, 0x0a) => {
+                *stack.get_mut(depth)? = row;
+                depth += 1;
+                None
+            }
+            (_, 0x0b) => {
+                depth = depth.checked_sub(1)?;
+                row = stack[depth];
+                None
+            }
+            (_, 0x0c) => {
+                row.cfa_register = reader.uleb()? as usize;
+                row.cfa_offset = reader.uleb()? as i64;
+                None
+            }
+            (_, 0x0d) => {
+                row.cfa_register = reader.uleb()? as usize;
+                None
+            }
+            (_, 0x0e) => {
+                row.cfa_offset = reader.uleb()? as i64;
+                None
+            }
+            (_, 0x11) => {
+                let reg = reader.uleb()?;
+                let offset = reader.sleb()? * cie.data_align;
+                row.set(reg, Rule::Offset(offset));
+                None
+            }
+            (_, 0x12) => {
+                row.cfa_register = reader.uleb()? as usize;
+                row.cfa_offset = reader.sleb()? * cie.data_align;
+                None
+            }
+            (_, 0x13) => {
+                row.cfa_offset = reader.sleb()? * cie.data_align;
+                None
+            }
+            (_, 0x14) => {
+                let reg = reader.uleb()?;
+                let offset = reader.uleb()? as i64 * cie.data_align;
+                row.set(reg, Rule::ValOffset(offset));
+                None
+            }
+            (_, 0x15) => {
+                let reg = reader.uleb()?;
+                let offset = reader.sleb()? * cie.data_align;
+                row.set(reg, Rule::ValOffset(offset));
+                None
+            }
+            // DW_CFA_GNU_args_size
+            (_, 0x2e) => {
+                reader.uleb()?;
+                None
+            }
+            // DWARF expressions aren't supported, give up on the frame
+            _ => return None,
+        };
+        if let Some(next_loc) = next_loc {
+            if next_loc > pc {
+                break;
+            }
+            loc = next_loc;
+        }
+    }
+    Some(row)
+}
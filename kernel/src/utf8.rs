@@ -0,0 +1,93 @@
+/// Incremental UTF-8 decoder for byte streams that may split a character
+/// across writes. Malformed input decodes to U+FFFD.
+pub struct Utf8Decoder {
+    buf: [u8; 4],
+    len: usize,
+    needed: usize,
+}
+
+impl Utf8Decoder {
+    pub const fn new() -> Utf8Decoder {
+        Utf8Decoder {
+            buf: [0; 4],
+            len: 0,
+            needed: 0,
+        }
+    }
+
+    pub fn decode(&mut self, bytes: &[u8], mut f: impl FnMut(char)) {
+        for &byte in bytes {
+            if self.needed > 0 {
+                if byte & 0xc0 == 0x80 {
+                    self.buf[self.len] = byte;
+                    self.len += 1;
+                    if self.len == self.needed {
+                        self.needed = 0;
+                        let c = core::str::from_utf8(&self.buf[..self.len])
+                            .ok()
+                            .and_then(|s| s.chars().next());
+                        f(c.unwrap_or(char::REPLACEMENT_CHARACTER));
+                    }
+                    continue;
+                }
+                // truncated sequence, start over at this byte
+                self.needed = 0;
+                f(char::REPLACEMENT_CHARACTER);
+            }
+            let needed = match byte {
+                0x00..=0x7f => {
+                    f(byte as char);
+                    continue;
+                }
+                0xc2..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf4 => 4,
+                _ => {
+                    f(char::REPLACEMENT_CHARACTER);
+                    continue;
+                }
+            };
+            self.buf[0] = byte;
+            self.len = 1;
+            self.needed = needed;
+        }
+    }
+}
+
+impl Default for Utf8Decoder {
+    fn default() -> Utf8Decoder {
+        Utf8Decoder::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::String;
+
+    fn decode(chunks: &[&[u8]]) -> String {
+        let mut decoder = Utf8Decoder::new();
+        let mut out = String::new();
+        for chunk in chunks {
+            decoder.decode(chunk, |c| out.push(c));
+        }
+        out
+    }
+
+    #[test_case]
+    fn split_across_writes() {
+        assert_eq!(decode(&[b"a\xe2", b"\x82", b"\xacb"]), "a€b");
+        assert_eq!(decode(&[b"\xf0\x9f", b"\x98\x80"]), "😀");
+    }
+
+    #[test_case]
+    fn malformed() {
+        // stray continuation, overlong lead and out of range lead bytes
+        assert_eq!(decode(&[b"\x80\xc0\xf5a"]), "\u{fffd}\u{fffd}\u{fffd}a");
+        // truncated sequences resync at the next byte
+        assert_eq!(decode(&[b"\xe2\x82a\xc3"]), "\u{fffd}a");
+        assert_eq!(decode(&[b"\xe2\x82", b"\xe2\x82\xac"]), "\u{fffd}€");
+        // encoded surrogates and overlong three byte forms
+        assert_eq!(decode(&[b"\xed\xa0\x80\xe0\x80\x80"]), "\u{fffd}\u{fffd}");
+    }
+}
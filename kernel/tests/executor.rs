@@ -0,0 +1,163 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader_api::info::BootInfo;
+use core::future::{poll_fn, Future};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::Poll;
+use kernel::executor::{self, WakerSlot, MAX_TASKS};
+use kernel::interrupt::ticks;
+use kernel::{check, exit_qemu, serial_print, serial_println, thread, QemuExitCode};
+use x86_64::instructions;
+
+const ROUNDS: usize = 300;
+
+/// Tells the tasks filling the ready queue to finish.
+static STOP: AtomicBool = AtomicBool::new(false);
+static EDGE_RAN: AtomicBool = AtomicBool::new(false);
+static MAIN: WakerSlot = WakerSlot::new();
+
+/// The last round the waker thread signalled and the last one the executor
+/// saw.
+static SIGNALLED: AtomicUsize = AtomicUsize::new(0);
+static SEEN: AtomicUsize = AtomicUsize::new(0);
+static SIGNAL: WakerSlot = WakerSlot::new();
+
+kernel::test_entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+
+    serial_print!("executor::spawn_and_join...\t");
+    let sum = executor::block_on(async {
+        let a = executor::spawn(async { 1 });
+        let b = executor::spawn(async {
+            yield_now().await;
+            executor::spawn(async { 2 }).await + 3
+        });
+        a.await + b.await
+    });
+    check(sum == 6, "wrong result from join");
+    serial_println!("[ok]");
+
+    serial_print!("executor::full_ready_queue...\t");
+    executor::block_on(full_ready_queue());
+    serial_println!("[ok]");
+
+    serial_print!("executor::sleep_is_woken_by_the_timer...\t");
+    let start = ticks();
+    executor::block_on(async {
+        // polled twice before it is due, which must not add a second sleeper
+        let mut sleep = core::pin::pin!(executor::sleep(5));
+        poll_fn(|cx| {
+            check(sleep.as_mut().poll(cx).is_pending(), "sleep was done early");
+            Poll::Ready(())
+        })
+        .await;
+        sleep.await;
+    });
+    check(ticks() >= start + 5, "sleep returned early");
+    serial_println!("[ok]");
+
+    serial_print!("executor::park_and_unpark...\t");
+    let waker = thread::spawn("waker", signal);
+    executor::block_on(async {
+        for round in 1..=ROUNDS {
+            poll_fn(|cx| {
+                SIGNAL.register(cx.waker());
+                if SIGNALLED.load(Ordering::SeqCst) >= round {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            })
+            .await;
+            SEEN.store(round, Ordering::SeqCst);
+        }
+    });
+    waker.join();
+    serial_println!("[ok]");
+
+    exit_qemu(QemuExitCode::Success);
+    loop {
+        instructions::hlt();
+    }
+}
+
+/// Has every slot of the ready queue taken at once: all tasks the executor
+/// allows, the one being polled and the future passed to `block_on`. A lost
+/// entry leaves a task never polled again and the test hanging.
+async fn full_ready_queue() {
+    let mut tasks: Vec<_> = (0..MAX_TASKS - 1)
+        .map(|_| executor::spawn(fill()))
+        .collect();
+    // polled after every filler has been polled and has woken itself
+    tasks.push(executor::spawn(async {
+        let inner = executor::spawn(fill());
+        MAIN.wake();
+        EDGE_RAN.store(true, Ordering::SeqCst);
+        yield_now().await;
+        inner.await
+    }));
+    poll_fn(|cx| {
+        MAIN.register(cx.waker());
+        if EDGE_RAN.load(Ordering::SeqCst) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await;
+    STOP.store(true, Ordering::SeqCst);
+    for task in tasks {
+        task.await;
+    }
+}
+
+/// Stays in the ready queue until told to stop.
+async fn fill() {
+    while !STOP.load(Ordering::SeqCst) {
+        yield_now().await;
+    }
+}
+
+/// Lets the other ready tasks run.
+async fn yield_now() {
+    let mut yielded = false;
+    poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+    .await
+}
+
+/// Wakes the executor from another thread each round, after waiting for it
+/// to see the last one and park. The wake comes right away, after a yield or
+/// after a timer tick, so it lands at different points of parking. A lost
+/// wakeup leaves the executor parked for good and the test hanging.
+fn signal() {
+    for round in 1..=ROUNDS {
+        while SEEN.load(Ordering::SeqCst) < round - 1 {
+            thread::yield_now();
+        }
+        match round % 3 {
+            0 => {}
+            1 => thread::yield_now(),
+            _ => {
+                let start = ticks();
+                while ticks() == start {
+                    core::hint::spin_loop();
+                }
+            }
+        }
+        SIGNALLED.store(round, Ordering::SeqCst);
+        SIGNAL.wake();
+    }
+}
@@ -0,0 +1,94 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::string::ToString;
+use bootloader_api::info::BootInfo;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use kernel::lockdep::SpinLock;
+use kernel::{exit_qemu, serial_print, serial_println, thread, QemuExitCode};
+use x86_64::instructions;
+
+kernel::test_entry_point!(main, panic);
+
+static FIRST: SpinLock<u32> = SpinLock::new("FIRST", 0);
+static SECOND: SpinLock<u32> = SpinLock::new("SECOND", 0);
+static SELF: SpinLock<u32> = SpinLock::new("SELF", 0);
+/// Left locked by a thread that is gone.
+static STUCK: SpinLock<u32> = SpinLock::new("STUCK", 0);
+
+/// Which test the next panic belongs to. Every test ends in a panic, whose
+/// handler checks it and starts the next one.
+static STAGE: AtomicUsize = AtomicUsize::new(0);
+
+/// The start of the panic message each test expects, and the next test.
+const STAGES: [(&str, Option<fn() -> !>); 3] = [
+    (
+        "lock order inversion between SECOND and FIRST",
+        Some(self_deadlock),
+    ),
+    ("SELF taken again at", Some(spin_timeout)),
+    ("gave up on STUCK at", None),
+];
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    thread::spawn("holder", || core::mem::forget(STUCK.lock())).join();
+
+    serial_print!("lockdep::inversion_is_reported...\t");
+    {
+        let _first = FIRST.lock();
+        let _second = SECOND.lock();
+    }
+    // can't deadlock on one CPU, but would with another taking them in the
+    // first order
+    {
+        let _second = SECOND.lock();
+        let _first = FIRST.lock();
+    }
+    did_not_panic("taking the locks the other way round went unnoticed")
+}
+
+fn self_deadlock() -> ! {
+    serial_print!("lockdep::self_deadlock_is_reported...\t");
+    let _first = SELF.lock();
+    let _second = SELF.lock();
+    did_not_panic("taking a lock twice went unnoticed")
+}
+
+/// Spins on a lock that is never released, which takes a few seconds.
+fn spin_timeout() -> ! {
+    serial_print!("lockdep::spin_times_out...\t");
+    let _stuck = STUCK.lock();
+    did_not_panic("got a lock that was never released")
+}
+
+fn did_not_panic(message: &str) -> ! {
+    serial_println!("[failed]");
+    serial_println!("{}", message);
+    exit_qemu(QemuExitCode::Failed);
+    loop {
+        instructions::hlt();
+    }
+}
+
+fn panic(info: &PanicInfo) -> ! {
+    let message = info.message().to_string();
+    let (expected, next) = STAGES[STAGE.fetch_add(1, Ordering::SeqCst)];
+    if message.starts_with(expected) {
+        serial_println!("[ok]");
+        if let Some(next) = next {
+            next();
+        }
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("{}", message);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {
+        instructions::hlt();
+    }
+}
@@ -0,0 +1,46 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader_api::info::BootInfo;
+use kernel::smp::{self, MAX_CPUS};
+use kernel::{acpi, check, exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::instructions;
+
+kernel::test_entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+
+    serial_print!("smp::every_cpu_checks_in...\t");
+    let mut expected = acpi::madt().expect("no MADT").apic_ids;
+    serial_println!();
+    let mut checked_in = Vec::new();
+    for cpu in 0..MAX_CPUS {
+        if let Some(apic_id) = smp::apic_id(cpu) {
+            serial_println!("  cpu {} (APIC id {}) online", cpu, apic_id);
+            checked_in.push(apic_id);
+        }
+    }
+    // the test runs with -smp 4
+    check(expected.len() > 1, "only one CPU, QEMU needs -smp");
+    check(
+        smp::cpu_count() == expected.len(),
+        "not every CPU checked in",
+    );
+    // every CPU stored the id its own local APIC reports
+    expected.sort_unstable();
+    checked_in.sort_unstable();
+    check(
+        checked_in == expected,
+        "CPUs checked in with the wrong APIC ids",
+    );
+    serial_println!("[ok]");
+
+    exit_qemu(QemuExitCode::Success);
+    loop {
+        instructions::hlt();
+    }
+}
@@ -0,0 +1,79 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader_api::info::BootInfo;
+use kernel::sync::{Condvar, Mutex};
+use kernel::{check, exit_qemu, serial_print, serial_println, thread, QemuExitCode};
+use x86_64::instructions;
+
+const THREADS: usize = 4;
+const ROUNDS: usize = 200;
+
+/// A count bumped in two steps, with a yield in between so the other
+/// threads pile up on the lock.
+static COUNTER: Mutex<usize> = Mutex::new(0);
+/// Whose turn it is and every turn taken so far.
+static BALL: Mutex<(u8, Vec<u8>)> = Mutex::new((b'a', Vec::new()));
+static TURN: Condvar = Condvar::new();
+
+kernel::test_entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+
+    serial_print!("sync::mutex_contention...\t");
+    let threads: Vec<_> = (0..THREADS)
+        .map(|_| thread::spawn("contender", contend))
+        .collect();
+    for thread in threads {
+        thread.join();
+    }
+    check(*COUNTER.lock() == THREADS * ROUNDS, "lost an update");
+    serial_println!("[ok]");
+
+    serial_print!("sync::condvar_ping_pong...\t");
+    let ping = thread::spawn("ping", || play(b'a', b'b'));
+    let pong = thread::spawn("pong", || play(b'b', b'a'));
+    ping.join();
+    pong.join();
+    let ball = BALL.lock();
+    let turns = &ball.1;
+    check(turns.len() == 2 * ROUNDS, "missed a turn");
+    check(
+        turns
+            .iter()
+            .enumerate()
+            .all(|(i, &turn)| turn == [b'a', b'b'][i % 2]),
+        "played out of turn",
+    );
+    serial_println!("[ok]");
+
+    exit_qemu(QemuExitCode::Success);
+    loop {
+        instructions::hlt();
+    }
+}
+
+fn contend() {
+    for _ in 0..ROUNDS {
+        let mut counter = COUNTER.lock();
+        let seen = *counter;
+        thread::yield_now();
+        *counter = seen + 1;
+    }
+}
+
+/// Waits for `me`'s turn, takes it and hands over to `other`, `ROUNDS` times.
+/// A lost wakeup leaves both threads waiting and the test hanging.
+fn play(me: u8, other: u8) {
+    for _ in 0..ROUNDS {
+        let mut ball = TURN.wait_while(BALL.lock(), |ball| ball.0 != me);
+        ball.1.push(me);
+        ball.0 = other;
+        drop(ball);
+        TURN.notify_all();
+    }
+}
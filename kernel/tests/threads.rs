@@ -0,0 +1,65 @@
+#![no_std]
+#![no_main]
+
+use bootloader_api::info::BootInfo;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use kernel::interrupt::ticks;
+use kernel::{check, exit_qemu, serial_print, serial_println, thread, QemuExitCode};
+use x86_64::instructions;
+
+const ROUNDS: usize = 20;
+
+/// Which thread ran each round, in the order they ran.
+static LOG: [AtomicU8; 2 * ROUNDS] = [const { AtomicU8::new(0) }; 2 * ROUNDS];
+static LOG_LEN: AtomicUsize = AtomicUsize::new(0);
+
+kernel::test_entry_point!(main);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+
+    serial_print!("threads::preempted_threads_interleave...\t");
+    let a = thread::spawn("a", || busy(b'a'));
+    let b = thread::spawn("b", || busy(b'b'));
+    a.join();
+    b.join();
+    let len = LOG_LEN.load(Ordering::SeqCst);
+    let switches = (1..len)
+        .filter(|&i| LOG[i].load(Ordering::SeqCst) != LOG[i - 1].load(Ordering::SeqCst))
+        .count();
+    serial_println!();
+    // without preemption each thread would run all its rounds in one go
+    check(
+        len == 2 * ROUNDS && switches >= 4,
+        "threads didn't interleave",
+    );
+    serial_println!("[ok]");
+
+    serial_print!("threads::sleep_and_join...\t");
+    let start = ticks();
+    let sleeper = thread::spawn("sleeper", || {
+        thread::sleep(5);
+        42
+    });
+    check(sleeper.join() == 42, "wrong result from join");
+    check(ticks() >= start + 5, "sleep returned early");
+    serial_println!("[ok]");
+
+    exit_qemu(QemuExitCode::Success);
+    loop {
+        instructions::hlt();
+    }
+}
+
+/// Spends a timer tick per round without ever yielding, so only preemption
+/// lets the other thread in.
+fn busy(name: u8) {
+    for _ in 0..ROUNDS {
+        let start = ticks();
+        while ticks() == start {
+            core::hint::spin_loop();
+        }
+        LOG[LOG_LEN.fetch_add(1, Ordering::SeqCst)].store(name, Ordering::SeqCst);
+        serial_print!("{}", name as char);
+    }
+}
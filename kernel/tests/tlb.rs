@@ -0,0 +1,89 @@
+#![no_std]
+#![no_main]
+
+use bootloader_api::info::BootInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use kernel::memory::{self, phys_to_virt, FRAME_ALLOCATOR};
+use kernel::smp::{self, MAX_CPUS};
+use kernel::{check, exit_qemu, percpu, serial_print, serial_println, QemuExitCode};
+use x86_64::instructions;
+use x86_64::structures::paging::{FrameAllocator, PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+
+const TEST_PAGE: u64 = 0x_7777_0000_0000;
+const NOT_RUN: u64 = u64::MAX;
+
+kernel::test_entry_point!(main);
+
+/// What each CPU last read from the test page.
+static SEEN: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(NOT_RUN) }; MAX_CPUS];
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    // the test runs with -smp 4
+    check(smp::cpu_count() > 1, "only one CPU, QEMU needs -smp");
+
+    serial_print!("tlb::calls_run_everywhere...\t");
+    smp::call_on_all(|| SEEN[percpu::id()].store(percpu::id() as u64, Ordering::SeqCst));
+    for cpu in 0..smp::cpu_count() {
+        check(
+            SEEN[cpu].load(Ordering::SeqCst) == cpu as u64,
+            "a CPU didn't run the call, or ran it as another",
+        );
+    }
+    for cpu in 1..smp::cpu_count() {
+        SEEN[cpu].store(NOT_RUN, Ordering::SeqCst);
+        smp::call_on(cpu, || SEEN[percpu::id()].store(1, Ordering::SeqCst));
+        check(
+            SEEN[cpu].load(Ordering::SeqCst) == 1,
+            "call_on ran elsewhere",
+        );
+    }
+    serial_println!("[ok]");
+
+    serial_print!("tlb::unmap_reaches_every_cpu...\t");
+    let old = frame_holding(1);
+    let new = frame_holding(2);
+    let page = VirtAddr::new(TEST_PAGE);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+    memory::map_physical(page, old.start_address(), 4096, flags).unwrap();
+    // every CPU caches the translation to the old frame
+    smp::call_on_all(read_test_page);
+    check(everyone_saw(1), "a CPU read the wrong frame");
+    // a CPU that missed the shootdown would still read the old frame
+    memory::unmap_range(page, 4096);
+    memory::map_physical(page, new.start_address(), 4096, flags).unwrap();
+    smp::call_on_all(read_test_page);
+    check(everyone_saw(2), "a CPU used a stale mapping");
+    serial_println!("[ok]");
+
+    exit_qemu(QemuExitCode::Success);
+    loop {
+        instructions::hlt();
+    }
+}
+
+/// A fresh frame with `value` at its start.
+fn frame_holding(value: u64) -> PhysFrame {
+    let frame = FRAME_ALLOCATOR
+        .get()
+        .unwrap()
+        .lock()
+        .allocate_frame()
+        .expect("out of frames");
+    unsafe {
+        phys_to_virt(frame.start_address())
+            .as_mut_ptr::<u64>()
+            .write(value)
+    };
+    frame
+}
+
+fn read_test_page() {
+    let value = unsafe { (TEST_PAGE as *const u64).read_volatile() };
+    SEEN[percpu::id()].store(value, Ordering::SeqCst);
+}
+
+fn everyone_saw(value: u64) -> bool {
+    (0..smp::cpu_count()).all(|cpu| SEEN[cpu].load(Ordering::SeqCst) == value)
+}
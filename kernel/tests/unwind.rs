@@ -0,0 +1,104 @@
+#![no_std]
+#![no_main]
+
+use bootloader_api::info::BootInfo;
+use core::arch::asm;
+use core::hint::black_box;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use kernel::unwind::{self, Registers};
+use kernel::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::instructions;
+
+const DEPTH: usize = 16;
+
+/// Which test the next panic belongs to.
+static STAGE: AtomicUsize = AtomicUsize::new(0);
+
+kernel::test_entry_point!(main, panic);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    serial_print!("unwind::panic_from_deep_call_chain...\t");
+    recurse(DEPTH);
+    did_not_panic()
+}
+
+#[inline(never)]
+fn recurse(depth: usize) -> usize {
+    if depth == 0 {
+        panic!("bottom of the call chain");
+    }
+    // keep the call from becoming a loop or a tail call
+    black_box(recurse(black_box(depth - 1))) + 1
+}
+
+/// Runs from the first panic, so unwinding from the second stops here.
+#[inline(never)]
+fn fault_chain() -> ! {
+    serial_print!("unwind::panic_in_exception_handler...\t");
+    black_box(fault(DEPTH));
+    did_not_panic()
+}
+
+/// Ends in a general protection fault, whose handler panics.
+#[inline(never)]
+fn fault(depth: usize) -> usize {
+    if depth == 0 {
+        unsafe {
+            asm!("mov {}, [{}]", out(reg) _, in(reg) 0xdead_beef_0000_0000u64, options(nostack))
+        };
+        return 0;
+    }
+    black_box(fault(black_box(depth - 1))) + 1
+}
+
+fn did_not_panic() -> ! {
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {
+        instructions::hlt();
+    }
+}
+
+/// Unwinds out of the panic machinery, counting the frames of `level` until
+/// it gets to `last`.
+#[inline(never)]
+fn unwind_to(level: u64, last: u64) -> (usize, bool) {
+    let (mut levels, mut reached_last) = (0, false);
+    unwind::unwind(Registers::capture(), |ip| {
+        match unwind::function_start(ip - 1) {
+            Some(start) if start == level => levels += 1,
+            Some(start) if start == last => reached_last = true,
+            _ => {}
+        }
+        !reached_last
+    });
+    (levels, reached_last)
+}
+
+/// Checks every level of `recurse` shows up, followed by `main`, then that
+/// the same goes for `fault` and `fault_chain` when the panic comes from an
+/// exception handler, which takes stepping over the interrupt frame.
+fn panic(_info: &PanicInfo) -> ! {
+    let stage = STAGE.fetch_add(1, Ordering::SeqCst);
+    let (level, last) = match stage {
+        0 => (recurse as *const () as u64, main as *const () as u64),
+        _ => (fault as *const () as u64, fault_chain as *const () as u64),
+    };
+    let (levels, reached_last) = unwind_to(level, last);
+    if levels != DEPTH + 1 || !reached_last {
+        serial_println!("[failed]");
+        serial_println!("unwound {} of {} levels", levels, DEPTH + 1);
+        exit_qemu(QemuExitCode::Failed);
+    } else {
+        serial_println!("[ok]");
+        if stage == 0 {
+            fault_chain();
+        }
+        exit_qemu(QemuExitCode::Success);
+    }
+    loop {
+        instructions::hlt();
+    }
+}
@@ -1,9 +1,17 @@
 use std::env;
 use std::fs::read;
+use std::path::Path;
+use std::process::Command;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
 pub const UEFI_PATH: &str = env!("UEFI_PATH");
 pub const BIOS_PATH: &str = env!("BIOS_PATH");
 pub const KERNEL_BINARY: &str = env!("KERNEL_BINARY");
+/// What the exit device turns `QemuExitCode::Success` into, `(code << 1) | 1`.
+const QEMU_SUCCESS: i32 = (0x10 << 1) | 1;
+/// How long a test kernel gets before it counts as hung.
+const TEST_TIMEOUT: Duration = Duration::from_secs(120);
 
 fn _read_psf1(path: &str) -> String {
     let bytes = read(path).unwrap();
@@ -60,12 +68,54 @@ fn _read_psf1(path: &str) -> String {
     )
 }
 
+/// Boots a test kernel from `kernel/tests` or the kernel's unit tests, the
+/// path cargo gives its runner, on BIOS without a display, with serial on
+/// stdout. Exits with success only if the kernel quit QEMU with
+/// `QemuExitCode::Success` within `TEST_TIMEOUT`.
+fn run_test(kernel: &Path) -> ! {
+    let image = kernel.with_extension("img");
+    bootloader::BiosBoot::new(kernel)
+        .create_disk_image(&image)
+        .expect("failed to create the test disk image");
+    let mut child = Command::new("qemu-system-x86_64")
+        .arg("-drive")
+        .arg(format!("format=raw,file={}", image.display()))
+        .args(["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04"])
+        .args(["-serial", "stdio", "-display", "none", "-smp", "4"])
+        // a triple fault ends the test instead of rebooting into it again
+        .arg("-no-reboot")
+        .spawn()
+        .expect("failed to run QEMU");
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if start.elapsed() > TEST_TIMEOUT {
+            let _ = child.kill();
+            let _ = child.wait();
+            eprintln!("test timed out after {}s", TEST_TIMEOUT.as_secs());
+            std::process::exit(1);
+        }
+        sleep(Duration::from_millis(100));
+    };
+    let passed = status.code() == Some(QEMU_SUCCESS);
+    std::process::exit(if passed { 0 } else { 1 });
+}
+
 fn main() {
     let uefi_path = env!("UEFI_PATH");
     let bios_path = env!("BIOS_PATH");
 
     let args = env::args().collect::<Vec<_>>();
-    let mut qemu_cmd = std::process::Command::new("qemu-system-x86_64");
+    if let Some(kernel) = args
+        .iter()
+        .position(|arg| arg == "-t")
+        .and_then(|i| args.get(i + 1))
+    {
+        run_test(Path::new(kernel));
+    }
+    let mut qemu_cmd = Command::new("qemu-system-x86_64");
     if args.contains(&String::from("-u")) {
         println!("UEFI: {}", uefi_path);
         qemu_cmd.arg("-bios").arg(ovmf_prebuilt::ovmf_pure_efi());
@@ -80,6 +130,17 @@ fn main() {
     } else {
         panic!("specify -b for bios or -u for uefi")
     };
+    qemu_cmd.args(["-smp", "4"]);
+    // lets the kernel quit QEMU with an exit code, as the `panic=exit` option
+    // and the tests do
+    qemu_cmd.args(["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04"]);
+
+    if args.contains(&String::from("-g")) {
+        // COM1 stays on the default console, COM2 goes to the in-kernel gdb
+        // stub, which is enabled by booting with the `gdb` option
+        qemu_cmd.args(["-serial", "vc", "-serial", "tcp::1235,server=on,wait=off"]);
+        println!("gdb stub on COM2, connect with `target remote :1235`");
+    }
 
     if args.contains(&String::from("-d")) {
         println!("generating debug.lldb");
@@ -92,7 +153,7 @@ fn main() {
         );
         std::fs::write("debug.lldb", lldb_content).expect("unable to create lldb debug file");
 
-        let mut lldb_cmd = std::process::Command::new("lldb");
+        let mut lldb_cmd = Command::new("lldb");
         lldb_cmd.args(["-s", "debug.lldb"]);
 
         // make sure to spawn qemu first
@@ -102,6 +163,12 @@ fn main() {
         qemu_process.wait().unwrap();
     } else {
         let mut child = qemu_cmd.spawn().unwrap();
-        child.wait().unwrap();
+        // pass an exit through the device on, so a crash fails whatever ran
+        // us, but as 0 for a clean one
+        let status = child.wait().unwrap();
+        std::process::exit(match status.code() {
+            Some(QEMU_SUCCESS) => 0,
+            code => code.unwrap_or(1),
+        });
     }
 }